@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use zip::write::FileOptions;
+
+use crate::errors::ReddSaverError;
+
+enum ArchiveWriter {
+    Tar(tar::Builder<File>),
+    Zip(zip::ZipWriter<File>),
+}
+
+/// A tar or zip archive that the downloaded media is added to, one file at a time,
+/// so that only one media file has to be kept on disk at any point
+pub struct Archive {
+    writer: Mutex<ArchiveWriter>,
+    /// Names of the files in the archive, to avoid adding the same media twice
+    names: Mutex<HashSet<String>>,
+}
+
+impl fmt::Debug for Archive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Archive").field("files", &self.names.lock().unwrap().len()).finish()
+    }
+}
+
+impl Archive {
+    /// Create a new archive, the format is chosen using the extension of the file name.
+    /// An existing archive is never overwritten
+    pub fn create(file_name: &str) -> Result<Archive, ReddSaverError> {
+        let extension = Path::new(file_name)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        if extension != "tar" && extension != "zip" {
+            return Err(ReddSaverError::UnsupportedArchiveFormat(String::from(file_name)));
+        }
+
+        let file = match OpenOptions::new().write(true).create_new(true).open(file_name) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(ReddSaverError::ArchiveExists(String::from(file_name)))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let writer = if extension == "tar" {
+            ArchiveWriter::Tar(tar::Builder::new(file))
+        } else {
+            ArchiveWriter::Zip(zip::ZipWriter::new(file))
+        };
+
+        Ok(Archive { writer: Mutex::new(writer), names: Mutex::new(HashSet::new()) })
+    }
+
+    /// Add the file to the archive with the given name. The contents are copied from the file
+    /// as they are read, so the whole file is never held in memory
+    pub fn add(&self, file_name: &str, name: &str) -> Result<(), ReddSaverError> {
+        if !self.names.lock().unwrap().insert(String::from(name)) {
+            return Ok(());
+        }
+
+        match &mut *self.writer.lock().unwrap() {
+            ArchiveWriter::Tar(builder) => builder.append_path_with_name(file_name, name)?,
+            ArchiveWriter::Zip(writer) => {
+                writer.start_file(name, FileOptions::default())?;
+                io::copy(&mut File::open(file_name)?, writer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the end of the archive, after which no more files can be added
+    pub fn finish(&self) -> Result<(), ReddSaverError> {
+        match &mut *self.writer.lock().unwrap() {
+            ArchiveWriter::Tar(builder) => builder.finish()?,
+            ArchiveWriter::Zip(writer) => {
+                writer.finish()?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -3,7 +3,9 @@ use crate::errors::ReddSaverError;
 use log::debug;
 use reqwest::header::{AUTHORIZATION, USER_AGENT};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
+use std::fmt;
 
 /// To generate the Reddit Client ID and secret, go to reddit [preferences](https://www.reddit.com/prefs/apps)
 pub struct Client<'a> {
@@ -17,6 +19,19 @@ pub struct Client<'a> {
     password: &'a str,
     /// Unique User agent string
     user_agent: &'a str,
+    /// Used for all the requests to the reddit API, trusting the configured certificates
+    http: reqwest::Client,
+}
+
+// the secrets are left out so that they don't end up in the debug logs
+impl fmt::Debug for Client<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("client_id", &self.client_id)
+            .field("username", &self.username)
+            .field("user_agent", &self.user_agent)
+            .finish()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -38,6 +53,7 @@ impl<'a> Client<'a> {
         username: &'a str,
         password: &'a str,
         agent: &'a str,
+        http: reqwest::Client,
     ) -> Self {
         Self {
             client_id: &id,
@@ -45,9 +61,14 @@ impl<'a> Client<'a> {
             username: &username,
             password: &password,
             user_agent: &agent,
+            http,
         }
     }
 
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.http
+    }
+
     pub async fn login(&self) -> Result<Auth, ReddSaverError> {
         let basic_token = base64::encode(format!("{}:{}", self.client_id, self.client_secret));
         let grant_type = String::from("password");
@@ -57,8 +78,8 @@ impl<'a> Client<'a> {
         body.insert("password", self.password);
         body.insert("grant_type", &grant_type);
 
-        let client = reqwest::Client::new();
-        let auth = client
+        let response = self
+            .http
             .post("https://www.reddit.com/api/v1/access_token")
             .header(USER_AGENT, self.user_agent)
             // base64 encoded <clientID>:<clientSecret> should be sent as a basic token
@@ -69,9 +90,17 @@ impl<'a> Client<'a> {
             .form(&body)
             .send()
             .await?
-            .json::<Auth>()
+            .error_for_status()?
+            .json::<Value>()
             .await?;
 
+        // rejected credentials are reported in the body, eg: {"error": "invalid_grant"}
+        if let Some(error) = response.get("error") {
+            let error = error.as_str().map_or_else(|| error.to_string(), String::from);
+            return Err(ReddSaverError::LoginFailed(error));
+        }
+        let auth = serde_json::from_value::<Auth>(response)?;
+
         debug!("Access token is: {}", auth.access_token);
         Ok(auth)
     }
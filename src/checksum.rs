@@ -0,0 +1,129 @@
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::errors::ReddSaverError;
+
+/// Algorithm of the checksums written for the downloaded media
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn from_name(name: &str) -> Option<ChecksumAlgorithm> {
+        match name {
+            "md5" => Some(ChecksumAlgorithm::Md5),
+            "sha256" => Some(ChecksumAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Name of the file the checksums are written to, as used by the md5sum and sha256sum tools
+    fn sums_file_name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Md5 => "MD5SUMS",
+            ChecksumAlgorithm::Sha256 => "SHA256SUMS",
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            ChecksumAlgorithm::Md5 => Hasher::Md5(md5::Context::new()),
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+}
+
+/// Computes the checksum of the media as it is downloaded, so that it is not read again
+pub enum Hasher {
+    Md5(md5::Context),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Md5(context) => context.consume(bytes),
+            Hasher::Sha256(hasher) => hasher.update(bytes),
+        }
+    }
+
+    /// Update the checksum with the contents of the file, eg: the part of the media downloaded
+    /// before the download was interrupted
+    pub fn update_from_file(&mut self, file_name: &str) -> std::io::Result<()> {
+        let mut file = File::open(file_name)?;
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            self.update(&buffer[..read]);
+        }
+    }
+
+    /// The checksum in hexadecimal
+    pub fn finish(self) -> String {
+        match self {
+            Hasher::Md5(context) => format!("{:x}", context.compute()),
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+        }
+    }
+}
+
+/// Record the checksum of the media in the sums file of the folder it is in, eg: MD5SUMS or
+/// SHA256SUMS, which can be verified with `sha256sum -c SHA256SUMS` from inside the folder.
+/// The entries are kept sorted by the file name and an older entry for the file is replaced
+pub fn record_checksum(
+    file_name: &str,
+    checksum: &str,
+    algorithm: ChecksumAlgorithm,
+) -> Result<(), ReddSaverError> {
+    update_sums_file(file_name, Some(checksum), algorithm)
+}
+
+/// Remove the entry of the file from the sums file of its folder, eg: once it has been replaced
+/// by a file with another name
+pub fn forget_checksum(
+    file_name: &str,
+    algorithm: ChecksumAlgorithm,
+) -> Result<(), ReddSaverError> {
+    update_sums_file(file_name, None, algorithm)
+}
+
+/// Replace the entry of the file in the sums file with the given checksum, or remove it
+fn update_sums_file(
+    file_name: &str,
+    checksum: Option<&str>,
+    algorithm: ChecksumAlgorithm,
+) -> Result<(), ReddSaverError> {
+    let path = Path::new(file_name);
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return Ok(()),
+    };
+    let sums_file_name = path.with_file_name(algorithm.sums_file_name());
+
+    // the entries are in the format of the sum tools, the checksum and the name separated by two spaces
+    let existing = fs::read_to_string(&sums_file_name).unwrap_or_default();
+    let mut entries: Vec<(&str, &str)> = existing
+        .lines()
+        .filter_map(|line| line.split_once("  "))
+        .filter(|(_, entry_name)| *entry_name != name)
+        .collect();
+    if let Some(checksum) = checksum {
+        entries.push((checksum, &name));
+    }
+    entries.sort_by(|a, b| a.1.cmp(b.1));
+
+    let contents: String =
+        entries.iter().map(|(checksum, name)| format!("{}  {}\n", checksum, name)).collect();
+    fs::write(&sums_file_name, contents)?;
+
+    Ok(())
+}
@@ -0,0 +1,28 @@
+use crate::structures::{CommentChild, CommentListing, PostData};
+
+/// Render the comments of the post as Markdown, each comment as an item of a list with the
+/// replies to it nested below. Placeholders for the comments that were not fetched are left out
+pub fn render_comments(post: &PostData, permalink: &str, comments: &CommentListing) -> String {
+    let title = post.title.as_deref().unwrap_or(&post.name);
+    let mut markdown = format!("# {}\n\n<{}>\n\n", title, permalink);
+    render_children(&comments.data.children, 0, &mut markdown);
+    markdown
+}
+
+fn render_children(children: &[CommentChild], depth: usize, markdown: &mut String) {
+    let indent = "  ".repeat(depth);
+    for child in children.iter().filter(|c| c.kind == "t1") {
+        let comment = &child.data;
+        let author = comment.author.as_deref().unwrap_or("[deleted]");
+        let score = comment.score.unwrap_or(0);
+        markdown.push_str(&format!("{}- **{}** ({} points)\n", indent, author, score));
+        // the lines of the body are indented to continue the list item
+        for line in comment.body.as_deref().unwrap_or_default().lines() {
+            markdown.push_str(format!("{}  {}", indent, line).trim_end());
+            markdown.push('\n');
+        }
+        if let Some(replies) = &comment.replies {
+            render_children(&replies.data.children, depth + 1, markdown);
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::io::Reader;
+use image::{AnimationDecoder, ImageOutputFormat};
+use tempfile::NamedTempFile;
+
+use crate::errors::ReddSaverError;
+use crate::utils::check_path_present;
+
+/// Quality of the converted JPEG images, high enough for the loss to be hardly noticeable
+static JPEG_QUALITY: u8 = 90;
+
+/// Extensions of the images that can be converted
+static CONVERTIBLE_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "webp", "gif"];
+
+/// Format all the downloaded images are converted to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImageFormat {
+    /// Lossy and without transparency, the transparent parts are filled with black
+    Jpg,
+    /// Lossless, but larger than JPEG for photos
+    Png,
+}
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "jpg" => Some(ImageFormat::Jpg),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+
+    fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            ImageFormat::Jpg => &["jpg", "jpeg"],
+            ImageFormat::Png => &["png"],
+        }
+    }
+}
+
+/// Convert the image to the given format, replacing the original. Videos, animated gifs and the
+/// images already in the format are left as they are. Returns the name of the converted image
+pub fn convert_image(
+    file_name: &str,
+    format: ImageFormat,
+) -> Result<Option<String>, ReddSaverError> {
+    let path = Path::new(file_name);
+    let extension = match path.extension() {
+        Some(extension) => extension.to_string_lossy().to_lowercase(),
+        None => return Ok(None),
+    };
+    if !CONVERTIBLE_EXTENSIONS.contains(&extension.as_str())
+        || format.extensions().contains(&extension.as_str())
+    {
+        return Ok(None);
+    }
+    // only the first frame of animated gifs would be kept
+    if extension == "gif" && is_animated(file_name)? {
+        return Ok(None);
+    }
+    let converted = path.with_extension(format.extensions()[0]);
+    if check_path_present(&converted.to_string_lossy()) {
+        return Err(ReddSaverError::ConvertedImagePresent(
+            converted.to_string_lossy().into_owned(),
+        ));
+    }
+
+    let image = Reader::open(path)?.with_guessed_format()?.decode()?;
+    // the image is written next to the original, so that it is never left partially written
+    let mut output = NamedTempFile::new_in(path.parent().unwrap_or_else(|| Path::new(".")))?;
+    {
+        let mut writer = BufWriter::new(output.as_file_mut());
+        match format {
+            ImageFormat::Jpg => JpegEncoder::new_with_quality(&mut writer, JPEG_QUALITY)
+                .encode_image(&image.to_rgb8())?,
+            ImageFormat::Png => image.write_to(&mut writer, ImageOutputFormat::Png)?,
+        }
+    }
+    output.persist(&converted).map_err(|e| e.error)?;
+    fs::remove_file(path)?;
+
+    Ok(Some(converted.to_string_lossy().into_owned()))
+}
+
+fn is_animated(file_name: &str) -> Result<bool, ReddSaverError> {
+    let decoder = GifDecoder::new(BufReader::new(File::open(file_name)?))?;
+    Ok(decoder.into_frames().take(2).count() > 1)
+}
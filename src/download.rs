@@ -1,23 +1,99 @@
 use std::borrow::Borrow;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Stdout, Write};
 use std::ops::Add;
-use std::path::Path;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{fs, io};
 
+use chrono::{Datelike, TimeZone, Utc};
+use deunicode::deunicode;
 use futures::stream::FuturesUnordered;
-use futures::TryStreamExt;
+use futures::{future, FutureExt, StreamExt, TryStreamExt};
 use log::{debug, error, info, warn};
-use reqwest::StatusCode;
-use tempfile::tempdir;
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, RANGE, USER_AGENT};
+use reqwest::{Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tempfile::{tempdir, tempdir_in};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::Semaphore;
+use tokio::time::{delay_for, delay_until, interval_at, Instant};
 use url::{Position, Url};
 
+use crate::archive::Archive;
+use crate::checksum::{forget_checksum, record_checksum, ChecksumAlgorithm, Hasher};
+use crate::comments::render_comments;
+use crate::convert::{convert_image, ImageFormat};
 use crate::errors::ReddSaverError;
-use crate::structures::{GfyData, PostData};
+use crate::remux::remux;
+use crate::storage::StorageBackend;
+use crate::structures::{
+    DuplicateGroup, DuplicateMedia, FailedDownload, GfyItem, GfyResponse, HostStats,
+    ManifestComponent, ManifestEntry, MediaResult, PostData, PostMedia, RedditVideo,
+    RedgifsGallery, RedgifsResponse, RedgifsToken,
+};
 use crate::structures::{Listing, Summary};
 use crate::user::{ListingType, User};
-use crate::utils::{check_path_present, check_url_is_mp4};
+use crate::utils::{
+    check_path_present, check_url_is_mp4, expand_template, get_user_agent_string, is_share_link,
+    list_files, move_file, post_id_from_url, sanitize_path_component,
+};
+
+static FAILURES_LOG_FILE_NAME: &str = ".reddsaver_failures.jsonl";
+/// Extension of the file the media is written to till it has been completely downloaded
+static PART_EXTENSION: &str = "part";
+
+/// Extended attributes used by browsers to record where a file was downloaded from
+static XATTR_ORIGIN_URL: &str = "user.xdg.origin.url";
+static XATTR_REFERRER_URL: &str = "user.xdg.referrer.url";
+static REDDIT_PERMALINK_HOST: &str = "www.reddit.com";
+
+/// Tokens that can be used in the output template
+pub static OUTPUT_TEMPLATE_TOKENS: [&str; 11] =
+    ["subreddit", "id", "name", "title", "index", "hash", "ext", "listing", "year", "month", "day"];
+
+/// Title or text that reddit leaves in place of the content of removed and deleted posts
+static REMOVED_POST_MARKERS: [&str; 3] = ["[removed]", "[deleted]", "[deleted by user]"];
+/// Placeholder images served in place of removed media
+static REMOVED_MEDIA_URLS: [&str; 2] =
+    ["https://i.imgur.com/removed.png", "https://i.redd.it/removed.png"];
+
+/// Hosts that have shut down, media linked from them can never be downloaded
+static DEAD_HOSTS: [&str; 5] = ["gfycat.com", "vid.me", "vine.co", "mixtape.moe", "pomf.se"];
+
+/// Placeholders that self posts sometimes have in place of a URL, like their thumbnails do
+static PLACEHOLDER_URLS: [&str; 4] = ["self", "default", "spoiler", "nsfw"];
+
+/// Extensions for the content types that the media is served with, used to correct the
+/// extension of media whose URL does not match its contents
+static MEDIA_CONTENT_TYPES: [(&str, &str); 6] = [
+    ("image/jpeg", "jpg"),
+    ("image/png", "png"),
+    ("image/gif", "gif"),
+    ("image/webp", "webp"),
+    ("video/mp4", "mp4"),
+    ("video/webm", "webm"),
+];
+
+/// Minimum size of a video in bytes, even the shortest of videos are larger than this
+static MIN_VIDEO_SIZE: usize = 1024;
+
+/// Number of pages of the listing processed at the same time, so that the slowest downloads of a
+/// page do not hold up the next ones
+static MAX_CONCURRENT_PAGES: usize = 4;
+/// Number of posts processed at the same time across all the pages, which is a full page
+static MAX_CONCURRENT_POSTS: usize = 100;
+/// Number of requests for the comments of the posts made at the same time
+static MAX_CONCURRENT_COMMENT_REQUESTS: usize = 4;
+/// Depth of the replies saved with the top comments of a post
+static COMMENT_DEPTH: u32 = 3;
+
+/// Bytes in a megabyte, for reporting the downloads from each host
+static BYTES_PER_MB: f64 = 1024.0 * 1024.0;
 
 static JPG_EXTENSION: &str = "jpg";
 static PNG_EXTENSION: &str = "png";
@@ -29,15 +105,24 @@ static REDDIT_DOMAIN: &str = "reddit.com";
 static REDDIT_IMAGE_SUBDOMAIN: &str = "i.redd.it";
 static REDDIT_VIDEO_SUBDOMAIN: &str = "v.redd.it";
 static REDDIT_GALLERY_PATH: &str = "gallery";
+static REDDIT_COMMENTS_PATH: &str = "/comments/";
 
 static IMGUR_DOMAIN: &str = "imgur.com";
 static IMGUR_SUBDOMAIN: &str = "i.imgur.com";
 
+static IMGUR_ATTEMPTS: u32 = 3;
+/// Time to wait when Imgur is rate limiting the downloads, if it does not say how long to wait
+static IMGUR_RATE_LIMIT_DELAY_SECS: u64 = 5;
+static IMGUR_MAX_RATE_LIMIT_DELAY_SECS: u64 = 60;
+
 static GFYCAT_DOMAIN: &str = "gfycat.com";
 static GFYCAT_API_PREFIX: &str = "https://api.gfycat.com/v1/gfycats";
 
 static REDGIFS_DOMAIN: &str = "redgifs.com";
-static REDGIFS_API_PREFIX: &str = "https://api.redgifs.com/v1/gfycats";
+static REDGIFS_API_PREFIX: &str = "https://api.redgifs.com/v2";
+
+static GFY_API_ATTEMPTS: u32 = 2;
+static GFY_API_RETRY_DELAY_SECS: u64 = 5;
 
 static GIPHY_DOMAIN: &str = "giphy.com";
 static GIPHY_MEDIA_SUBDOMAIN: &str = "media.giphy.com";
@@ -46,8 +131,25 @@ static GIPHY_MEDIA_SUBDOMAIN_1: &str = "media1.giphy.com";
 static GIPHY_MEDIA_SUBDOMAIN_2: &str = "media2.giphy.com";
 static GIPHY_MEDIA_SUBDOMAIN_3: &str = "media3.giphy.com";
 static GIPHY_MEDIA_SUBDOMAIN_4: &str = "media4.giphy.com";
+static GIPHY_MEDIA_PATH: &str = "media";
+// giphy IDs are mixed case alphanumeric strings, unlike the words of the slug before them
+static GIPHY_MIN_ID_LENGTH: usize = 8;
+
+/// The hosts of the media resolved in get_media and what is downloaded from them, as listed by
+/// --list-supported. This has to be updated along with get_media when a host is added or removed
+static SUPPORTED_HOSTS: [(&[&str], &str); 4] = [
+    (
+        &[REDDIT_DOMAIN, REDDIT_IMAGE_SUBDOMAIN, REDDIT_VIDEO_SUBDOMAIN],
+        "PNG/JPG images, GIFs, image galleries and videos, including posts linking to their own \
+         comments page",
+    ),
+    (&[GIPHY_DOMAIN], "GIFs"),
+    (&[IMGUR_SUBDOMAIN], "direct links to PNG/JPG images and GIFVs, GIFVs are downloaded as mp4"),
+    (&[REDGIFS_DOMAIN], "GIFs and galleries, downloaded as mp4"),
+];
 
 /// Status of media processing
+#[derive(Debug, Copy, Clone, PartialEq)]
 enum MediaStatus {
     /// If we are able to successfully download the media
     Downloaded,
@@ -55,6 +157,55 @@ enum MediaStatus {
     /// or because we could not find the media or because we are unable to decode
     /// the media
     Skipped,
+    /// If we tried to download the media but were unable to save it
+    Failed,
+    /// If the media was removed from the host, which only serves a placeholder instead
+    Removed,
+}
+
+impl MediaStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            MediaStatus::Downloaded => "downloaded",
+            MediaStatus::Skipped => "skipped",
+            MediaStatus::Failed => "failed",
+            MediaStatus::Removed => "removed",
+        }
+    }
+}
+
+/// Progress of the run, sent as each media and post is processed, since the summary is only
+/// available at the end. `--print-events` prints them as JSON lines for other tools to follow
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DownloadEvent {
+    /// The media was downloaded to the path, which is this many bytes
+    MediaDownloaded { url: String, path: String, bytes: u64 },
+    /// The media was not downloaded, eg: since it is already present at the path
+    MediaSkipped { url: String, path: String },
+    /// The media could not be downloaded
+    MediaFailed { url: String },
+    /// The media was removed from the host, which only serves a placeholder instead
+    MediaRemoved { url: String },
+    /// The post is done with, whether its media was saved, it was skipped or it failed
+    PostProcessed { post_id: String },
+}
+
+impl DownloadEvent {
+    /// The event of the media at the URL, saved to the file name unless it has failed
+    fn of_media(status: &MediaStatus, url: &str, file_name: &str) -> DownloadEvent {
+        let url = String::from(url);
+        let path = String::from(file_name);
+        match status {
+            MediaStatus::Downloaded => {
+                let bytes = fs::metadata(&path).map_or(0, |m| m.len());
+                DownloadEvent::MediaDownloaded { url, path, bytes }
+            }
+            MediaStatus::Skipped => DownloadEvent::MediaSkipped { url, path },
+            MediaStatus::Failed => DownloadEvent::MediaFailed { url },
+            MediaStatus::Removed => DownloadEvent::MediaRemoved { url },
+        }
+    }
 }
 
 /// Media Types Supported
@@ -62,15 +213,94 @@ enum MediaStatus {
 enum MediaType {
     RedditImage,
     RedditGif,
+    RedditGifMp4,
     RedditVideoWithAudio,
     RedditVideoWithoutAudio,
+    RedditVideoHls,
     GfycatGif,
+    GfycatThumbnail,
+    /// The thumbnail of a post, saved when none of its media could be saved
+    RedditThumbnail,
     GiphyGif,
     ImgurImage,
     ImgurGif,
 }
 
+impl MediaType {
+    /// Name of the directory for the type of media, used when segregating the media by type
+    fn directory(&self) -> &'static str {
+        match self {
+            MediaType::RedditImage
+            | MediaType::ImgurImage
+            | MediaType::GfycatThumbnail
+            | MediaType::RedditThumbnail => "images",
+            MediaType::RedditGif
+            | MediaType::RedditGifMp4
+            | MediaType::GfycatGif
+            | MediaType::GiphyGif
+            | MediaType::ImgurGif => "gifs",
+            MediaType::RedditVideoWithAudio
+            | MediaType::RedditVideoWithoutAudio
+            | MediaType::RedditVideoHls => "videos",
+        }
+    }
+
+    fn is_image(&self) -> bool {
+        matches!(self, MediaType::RedditImage | MediaType::ImgurImage)
+    }
+}
+
+/// What is done when the media of different URLs in a run are given the same file name, eg: by
+/// an output template using the title of the posts
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CollisionPolicy {
+    /// The later media replaces the one saved before it
+    Overwrite,
+    /// The media saved first is kept and the later media is skipped
+    Skip,
+    /// The later media is saved with an incrementing suffix, eg: `title (1).jpg`
+    Suffix,
+}
+
+impl CollisionPolicy {
+    pub fn from_name(name: &str) -> Option<CollisionPolicy> {
+        match name {
+            "overwrite" => Some(CollisionPolicy::Overwrite),
+            "skip" => Some(CollisionPolicy::Skip),
+            "suffix" => Some(CollisionPolicy::Suffix),
+            _ => None,
+        }
+    }
+}
+
+/// Format the audio track of reddit videos is saved in on its own
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AudioFormat {
+    /// The AAC audio as it was downloaded from reddit
+    Mp4,
+    /// Transcoded to mp3 using ffmpeg
+    Mp3,
+}
+
+impl AudioFormat {
+    pub fn from_name(name: &str) -> Option<AudioFormat> {
+        match name {
+            "mp4" => Some(AudioFormat::Mp4),
+            "mp3" => Some(AudioFormat::Mp3),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Mp4 => MP4_EXTENSION,
+            AudioFormat::Mp3 => "mp3",
+        }
+    }
+}
+
 /// Information about supported media for downloading
+#[derive(Debug)]
 struct SupportedMedia {
     /// The components for the media. This is a vector of size one for
     /// all media types except Reddit videos and Reddit Galleries.
@@ -79,51 +309,536 @@ struct SupportedMedia {
     media_type: MediaType,
 }
 
+/// Limits the combined rate of all the downloads in a run
+#[derive(Debug)]
+struct RateLimiter {
+    /// Maximum number of bytes downloaded per second. There is no limit if this is None
+    max_rate: Option<u64>,
+    /// The time at which the bytes downloaded so far would have been downloaded at the maximum rate
+    next_available: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(max_rate: Option<u64>) -> Self {
+        RateLimiter { max_rate, next_available: Mutex::new(Instant::now()) }
+    }
+
+    /// Wait till the given number of bytes can be downloaded without exceeding the maximum rate
+    async fn acquire(&self, bytes: usize) {
+        if let Some(max_rate) = self.max_rate {
+            let until = {
+                let mut next_available = self.next_available.lock().unwrap();
+                // the unused rate of an idle period is not carried forward,
+                // so that downloads cannot burst beyond the maximum rate
+                let now = Instant::now();
+                if *next_available < now {
+                    *next_available = now;
+                }
+                *next_available += Duration::from_secs_f64(bytes as f64 / max_rate as f64);
+                *next_available
+            };
+            delay_until(until).await;
+        }
+    }
+}
+
+/// Number of the failed downloads by the reason they failed for, eg: 404 Not Found
+#[derive(Debug, Default)]
+struct FailureReasons(Mutex<HashMap<String, u32>>);
+
+impl FailureReasons {
+    fn record(&self, reason: &str) {
+        *self.0.lock().unwrap().entry(String::from(reason)).or_insert(0) += 1;
+    }
+
+    /// Log the reasons the downloads failed for, the most frequent one first
+    fn report(&self) {
+        let reasons = self.0.lock().unwrap();
+        if reasons.is_empty() {
+            return;
+        }
+        let mut reasons: Vec<(&String, &u32)> = reasons.iter().collect();
+        reasons.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        info!("Failed downloads by reason:");
+        for (reason, count) in reasons {
+            info!("  {}: {}", reason, count);
+        }
+    }
+}
+
+/// Options of the run given on the command line, passed to the downloader as a whole
+#[derive(Debug)]
+pub struct DownloadOptions<'a> {
+    /// The user to undo the save or upvote for. Not available when the listing was read from a file
+    pub user: Option<&'a User<'a>>,
+    /// Listing the posts are from, eg: saved or upvoted
+    pub listing_type: &'a ListingType,
+    /// Directory the media is saved to
+    pub data_directory: &'a str,
+    /// Only download the media of the posts in these subreddits
+    pub subreddits: &'a Option<Vec<&'a str>>,
+    /// Posts from these subreddits are never downloaded
+    pub skipped_subreddits: Vec<String>,
+    /// Media saved with these extensions is never downloaded
+    pub excluded_extensions: Vec<String>,
+    /// Only the posts with this flair are downloaded
+    pub flair: Option<&'a str>,
+    /// Posts with this flair are never downloaded
+    pub excluded_flair: Option<&'a str>,
+    /// Only the posts created after this time, in seconds since the epoch, are downloaded
+    pub since: Option<i64>,
+    /// The posts of the listing are in the order they were created, newest first, so the rest of
+    /// the listing is not fetched once a whole page is older than the age limit
+    pub newest_first: bool,
+    /// At most this many posts are downloaded from each subreddit in a run
+    pub max_per_subreddit: Option<usize>,
+    /// Download the media, false for dry runs and the runs only listing or counting it
+    pub should_download: bool,
+    /// Print the URLs of the media instead of downloading them
+    pub print_urls: bool,
+    /// Only report the aggregate counts without downloading or printing the URLs
+    pub summary_only: bool,
+    /// Only rewrite the metadata sidecars of the media already present on disk
+    pub refresh_metadata: bool,
+    /// Only resolve the media and save the manifest of the media to this file
+    pub resolve_only: Option<&'a str>,
+    /// Name the files after the title of the post instead of the hash of their URL
+    pub use_human_readable: bool,
+    /// Transliterate the titles used in the file names to ASCII
+    pub ascii_filenames: bool,
+    /// Save the media of each listing in its own directory
+    pub segregate_by_listing: bool,
+    /// Save the images and the videos in their own directories
+    pub segregate_by_type: bool,
+    /// Save the images of each gallery in a directory named after the post
+    pub gallery_subfolder: bool,
+    /// Template for the path of the media relative to the data directory
+    pub output_template: Option<&'a str>,
+    /// What is done when the media of different URLs are given the same file name
+    pub on_collision: CollisionPolicy,
+    /// Unsave or unupvote the posts once their media has been downloaded
+    pub undo: bool,
+    /// Keep downloading without undoing if the app is not authorized to undo
+    pub continue_without_undo: bool,
+    /// Combine the video and audio of reddit videos with ffmpeg
+    pub ffmpeg_available: bool,
+    /// Directory the videos are combined and remuxed in by ffmpeg before being moved into place
+    pub temp_dir: &'a str,
+    /// Download the mp4 variant of reddit gifs when available
+    pub prefer_mp4: bool,
+    /// Also download the poster image of gfycat/redgifs gifs
+    pub with_thumbnails: bool,
+    /// Only download the video track of reddit videos
+    pub no_audio: bool,
+    /// Also save the audio track of reddit videos on its own, next to the combined video
+    pub extract_audio: Option<AudioFormat>,
+    /// Download the reddit preview of videos from dead or unsupported hosts instead
+    pub preview_fallback: bool,
+    /// Download the thumbnail of the posts none of whose media could be downloaded
+    pub thumbnail_fallback: bool,
+    /// Use aria2c instead of the built-in downloader
+    pub use_aria2c: bool,
+    /// Record the URL and the permalink that the media was downloaded from
+    pub record_source: bool,
+    /// Write the metadata of the post to an XMP sidecar next to each downloaded image
+    pub xmp_sidecar: bool,
+    /// Program to run for each downloaded file
+    pub post_download_hook: Option<&'a str>,
+    /// Minimum delay between starting two downloads
+    pub throttle: Duration,
+    /// Maximum combined rate of all the downloads in bytes per second
+    pub max_rate: Option<u64>,
+    /// Send the progress of the run to this channel as it is made, nothing is sent without one
+    pub events: Option<UnboundedSender<DownloadEvent>>,
+    /// Record the checksums of the downloaded media using this algorithm
+    pub checksums: Option<ChecksumAlgorithm>,
+    /// Convert the downloaded images to this format, replacing the originals
+    pub convert_images: Option<ImageFormat>,
+    /// No new posts are processed after this time, while the ones in progress are completed
+    pub deadline: Option<Instant>,
+    /// Log the counts of the run so far at this interval, for runs without a progress bar
+    pub stats_interval: Option<Duration>,
+    /// Report the files in the data directory that do not belong to any post in the listing
+    pub prune: bool,
+    /// Delete the files found when pruning instead of only reporting them
+    pub delete_orphans: bool,
+    /// Write the groups of posts whose media has the same content to this file
+    pub dedupe_report: Option<&'a str>,
+    /// Write the ID of the post, the URL and the path of each media present to this file as TSV
+    pub mapping_file: Option<&'a str>,
+    /// Report the number of downloads, their size and throughput for each host
+    pub host_stats: bool,
+    /// Stop downloading from a host after this many downloads from it have failed in a row,
+    /// instead of failing each of its remaining media. Zero to never stop
+    pub max_host_failures: u32,
+    /// Write the result of each media to stdout as a JSON line as soon as it is known
+    pub json_lines: bool,
+    /// Save this many of the top comments of each downloaded post
+    pub with_comments: Option<u32>,
+    /// Log every decision made in resolving the media of the post with this ID
+    pub trace_post: Option<&'a str>,
+}
+
 #[derive(Debug)]
 pub struct Downloader<'a> {
-    user: &'a User<'a>,
-    listing: &'a Vec<Listing>,
+    /// The user to undo the save or upvote for. Not available when the listing was read from a file
+    user: Option<&'a User<'a>>,
     listing_type: &'a ListingType,
     data_directory: &'a str,
     subreddits: &'a Option<Vec<&'a str>>,
+    /// Posts from these subreddits are never downloaded
+    skipped_subreddits: Vec<String>,
+    /// Media saved with these extensions is never downloaded
+    excluded_extensions: Vec<String>,
+    /// Only the posts with this flair are downloaded
+    flair: Option<&'a str>,
+    /// Posts with this flair are never downloaded
+    excluded_flair: Option<&'a str>,
+    /// Only the posts created after this time, in seconds since the epoch, are downloaded
+    since: Option<i64>,
+    /// The posts of the listing are in the order they were created, newest first, so the rest of
+    /// the listing is not fetched once a whole page is older than the age limit
+    newest_first: bool,
+    /// At most this many posts are downloaded from each subreddit in a run
+    max_per_subreddit: Option<usize>,
+    /// Number of posts downloaded from each subreddit so far, keyed by the lowercase name
+    subreddit_counts: Mutex<HashMap<String, usize>>,
     should_download: bool,
+    print_urls: bool,
+    /// Only report the aggregate counts without downloading or printing the URLs
+    summary_only: bool,
+    /// Only rewrite the metadata sidecars of the media already present on disk
+    refresh_metadata: bool,
+    /// Only resolve the media and save the manifest of the media to this file
+    resolve_only: Option<&'a str>,
+    /// The resolved media, saved to the manifest when resolving only
+    manifest: Mutex<Vec<ManifestEntry>>,
     use_human_readable: bool,
+    /// Transliterate the titles used in the file names to ASCII
+    ascii_filenames: bool,
+    segregate_by_listing: bool,
+    segregate_by_type: bool,
+    /// Save the images of each gallery in a directory named after the post
+    gallery_subfolder: bool,
+    /// Template for the path of the media relative to the data directory
+    output_template: Option<&'a str>,
+    /// What is done when the media of different URLs are given the same file name
+    on_collision: CollisionPolicy,
+    /// The URL of the media each file name has been given to in the run, without the extension
+    file_claims: Mutex<HashMap<PathBuf, String>>,
+    /// The file names claimed by the media of another URL, whose saved media is to be replaced
+    /// once the later media is downloaded, without the extension
+    overwritten_files: Mutex<HashSet<PathBuf>>,
+    /// The file names claimed by the posts still being processed, with the names of the
+    /// posts, without the extension
+    in_flight_files: Mutex<HashMap<PathBuf, String>>,
     undo: bool,
+    /// Keep downloading without undoing if the app is not authorized to undo
+    continue_without_undo: bool,
+    /// Set once undoing has failed for the lack of the scope, so that it is not tried again
+    undo_disabled: AtomicBool,
     ffmpeg_available: bool,
+    /// Directory the videos are combined and remuxed in by ffmpeg before being moved into place
+    temp_dir: &'a str,
+    /// Download the mp4 variant of reddit gifs when available
+    prefer_mp4: bool,
+    /// Also download the poster image of gfycat/redgifs gifs
+    with_thumbnails: bool,
+    /// Only download the video track of reddit videos
+    no_audio: bool,
+    /// Also save the audio track of reddit videos on its own, next to the combined video
+    extract_audio: Option<AudioFormat>,
+    /// Download the reddit preview of videos from dead or unsupported hosts instead
+    preview_fallback: bool,
+    /// Download the thumbnail of the posts none of whose media could be downloaded
+    thumbnail_fallback: bool,
+    /// Use aria2c instead of the built-in downloader
+    use_aria2c: bool,
+    /// Record the URL and the permalink that the media was downloaded from
+    record_source: bool,
+    /// Write the metadata of the post to an XMP sidecar next to each downloaded image
+    xmp_sidecar: bool,
+    /// Program to run for each downloaded file
+    post_download_hook: Option<&'a str>,
+    /// Minimum delay between starting two downloads
+    throttle: Duration,
+    /// The earliest time at which the next download can be started
+    next_download: Mutex<Instant>,
+    /// Limits the combined rate of all the downloads
+    rate_limiter: RateLimiter,
+    /// Record the checksums of the downloaded media using this algorithm
+    checksums: Option<ChecksumAlgorithm>,
+    /// Convert the downloaded images to this format, replacing the originals
+    convert_images: Option<ImageFormat>,
+    /// No new posts are processed after this time, while the ones in progress are completed
+    deadline: Option<Instant>,
+    /// Log the counts of the run so far at this interval, for runs without a progress bar
+    stats_interval: Option<Duration>,
+    /// Results of the content-type probes for reddit video audio tracks, keyed by the audio URL.
+    /// The same video can be present multiple times across listings, so the probes are cached
+    /// to avoid making the same request more than once in a run.
+    probe_cache: Mutex<HashMap<String, Option<bool>>>,
+    /// Temporary token of the redgifs API, shared by all the posts until it expires
+    redgifs_token: Mutex<Option<String>>,
+    /// Posts that the share links and the short links resolve to, keyed by the link. The same
+    /// post is often shared more than once, so the links are only resolved once in a run
+    linked_posts: Mutex<HashMap<String, Option<PostData>>>,
+    /// Bounds the posts processed at the same time, shared by the pages processed concurrently
+    post_permits: Semaphore,
+    /// HTTP client shared by all the downloads
+    client: Client,
+    /// Report the files in the data directory that do not belong to any post in the listing
+    prune: bool,
+    /// Delete the files found when pruning instead of only reporting them
+    delete_orphans: bool,
+    /// Names of all the posts in the listing, used for pruning
+    known_posts: Mutex<HashSet<String>>,
+    /// Names of the posts whose media was all saved, used for pruning
+    saved_posts: Mutex<HashSet<String>>,
+    /// Summaries of the pages that are still being downloaded, for the progress
+    pages_in_progress: Mutex<Vec<Arc<Mutex<Summary>>>>,
+    /// Paths of the media of the posts in the listing without their extensions, used for pruning
+    known_files: Mutex<HashSet<PathBuf>>,
+    /// Write the groups of posts whose media has the same content to this file
+    dedupe_report: Option<&'a str>,
+    /// Media of the posts in the listing, keyed by the hash of their content
+    content_hashes: Mutex<HashMap<String, Vec<DuplicateMedia>>>,
+    /// Write the ID of the post, the URL and the path of each media present to this file as TSV
+    mapping_file: Option<&'a str>,
+    /// The ID of the post, the URL and the path of each media that is present
+    mappings: Mutex<Vec<(String, String, String)>>,
+    /// Report the number of downloads, their size and throughput for each host
+    host_stats: bool,
+    /// Downloads from each host, keyed by the host name
+    host_downloads: Mutex<HashMap<String, HostStats>>,
+    /// Stop downloading from a host after this many downloads from it have failed in a row,
+    /// instead of failing each of its remaining media. Zero to never stop
+    max_host_failures: u32,
+    /// Number of consecutive failed downloads from each host, keyed by the host name
+    host_failures: Mutex<HashMap<String, u32>>,
+    /// Number of the failed downloads by the reason they failed for
+    failure_reasons: FailureReasons,
+    /// Write the result of each media to stdout as a JSON line as soon as it is known
+    json_lines: bool,
+    /// Guards stdout so that the lines of the posts processed concurrently are not interleaved
+    stdout: Mutex<Stdout>,
+    /// Save this many of the top comments of each downloaded post
+    with_comments: Option<u32>,
+    /// Bounds the requests for the comments made at the same time
+    comment_permits: Semaphore,
+    /// Log every decision made in resolving the media of the post with this ID
+    trace_post: Option<&'a str>,
+    /// Archive to move the downloaded media into, instead of keeping it in the data directory
+    archive: Option<Archive>,
+    /// Where the downloaded media is stored, the data directory unless a bucket is given
+    storage: Box<dyn StorageBackend + 'a>,
+    /// Files in the data directory listed once at the start, which are checked for the media
+    /// already downloaded instead of checking for each file on slow filesystems
+    existing_files: Option<Mutex<HashSet<PathBuf>>>,
+    /// Send the progress of the run to this channel as it is made, nothing is sent without one
+    events: Option<UnboundedSender<DownloadEvent>>,
 }
 
 impl<'a> Downloader<'a> {
     pub fn new(
-        user: &'a User,
-        listing: &'a Vec<Listing>,
-        listing_type: &'a ListingType,
-        data_directory: &'a str,
-        subreddits: &'a Option<Vec<&'a str>>,
-        should_download: bool,
-        use_human_readable: bool,
-        undo: bool,
-        ffmpeg_available: bool,
+        options: DownloadOptions<'a>,
+        client: Client,
+        archive: Option<Archive>,
+        storage: Box<dyn StorageBackend + 'a>,
+        existing_files: Option<HashSet<PathBuf>>,
     ) -> Downloader<'a> {
+        let DownloadOptions {
+            user,
+            listing_type,
+            data_directory,
+            subreddits,
+            skipped_subreddits,
+            excluded_extensions,
+            flair,
+            excluded_flair,
+            since,
+            newest_first,
+            max_per_subreddit,
+            should_download,
+            print_urls,
+            summary_only,
+            refresh_metadata,
+            resolve_only,
+            use_human_readable,
+            ascii_filenames,
+            segregate_by_listing,
+            segregate_by_type,
+            gallery_subfolder,
+            output_template,
+            on_collision,
+            undo,
+            continue_without_undo,
+            ffmpeg_available,
+            temp_dir,
+            prefer_mp4,
+            with_thumbnails,
+            no_audio,
+            extract_audio,
+            preview_fallback,
+            thumbnail_fallback,
+            use_aria2c,
+            record_source,
+            xmp_sidecar,
+            post_download_hook,
+            throttle,
+            max_rate,
+            events,
+            checksums,
+            convert_images,
+            deadline,
+            stats_interval,
+            prune,
+            delete_orphans,
+            dedupe_report,
+            mapping_file,
+            host_stats,
+            max_host_failures,
+            json_lines,
+            with_comments,
+            trace_post,
+        } = options;
         Downloader {
             user,
-            listing,
             listing_type,
             data_directory,
             subreddits,
+            skipped_subreddits,
+            excluded_extensions,
+            flair,
+            excluded_flair,
+            since,
+            newest_first,
+            max_per_subreddit,
+            subreddit_counts: Mutex::new(HashMap::new()),
             should_download,
+            print_urls,
+            summary_only,
+            refresh_metadata,
+            resolve_only,
+            manifest: Mutex::new(Vec::new()),
             use_human_readable,
+            ascii_filenames,
+            segregate_by_listing,
+            segregate_by_type,
+            gallery_subfolder,
+            output_template,
+            on_collision,
+            file_claims: Mutex::new(HashMap::new()),
+            overwritten_files: Mutex::new(HashSet::new()),
+            in_flight_files: Mutex::new(HashMap::new()),
             undo,
+            continue_without_undo,
+            undo_disabled: AtomicBool::new(false),
             ffmpeg_available,
+            temp_dir,
+            prefer_mp4,
+            with_thumbnails,
+            no_audio,
+            extract_audio,
+            preview_fallback,
+            thumbnail_fallback,
+            use_aria2c,
+            record_source,
+            xmp_sidecar,
+            post_download_hook,
+            throttle,
+            next_download: Mutex::new(Instant::now()),
+            rate_limiter: RateLimiter::new(max_rate),
+            checksums,
+            convert_images,
+            deadline,
+            stats_interval,
+            probe_cache: Mutex::new(HashMap::new()),
+            redgifs_token: Mutex::new(None),
+            linked_posts: Mutex::new(HashMap::new()),
+            post_permits: Semaphore::new(MAX_CONCURRENT_POSTS),
+            client,
+            prune,
+            delete_orphans,
+            known_posts: Mutex::new(HashSet::new()),
+            saved_posts: Mutex::new(HashSet::new()),
+            pages_in_progress: Mutex::new(Vec::new()),
+            known_files: Mutex::new(HashSet::new()),
+            dedupe_report,
+            content_hashes: Mutex::new(HashMap::new()),
+            mapping_file,
+            mappings: Mutex::new(Vec::new()),
+            host_stats,
+            host_downloads: Mutex::new(HashMap::new()),
+            max_host_failures,
+            host_failures: Mutex::new(HashMap::new()),
+            failure_reasons: FailureReasons::default(),
+            json_lines,
+            stdout: Mutex::new(io::stdout()),
+            with_comments,
+            comment_permits: Semaphore::new(MAX_CONCURRENT_COMMENT_REQUESTS),
+            trace_post,
+            archive,
+            storage,
+            existing_files: existing_files.map(Mutex::new),
+            events,
         }
     }
 
-    pub async fn run(self) -> Result<(), ReddSaverError> {
-        let mut full_summary =
-            Summary { media_downloaded: 0, media_skipped: 0, media_supported: 0 };
+    /// Download the media from each page of the listing as it is received
+    pub async fn run(self, listing: UnboundedReceiver<Listing>) -> Result<Summary, ReddSaverError> {
+        let mut full_summary = Summary::default();
+        // the last post of the last page that was completely processed
+        let mut cursor: Option<String> = None;
+
+        // the pages are processed concurrently, but their results are received in order
+        let downloader = &self;
+        let mut collections = listing
+            .take_while(|collection| future::ready(!downloader.is_listing_done(collection)))
+            .map(|collection| async move {
+                let page_summary = Arc::new(Mutex::new(Summary::default()));
+                downloader.pages_in_progress.lock().unwrap().push(page_summary.clone());
+                let summary = downloader
+                    .download_collection(&collection, downloader.listing_type, page_summary.clone())
+                    .await;
+                (collection, page_summary, summary)
+            })
+            .buffered(MAX_CONCURRENT_PAGES);
+
+        let started = Instant::now();
+        let mut stats = self.stats_interval.map(|period| interval_at(started + period, period));
+        loop {
+            let next = match &mut stats {
+                Some(stats) => tokio::select! {
+                    next = collections.next() => next,
+                    _ = stats.tick() => {
+                        log_progress(&self.progress(&full_summary), started);
+                        continue;
+                    }
+                },
+                None => collections.next().await,
+            };
+            let (collection, page_summary, summary) = match next {
+                Some(next) => next,
+                None => break,
+            };
+            self.pages_in_progress.lock().unwrap().retain(|page| !Arc::ptr_eq(page, &page_summary));
+            full_summary = full_summary.add(summary?);
+            if self.deadline_exceeded() {
+                // the page might have been processed only partially, so it is not a safe cursor
+                warn!("The maximum duration of the run was exceeded. Stopping...");
+                if let Some(after) = &cursor {
+                    info!("To continue from the last completed page, use: --after {}", after);
+                }
+                break;
+            }
+            cursor = collection.data.children.last().map(|post| post.data.name.clone());
+        }
+        // stop fetching the rest of the listing
+        drop(collections);
 
-        for collection in self.listing {
-            full_summary =
-                full_summary.add(self.download_collection(collection, self.listing_type).await?);
+        if let Some(archive) = &self.archive {
+            archive.finish()?;
         }
 
         info!("#####################################");
@@ -131,24 +846,100 @@ impl<'a> Downloader<'a> {
         info!("Number of supported media: {}", full_summary.media_supported);
         info!("Number of media downloaded: {}", full_summary.media_downloaded);
         info!("Number of media skipped: {}", full_summary.media_skipped);
+        info!("Number of media failed: {}", full_summary.media_failed);
+        info!("Number of media removed from the host: {}", full_summary.media_removed);
+        self.failure_reasons.report();
+        if self.with_thumbnails {
+            info!("Number of thumbnails downloaded: {}", full_summary.thumbnails_downloaded);
+        }
+        if self.thumbnail_fallback {
+            info!(
+                "Number of posts with only their thumbnail: {}",
+                full_summary.posts_thumbnail_only
+            );
+        }
+        info!("Number of posts filtered: {}", full_summary.posts_filtered);
+        info!("Number of removed posts skipped: {}", full_summary.posts_removed);
+        info!("Number of posts linking to dead hosts: {}", full_summary.posts_dead_host);
+        info!("Number of posts without media: {}", full_summary.posts_no_media);
+        if !self.skipped_subreddits.is_empty() {
+            info!(
+                "Number of posts from skipped subreddits: {}",
+                full_summary.posts_skipped_subreddit
+            );
+        }
+        if self.flair.is_some() || self.excluded_flair.is_some() {
+            info!("Number of posts skipped by their flair: {}", full_summary.posts_skipped_flair);
+        }
+        if self.since.is_some() {
+            info!("Number of posts skipped by their age: {}", full_summary.posts_too_old);
+        }
+        if self.max_per_subreddit.is_some() {
+            info!("Number of posts capped by their subreddit: {}", full_summary.posts_capped);
+        }
+        if !self.excluded_extensions.is_empty() {
+            info!("Number of media excluded by their extension: {}", full_summary.media_excluded);
+        }
+        if self.undo {
+            info!("Number of posts undone: {}", full_summary.posts_undone);
+            info!("Number of posts that could not be undone: {}", full_summary.posts_undo_failed);
+        }
+        if self.refresh_metadata {
+            info!("Number of metadata sidecars refreshed: {}", full_summary.sidecars_refreshed);
+        }
+        // the media already present is counted in dry runs too
+        if !self.should_download && !self.print_urls && !self.refresh_metadata {
+            info!("Number of media already present: {}", full_summary.media_present);
+            info!(
+                "Number of new media: {}",
+                full_summary.media_supported - full_summary.media_present
+            );
+        }
         info!("#####################################");
+
+        if self.host_stats {
+            self.report_host_stats();
+        }
+
+        if self.prune {
+            self.prune_orphans(&full_summary)?;
+        }
+
+        if let Some(file_name) = self.dedupe_report {
+            self.write_dedupe_report(file_name)?;
+        }
+
+        if let Some(file_name) = self.mapping_file {
+            self.write_mapping_file(file_name)?;
+        }
+
+        if let Some(file_name) = self.resolve_only {
+            let manifest = self.manifest.lock().unwrap();
+            fs::write(file_name, serde_json::to_string_pretty(&*manifest)?)?;
+            info!("Saved the manifest of {} posts to: {}", manifest.len(), file_name);
+        }
         info!("FIN.");
 
-        Ok(())
+        Ok(full_summary)
+    }
+
+    /// Counts of the pages completed so far, together with those of the pages that are still
+    /// being downloaded
+    fn progress(&self, completed: &Summary) -> Summary {
+        let pages_in_progress = self.pages_in_progress.lock().unwrap();
+        pages_in_progress
+            .iter()
+            .fold(*completed, |progress, page| progress.add(*page.lock().unwrap()))
     }
 
-    /// Download and save medias from Reddit in parallel
+    /// Download and save medias from Reddit in parallel. The counts of the page are kept in the
+    /// summary as the media is downloaded
     async fn download_collection(
         &self,
         collection: &Listing,
         listing_type: &ListingType,
+        summary: Arc<Mutex<Summary>>,
     ) -> Result<Summary, ReddSaverError> {
-        let summary = Arc::new(Mutex::new(Summary {
-            media_supported: 0,
-            media_downloaded: 0,
-            media_skipped: 0,
-        }));
-
         collection
             .data
             .children
@@ -156,18 +947,33 @@ impl<'a> Downloader<'a> {
             .into_iter()
             // filter out the posts where a URL is present
             // not that this application cannot download URLs linked within the text of the post
-            .filter(|item| item.data.url.is_some())
+            .filter(|item| {
+                let has_url = item.data.url.is_some();
+                if !has_url {
+                    let post_id = item.data.id.clone();
+                    self.send_event(DownloadEvent::PostProcessed { post_id });
+                }
+                has_url
+            })
             .map(|item| {
                 let summary_arc = summary.clone();
+                let post_id = item.data.id.clone();
                 // since the latency for downloading an media from the network is unpredictable
                 // we spawn a new async task for the each of the medias to be downloaded
                 async move {
+                    // the statuses of the media of the post, which tell if all of it was saved
+                    let mut media_statuses = Vec::new();
+                    let _permit = self.post_permits.acquire().await;
                     let subreddit = item.data.subreddit.borrow();
                     let post_name = item.data.name.borrow();
-                    let post_title = match item.data.title.as_ref() {
-                        Some(t) => t,
-                        None => "",
-                    };
+                    if self.prune {
+                        self.known_posts.lock().unwrap().insert(String::from(post_name));
+                    }
+
+                    if self.deadline_exceeded() {
+                        debug!("Not processing post {} since the run is out of time", post_name);
+                        return Ok(());
+                    }
 
                     let is_valid = if let Some(s) = self.subreddits.as_ref() {
                         if s.contains(&subreddit) {
@@ -179,24 +985,222 @@ impl<'a> Downloader<'a> {
                         true
                     };
 
-                    if is_valid {
+                    // the subreddits in the skip list are excluded even if they are in the subreddits to download from
+                    let is_skipped_subreddit = is_valid
+                        && self
+                            .skipped_subreddits
+                            .iter()
+                            .any(|s| s.eq_ignore_ascii_case(subreddit));
+                    if is_skipped_subreddit {
+                        debug!(
+                            "Skipping post {} from the skipped subreddit {}",
+                            post_name, subreddit
+                        );
+                        summary_arc.lock().unwrap().posts_skipped_subreddit += 1;
+                    }
+
+                    let is_skipped_flair =
+                        is_valid && !is_skipped_subreddit && !self.is_flair_allowed(&item.data);
+                    if is_skipped_flair {
+                        debug!(
+                            "Skipping post {} with the flair {:?}",
+                            post_name, item.data.link_flair_text
+                        );
+                        summary_arc.lock().unwrap().posts_skipped_flair += 1;
+                    }
+
+                    let is_too_old = is_valid
+                        && !is_skipped_subreddit
+                        && !is_skipped_flair
+                        && self.is_too_old(&item.data);
+                    if is_too_old {
+                        debug!("Skipping post {} since it is older than the age limit", post_name);
+                        summary_arc.lock().unwrap().posts_too_old += 1;
+                    }
+                    let is_skipped = is_skipped_subreddit || is_skipped_flair || is_too_old;
+
+                    // posts whose content was removed only have placeholders left, which are not worth saving
+                    let is_removed = is_valid && !is_skipped && is_removed_post(&item.data);
+                    if is_removed {
+                        info!(
+                            "Skipping post {} ({}) since its content was removed or deleted",
+                            post_name,
+                            permalink_url(&item.data)
+                        );
+                        summary_arc.lock().unwrap().posts_removed += 1;
+                    }
+
+                    // the media linked from hosts which have shut down is lost, so don't try to fetch it
+                    // the reddit preview of a video is still available after its host has shut down
+                    let has_fallback = self.preview_fallback && video_preview(&item.data).is_some();
+                    let dead_host = if is_valid && !is_skipped && !is_removed && !has_fallback {
+                        dead_host(&item.data)
+                    } else {
+                        None
+                    };
+                    if let Some(host) = dead_host {
+                        warn!(
+                            "Skipping post {} ({}) since it links to the known dead host {}",
+                            post_name,
+                            permalink_url(&item.data),
+                            host
+                        );
+                        summary_arc.lock().unwrap().posts_dead_host += 1;
+                    }
+
+                    let has_no_media = is_valid
+                        && !is_skipped
+                        && !is_removed
+                        && dead_host.is_none()
+                        && has_placeholder_url(&item.data);
+                    if has_no_media {
+                        debug!("Skipping post {} since it has no media", post_name);
+                        summary_arc.lock().unwrap().posts_no_media += 1;
+                    }
+
+                    // only the posts that would otherwise be downloaded count towards the cap
+                    let is_capped = is_valid
+                        && !is_skipped
+                        && !is_removed
+                        && dead_host.is_none()
+                        && !has_no_media
+                        && !self.take_subreddit_slot(subreddit);
+                    if is_capped {
+                        debug!(
+                            "Skipping post {} since the cap of the subreddit {} has been reached",
+                            post_name, subreddit
+                        );
+                        summary_arc.lock().unwrap().posts_capped += 1;
+                    }
+
+                    let traced = self.is_traced(&item.data);
+                    if traced {
+                        info!(
+                            "[trace {}] valid: {}, skipped: {}, removed: {}, dead host: {:?}, no media: {}, capped: {}",
+                            item.data.id,
+                            is_valid,
+                            is_skipped,
+                            is_removed,
+                            dead_host,
+                            has_no_media,
+                            is_capped
+                        );
+                    }
+
+                    if is_valid
+                        && !is_skipped
+                        && !is_removed
+                        && dead_host.is_none()
+                        && !has_no_media
+                        && !is_capped
+                    {
                         debug!("Subreddit VALID: {} present in {:#?}", subreddit, subreddit);
 
-                        let supported_media_items = get_media(item.data.borrow()).await?;
+                        // the media of a post linking to another post is in the linked post
+                        let linked_post = self.linked_post(&item.data).await;
+                        let media_post = linked_post.as_ref().unwrap_or(&item.data);
+                        if traced && linked_post.is_some() {
+                            info!(
+                                "[trace {}] resolving the media of the linked post {}",
+                                item.data.id, media_post.id
+                            );
+                        }
+                        let supported_media_items = get_media(
+                            &self.client,
+                            media_post,
+                            &self.probe_cache,
+                            &self.redgifs_token,
+                            ResolveOptions {
+                                prefer_mp4: self.prefer_mp4,
+                                with_thumbnails: self.with_thumbnails,
+                                no_audio: self.no_audio,
+                                preview_fallback: self.preview_fallback,
+                                traced,
+                            },
+                        )
+                        .await?;
 
+                        // the thumbnail of a gif is named after the file of the gif preceding it
+                        let mut gif_file_name: Option<String> = None;
+                        // the comments are saved next to the first media of the post that is present
+                        let mut present_file_name: Option<String> = None;
                         for supported_media in supported_media_items {
-                            let media_urls = &supported_media.components;
                             let media_type = supported_media.media_type;
+                            // the components of a reddit video all have the extension of the video
+                            let (media_urls, excluded): (Vec<String>, Vec<String>) =
+                                supported_media.components.into_iter().partition(|url| {
+                                    !self.is_extension_excluded(&media_extension(url, &media_type))
+                                });
+                            if !excluded.is_empty() {
+                                debug!("Skipping media from urls {:?} by their extension", excluded);
+                                summary_arc.lock().unwrap().media_excluded += excluded.len() as i32;
+                            }
+                            if media_urls.is_empty() {
+                                continue;
+                            }
+                            let media_urls = &media_urls;
+
+                            if media_type == MediaType::GfycatThumbnail {
+                                if let Some(gif_file_name) = &gif_file_name {
+                                    let file_name = thumbnail_file_name(gif_file_name);
+                                    self.remember_file(&file_name);
+                                    if self.should_download && !self.is_stored(&file_name).await {
+                                        let status =
+                                            self.save_media(&media_urls[0], &file_name).await?;
+                                        if status == MediaStatus::Downloaded {
+                                            summary_arc.lock().unwrap().thumbnails_downloaded += 1;
+                                            self.move_media(&file_name).await;
+                                        }
+                                    }
+                                }
+                                continue;
+                            }
+
+                            let is_gallery = media_type == MediaType::RedditImage
+                                && item.data.gallery_data.is_some();
                             let mut media_files = Vec::new();
 
                             // the number of components in the supported media is the number available for download
-                            summary_arc.lock().unwrap().media_supported += supported_media.components.len() as i32;
+                            summary_arc.lock().unwrap().media_supported +=
+                                media_urls.len() as i32;
+
+                            // the components of a reddit video are not needed again once they have been combined
+                            if self.should_download
+                                && media_type == MediaType::RedditVideoWithAudio
+                                && media_urls.len() == 2
+                            {
+                                let combined_file_name = self.combined_file_name(
+                                    media_urls.first().unwrap(),
+                                    &item.data,
+                                    &media_type,
+                                );
+                                self.remove_overwritten(&combined_file_name);
+                                if self
+                                    .storage
+                                    .exists(&self.storage_path(&combined_file_name))
+                                    .await
+                                {
+                                    debug!(
+                                        "Video {} already combined. Skipping...",
+                                        combined_file_name
+                                    );
+                                    self.remember_mapping(
+                                        &item.data,
+                                        &media_urls[0],
+                                        &combined_file_name,
+                                    );
+                                    summary_arc.lock().unwrap().media_skipped +=
+                                        media_urls.len() as i32;
+                                    media_statuses.push(MediaStatus::Skipped);
+                                    continue;
+                                }
+                            }
 
                             let mut local_skipped = 0;
+                            let mut aria2c_downloads = Vec::new();
                             for (index, url) in media_urls.iter().enumerate() {
                                 let mut item_index = format!("{}", index);
-                                let mut extension =
-                                    String::from(url.split('.').last().unwrap_or("unknown")).replace("/", "_");
+                                let extension = media_extension(url, &media_type);
 
                                 // if the media is a reddit video, they have separate audio and video components.
                                 // to differentiate this from albums, which use the regular _0, _1, etc indices,
@@ -205,36 +1209,100 @@ impl<'a> Downloader<'a> {
                                 if media_type == MediaType::RedditVideoWithAudio {
                                     item_index = format!("component_{}", index);
                                 };
-                                // some reddit videos don't have the mp4 extension, eg. DASH_<A>_<B>
-                                // explicitly adding an mp4 extension to make it easy to recognize in the finder
-                                if (media_type == MediaType::RedditVideoWithoutAudio
-                                    || media_type == MediaType::RedditVideoWithAudio)
-                                    && !extension.ends_with(".mp4") {
-                                    extension = format!("{}.{}", extension, ".mp4");
-                                }
                                 let file_name = self.generate_file_name(
                                     &url,
-                                    &subreddit,
+                                    &item.data,
+                                    &media_type,
                                     &extension,
-                                    &post_name,
-                                    &post_title,
                                     &item_index,
+                                    is_gallery,
                                 );
+                                self.remember_file(&file_name);
 
                                 if self.should_download {
-                                    let status = save_or_skip(url, &file_name);
-                                    // update the summary statistics based on the status
-                                    match status.await? {
-                                        MediaStatus::Downloaded => {
-                                            summary_arc.lock().unwrap().media_downloaded += 1;
+                                    self.wait_for_throttle().await;
+                                    self.remove_overwritten(&file_name);
+                                    let status = if self.is_stored(&file_name).await {
+                                        debug!(
+                                            "Media from url {} already downloaded. Skipping...",
+                                            url
+                                        );
+                                        Some(MediaStatus::Skipped)
+                                    } else if media_type == MediaType::RedditVideoHls {
+                                        Some(save_hls_or_skip(
+                                            url,
+                                            &file_name,
+                                            self.ffmpeg_available,
+                                            self.temp_dir,
+                                            &self.failure_reasons,
+                                        )
+                                        .await?)
+                                    } else if self.use_aria2c {
+                                        // the components are handed over to aria2c together once all of them are known
+                                        if self.existing_file_name(&file_name).is_some() {
+                                            debug!(
+                                                "Media from url {} already downloaded. Skipping...",
+                                                url
+                                            );
+                                            Some(MediaStatus::Skipped)
+                                        } else {
+                                            aria2c_downloads.push((url.clone(), file_name.clone()));
+                                            None
                                         }
-                                        MediaStatus::Skipped => {
+                                    } else {
+                                        Some(self.save_media(url, &file_name).await?)
+                                    };
+                                    // update the summary statistics based on the status
+                                    if let Some(status) = status {
+                                        if status != MediaStatus::Downloaded {
                                             local_skipped += 1;
-                                            summary_arc.lock().unwrap().media_skipped += 1;
+                                        }
+                                        if status == MediaStatus::Downloaded
+                                            || status == MediaStatus::Skipped
+                                        {
+                                            present_file_name.get_or_insert(file_name.clone());
+                                        }
+                                        media_statuses.push(status);
+                                        self.update_summary(
+                                            &summary_arc,
+                                            status,
+                                            url,
+                                            &file_name,
+                                            &item.data,
+                                            &media_type,
+                                        )
+                                        .await;
+                                    }
+                                } else if self.summary_only {
+                                    // only classify the media as new or present, it is reported in the summary
+                                    if self.existing_file_name(&file_name).is_some() {
+                                        summary_arc.lock().unwrap().media_present += 1;
+                                    }
+                                    summary_arc.lock().unwrap().media_skipped += 1;
+                                } else if self.refresh_metadata {
+                                    // the media is never downloaded, only the sidecars of the
+                                    // media already present are written again
+                                    if let Some(saved) = saved_file_name(&file_name) {
+                                        if media_type.is_image() {
+                                            write_xmp_sidecar(&saved, &item.data);
+                                            summary_arc.lock().unwrap().sidecars_refreshed += 1;
                                         }
                                     }
+                                    summary_arc.lock().unwrap().media_skipped += 1;
+                                } else if self.print_urls {
+                                    // only the URL is written to stdout so that the output can be
+                                    // piped into other tools. logs continue to be written to stderr
+                                    println!("{}", &url);
+                                    summary_arc.lock().unwrap().media_skipped += 1;
                                 } else {
-                                    info!("Media available at URL: {}", &url);
+                                    // show what a real run would download and what it would skip
+                                    match self.existing_file_name(&file_name) {
+                                        Some(saved) => {
+                                            info!("EXISTS {} at {}", &url, saved);
+                                            summary_arc.lock().unwrap().media_present += 1;
+                                        }
+                                        None => info!("NEW {} to {}", &url, file_name),
+                                    }
                                     summary_arc.lock().unwrap().media_skipped += 1;
                                 }
 
@@ -243,257 +1311,2391 @@ impl<'a> Downloader<'a> {
                                 media_files.push(file_name);
                             }
 
+                            if !aria2c_downloads.is_empty() {
+                                let statuses =
+                                    save_with_aria2c(&aria2c_downloads)
+                                        .await?;
+                                for ((url, file_name), status) in
+                                    aria2c_downloads.iter().zip(statuses)
+                                {
+                                    if status == MediaStatus::Failed {
+                                        self.failure_reasons.record("aria2c could not download it");
+                                    } else {
+                                        self.convert_image(file_name);
+                                    }
+                                    if status != MediaStatus::Downloaded {
+                                        local_skipped += 1;
+                                    } else {
+                                        present_file_name.get_or_insert(file_name.clone());
+                                    }
+                                    media_statuses.push(status);
+                                    self.update_summary(
+                                        &summary_arc,
+                                        status,
+                                        url,
+                                        file_name,
+                                        &item.data,
+                                        &media_type,
+                                    )
+                                    .await;
+                                }
+                            }
+
+                            if media_type == MediaType::GfycatGif {
+                                gif_file_name = media_files.first().cloned();
+                            }
+
                             debug!("Media type: {:#?}", media_type);
                             debug!("Media files: {:?}", media_files.len());
                             debug!("Locally skipped items: {:?}", local_skipped);
 
+                            // the resolved media is only recorded in the manifest, to be downloaded later
+                            if self.resolve_only.is_some() {
+                                let combined_file_name = if (media_type
+                                    == MediaType::RedditVideoWithAudio)
+                                    && (media_files.len() == 2)
+                                {
+                                    Some(self.combined_file_name(
+                                        media_urls.first().unwrap(),
+                                        &item.data,
+                                        &media_type,
+                                    ))
+                                } else {
+                                    None
+                                };
+                                let components = media_urls
+                                    .iter()
+                                    .zip(&media_files)
+                                    .map(|(url, file_name)| ManifestComponent {
+                                        url: url.clone(),
+                                        file_name: file_name.clone(),
+                                    })
+                                    .collect();
+                                self.manifest.lock().unwrap().push(ManifestEntry {
+                                    name: String::from(post_name),
+                                    components,
+                                    combined_file_name,
+                                    hls: media_type == MediaType::RedditVideoHls,
+                                });
+                            }
+
                             if (media_type == MediaType::RedditVideoWithAudio)
                                 && (media_files.len() == 2)
-                                && (local_skipped < 2) {
-                                if self.ffmpeg_available {
-                                    debug!("Assembling components together");
-                                    let first_url = media_urls.first().unwrap();
-                                    let extension =
-                                        String::from(first_url.split('.').last().unwrap_or("unknown"));
-                                    // this generates the name of the media without the component indices
-                                    // this file name is used for saving the ffmpeg combined file
-                                    let combined_file_name = self.generate_file_name(
-                                        first_url,
-                                        &subreddit,
-                                        &extension,
-                                        &post_name,
-                                        &post_title,
-                                        "0",
-                                    );
-
-                                    let temporary_dir = tempdir()?;
-                                    let temporary_file_name = temporary_dir.path().join("combined.mp4");
+                                && (local_skipped < 2)
+                            {
+                                debug!("Assembling components together");
+                                // this generates the name of the media without the component indices
+                                // this file name is used for saving the combined file
+                                let combined_file_name = self.combined_file_name(
+                                    media_urls.first().unwrap(),
+                                    &item.data,
+                                    &media_type,
+                                );
 
-                                    if self.should_download {
-                                        // if the media is a reddit video and it has two components, then we
-                                        // need to assemble them into one file using ffmpeg.
-                                        let mut command = Command::new("ffmpeg");
-                                        for media_file in &media_files {
-                                            command.arg("-i").arg(media_file);
-                                        }
-                                        command.arg("-c").arg("copy")
-                                            .arg("-map").arg("1:a")
-                                            .arg("-map").arg("0:v")
-                                            .arg(&temporary_file_name);
-
-                                        debug!("Executing command: {:#?}", command);
-                                        let output = command.output()?;
-
-                                        // check the status code of the ffmpeg command. if the command is unsuccessful,
-                                        // display the error and skip combining the media.
-                                        if output.status.success() {
-                                            debug!("Successfully combined into temporary file: {:?}", temporary_file_name);
-                                            debug!("Renaming file: {} -> {}", temporary_file_name.display(), combined_file_name);
-                                            fs::rename(&temporary_file_name, &combined_file_name)?;
-                                        } else {
-                                            // if we encountered an error, we will write logs from ffmpeg into a new log file
-                                            let log_file_name = self.generate_file_name(
-                                                first_url,
-                                                &subreddit,
-                                                "log",
-                                                &post_name,
-                                                &post_title,
-                                                "0",
-                                            );
-                                            let err = String::from_utf8(output.stderr).unwrap();
-                                            warn!("Could not combine video {} and audio {}. Saving log to: {}", 
-                                                media_urls.first().unwrap(), media_urls.last().unwrap(), log_file_name);
-                                            fs::write(log_file_name, err)?;
+                                if self.should_download {
+                                    if self.ffmpeg_available {
+                                        combine_components(
+                                            &media_files,
+                                            &combined_file_name,
+                                            self.temp_dir,
+                                        )
+                                        .await?;
+                                    } else {
+                                        remux_components(&media_files, &combined_file_name);
+                                    }
+                                    if let Some(format) = self.extract_audio {
+                                        let audio_file_name = Path::new(&combined_file_name)
+                                            .with_extension(format!("audio.{}", format.extension()))
+                                            .to_string_lossy()
+                                            .into_owned();
+                                        if extract_audio(
+                                            &media_files[1],
+                                            &audio_file_name,
+                                            format,
+                                            self.temp_dir,
+                                        )
+                                        .await?
+                                        {
+                                            self.move_media(&audio_file_name).await;
                                         }
                                     }
-                                } else {
-                                    warn!("Skipping combining the individual components since ffmpeg is not installed");
+                                    self.remember_mapping(
+                                        &item.data,
+                                        &media_urls[0],
+                                        &combined_file_name,
+                                    );
+                                    self.move_video(&media_files, &combined_file_name).await;
                                 }
                             } else {
                                 debug!("Skipping combining reddit video.");
                             }
                         }
-                    } else {
+
+                        // the archive at least has the thumbnail of the posts whose media is lost
+                        if self.thumbnail_fallback
+                            && self.should_download
+                            && present_file_name.is_none()
+                        {
+                            if let Some(file_name) = self.save_thumbnail(&item.data).await? {
+                                summary_arc.lock().unwrap().posts_thumbnail_only += 1;
+                                present_file_name = Some(file_name);
+                            }
+                        }
+
+                        if let (Some(limit), Some(file_name)) =
+                            (self.with_comments, &present_file_name)
+                        {
+                            self.save_comments(&item.data, file_name, limit).await;
+                        }
+                    } else if !is_valid {
                         debug!(
                             "Subreddit INVALID!: {} NOT present in {:#?}",
                             subreddit, self.subreddits
                         );
+                        summary_arc.lock().unwrap().posts_filtered += 1;
                     }
 
-                    if self.undo {
-                        self.user.undo(post_name, listing_type).await?;
+                    // the media of the post is done with, so its file names are no longer in flight
+                    self.release_file_names(post_name);
+
+                    if self.prune && is_fully_saved(&media_statuses) {
+                        self.saved_posts.lock().unwrap().insert(String::from(post_name));
+                    }
+
+                    // the posts left out of the run or whose media was not all saved are kept in the listing,
+                    // so that they are downloaded the next time
+                    let undo = self.undo && (!self.should_download || is_fully_saved(&media_statuses));
+                    if self.undo && !undo {
+                        debug!("Not undoing {} for post {} since its media was not saved", listing_type, post_name);
+                    }
+
+                    if undo && !self.undo_disabled.load(Ordering::Relaxed) {
+                        if let Some(user) =
+                            self.user.and_then(|user| undo_user(user, self.should_download))
+                        {
+                            match user.undo(post_name, listing_type).await {
+                                Ok(_) => {
+                                    info!("Undid {} for post: {}", listing_type, post_name);
+                                    summary_arc.lock().unwrap().posts_undone += 1;
+                                }
+                                // the other posts are still undone, the failed ones are reported
+                                Err(e @ ReddSaverError::UndoFailed(..)) => {
+                                    error!("{}", e);
+                                    summary_arc.lock().unwrap().posts_undo_failed += 1;
+                                }
+                                Err(ReddSaverError::InsufficientScope(scope))
+                                    if self.continue_without_undo =>
+                                {
+                                    if !self.undo_disabled.swap(true, Ordering::Relaxed) {
+                                        warn!(
+                                            "The app is not authorized with the `{}` scope. Continuing without undoing {} for any of the posts",
+                                            scope, listing_type
+                                        );
+                                    }
+                                }
+                                Err(e) => return Err(e),
+                            }
+                        } else {
+                            info!("Dry run, not undoing {} for post: {}", listing_type, post_name);
+                        }
                     }
 
                     Ok::<(), ReddSaverError>(())
                 }
-            })
-            .collect::<FuturesUnordered<_>>()
-            .try_collect::<()>()
-            .await?;
+                .inspect(move |_| self.send_event(DownloadEvent::PostProcessed { post_id }))
+            })
+            .collect::<FuturesUnordered<_>>()
+            .try_collect::<()>()
+            .await?;
+
+        let local_summary = *summary.lock().unwrap();
+
+        debug!("Collection statistics: ");
+        debug!("Number of supported media: {}", local_summary.media_supported);
+        debug!("Number of media downloaded: {}", local_summary.media_downloaded);
+        debug!("Number of media skipped: {}", local_summary.media_skipped);
+        debug!("Number of media failed: {}", local_summary.media_failed);
+        debug!("Number of media removed from the host: {}", local_summary.media_removed);
+        debug!("Number of posts filtered: {}", local_summary.posts_filtered);
+        debug!("Number of removed posts skipped: {}", local_summary.posts_removed);
+        debug!("Number of posts linking to dead hosts: {}", local_summary.posts_dead_host);
+
+        Ok(local_summary)
+    }
+
+    /// Resolve the post that a post links to with a share link or a short link, eg:
+    /// https://www.reddit.com/r/pics/s/a1B2c3D4e5 or https://redd.it/k1ng2h. Share links are
+    /// resolved by following their redirect to the permalink of the post, and the post is then
+    /// fetched from the API, since the linking post has none of the media
+    async fn linked_post(&self, post: &PostData) -> Option<PostData> {
+        let url = post.url.as_deref()?;
+        let user = self.user?;
+        let is_short_link = Url::parse(url).ok()?.host_str() == Some("redd.it");
+        if !is_short_link && !is_share_link(url) {
+            return None;
+        }
+
+        if let Some(linked_post) = self.linked_posts.lock().unwrap().get(url) {
+            debug!("Using cached post for link {}", url);
+            return linked_post.clone();
+        }
+
+        let id = if is_short_link {
+            post_id_from_url(url)
+        } else {
+            match self
+                .client
+                .get(url)
+                .header(USER_AGENT, get_user_agent_string(None, None))
+                .send()
+                .await
+            {
+                Ok(response) => post_id_from_url(response.url().as_str()),
+                Err(e) => {
+                    warn!("Could not follow the share link {}: {}", url, e);
+                    None
+                }
+            }
+        };
+
+        // a post linking to itself has its own media
+        let linked_post = match id.filter(|id| post.name != format!("t3_{}", id)) {
+            Some(id) => match user.post(&id).await {
+                Ok(listing) => listing.data.children.into_iter().next().map(|p| p.data),
+                Err(e) => {
+                    warn!("Could not fetch the post {} linked from {}: {}", id, url, e);
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(p) = &linked_post {
+            debug!("Resolved {} to the post {}", url, p.name);
+        }
+        self.linked_posts.lock().unwrap().insert(String::from(url), linked_post.clone());
+
+        linked_post
+    }
+
+    /// Download the thumbnail of the post in place of its media, returning the name of the file
+    /// it was saved to. Thumbnails saved in an earlier run are not downloaded again
+    async fn save_thumbnail(&self, post: &PostData) -> Result<Option<String>, ReddSaverError> {
+        let url = match thumbnail_url(post) {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let extension = thumbnail_extension(&url);
+        let file_name = self.generate_file_name(
+            &url,
+            post,
+            &MediaType::RedditThumbnail,
+            &extension,
+            "thumbnail",
+            false,
+        );
+        self.remember_file(&file_name);
+        if self.is_stored(&file_name).await {
+            debug!("Thumbnail of post {} already downloaded. Skipping...", post.name);
+            return Ok(None);
+        }
+
+        if self.save_media(&url, &file_name).await? != MediaStatus::Downloaded {
+            return Ok(None);
+        }
+        info!("Saved only the thumbnail of post {}", permalink_url(post));
+        self.move_media(&file_name).await;
+        Ok(Some(file_name))
+    }
+
+    /// Save the top comments of the post as Markdown in the directory of its media, named after
+    /// the post, eg: t3_k1ng2h.comments.md. The comments saved in an earlier run are kept
+    async fn save_comments(&self, post: &PostData, media_file_name: &str, limit: u32) {
+        let user = match self.user {
+            Some(user) => user,
+            None => return,
+        };
+        let file_name = Path::new(media_file_name)
+            .with_file_name(format!("{}.comments.md", post.name))
+            .to_string_lossy()
+            .into_owned();
+        if self.is_stored(&file_name).await {
+            debug!("Comments of post {} already saved. Skipping...", post.name);
+            return;
+        }
+
+        let comments = {
+            let _permit = self.comment_permits.acquire().await;
+            user.comments(&post.id, limit, COMMENT_DEPTH).await
+        };
+        let markdown = match comments {
+            Ok(comments) => render_comments(post, &permalink_url(post), &comments),
+            Err(e) => {
+                warn!("Could not fetch the comments of post {}: {}", permalink_url(post), e);
+                return;
+            }
+        };
+        // the directory might have been removed once the media was moved to the archive
+        let result = Path::new(&file_name)
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&file_name, markdown));
+        match result {
+            Ok(_) => {
+                info!("Saved the comments of post {} to {}", post.name, file_name);
+                self.move_media(&file_name).await;
+            }
+            Err(e) => {
+                warn!("Could not save the comments of post {} to {}: {}", post.name, file_name, e)
+            }
+        }
+    }
+
+    /// Whether the decisions made for the post are logged, see --trace-post. The ID can be
+    /// given with or without the t3_ prefix
+    fn is_traced(&self, post: &PostData) -> bool {
+        self.trace_post.is_some_and(|id| id.trim_start_matches("t3_") == post.id)
+    }
+
+    /// Count the post towards the cap of its subreddit, returning false once the cap has been
+    /// reached. The counts are shared by all the pages, so the cap holds for the whole run
+    fn take_subreddit_slot(&self, subreddit: &str) -> bool {
+        let max = match self.max_per_subreddit {
+            Some(max) => max,
+            None => return true,
+        };
+        let mut counts = self.subreddit_counts.lock().unwrap();
+        let count = counts.entry(subreddit.to_lowercase()).or_insert(0);
+        if *count >= max {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Check if none of the posts in the rest of the listing can be downloaded, so that it need
+    /// not be fetched. This is the case once all the subreddits to download from have reached
+    /// their cap, or when the posts are listed newest first and the page is older than the age limit
+    fn is_listing_done(&self, collection: &Listing) -> bool {
+        if let (Some(subreddits), Some(max)) = (self.subreddits.as_ref(), self.max_per_subreddit) {
+            let counts = self.subreddit_counts.lock().unwrap();
+            if subreddits.iter().all(|s| counts.get(&s.to_lowercase()).is_some_and(|c| *c >= max)) {
+                info!("All the subreddits have reached their cap of {} posts. Stopping...", max);
+                return true;
+            }
+        }
+        let posts = &collection.data.children;
+        if self.newest_first && !posts.is_empty() && posts.iter().all(|p| self.is_too_old(&p.data))
+        {
+            info!("The rest of the listing is older than the age limit. Stopping...");
+            return true;
+        }
+        false
+    }
+
+    /// Check if the media saved with the extension is not to be downloaded
+    fn is_extension_excluded(&self, extension: &str) -> bool {
+        let saved = extension.rsplit('.').next().unwrap_or(extension).to_lowercase();
+        self.excluded_extensions.contains(&saved)
+    }
+
+    /// Check if the post was created before the age limit. The time at which the post was saved
+    /// or upvoted is not known, so the posts whose creation time is unknown are kept
+    fn is_too_old(&self, post: &PostData) -> bool {
+        match (self.since, post.created_utc.as_f64()) {
+            (Some(since), Some(created)) => (created as i64) < since,
+            _ => false,
+        }
+    }
+
+    /// Check the flair of the post against the flair filters, ignoring its case. Posts
+    /// without a flair never match the flair to download
+    fn is_flair_allowed(&self, post: &PostData) -> bool {
+        let flair = post.link_flair_text.as_deref().map(str::trim);
+        let matches = |filter: &str| flair.is_some_and(|f| f.eq_ignore_ascii_case(filter.trim()));
+        self.flair.is_none_or(matches) && !self.excluded_flair.is_some_and(matches)
+    }
+
+    /// Generate the name of the reddit video without the component indices,
+    /// used for saving the combined video and audio
+    fn combined_file_name(
+        &self,
+        first_url: &str,
+        post: &PostData,
+        media_type: &MediaType,
+    ) -> String {
+        let extension = String::from(first_url.split('.').last().unwrap_or("unknown"));
+        let file_name =
+            self.generate_file_name(first_url, post, media_type, &extension, "0", false);
+        self.remember_file(&file_name);
+        file_name
+    }
+
+    /// Download the media with the built-in downloader, keeping track of the downloads from each host
+    async fn save_media(&self, url: &str, file_name: &str) -> Result<MediaStatus, ReddSaverError> {
+        self.remove_overwritten(file_name);
+        let host = media_host(url);
+        if saved_file_name(file_name).is_none() && self.is_host_down(&host) {
+            debug!("Skipping media from url {} since {} appears to be down", url, host);
+            self.failure_reasons.record("Host appears to be down");
+            return Ok(MediaStatus::Failed);
+        }
+
+        let started = Instant::now();
+        let status = if self.existing_files.is_none() {
+            save_or_skip(
+                &self.client,
+                url,
+                file_name,
+                &self.rate_limiter,
+                self.checksums,
+                &self.failure_reasons,
+            )
+            .await?
+        } else if self.existing_file_name(file_name).is_some() {
+            debug!("Media from url {} already downloaded. Skipping...", url);
+            MediaStatus::Skipped
+        } else {
+            download_media(
+                &self.client,
+                file_name,
+                url,
+                &self.rate_limiter,
+                self.checksums,
+                &self.failure_reasons,
+            )
+            .await?
+        };
+        if status == MediaStatus::Downloaded {
+            self.convert_image(file_name);
+        }
+        self.record_host_status(&host, status);
+        // the same media can be linked from more than one post in the run
+        if let (Some(existing_files), MediaStatus::Downloaded) = (&self.existing_files, status) {
+            if let Some(saved) = saved_file_name(file_name) {
+                existing_files.lock().unwrap().insert(PathBuf::from(saved));
+            }
+        }
+
+        if self.host_stats && status == MediaStatus::Downloaded {
+            // the extension of the file might have been corrected after downloading it
+            let bytes = saved_file_name(file_name)
+                .and_then(|f| fs::metadata(f).ok())
+                .map_or(0, |m| m.len());
+            let download = HostStats { downloads: 1, bytes, duration: started.elapsed() };
+            let mut host_downloads = self.host_downloads.lock().unwrap();
+            let stats = host_downloads.entry(host).or_default();
+            *stats = stats.add(download);
+        }
+
+        Ok(status)
+    }
+
+    /// Convert the downloaded image to the format of all the images. The original is kept
+    /// if it could not be converted
+    fn convert_image(&self, file_name: &str) {
+        let (format, saved) = match (self.convert_images, saved_file_name(file_name)) {
+            (Some(format), Some(saved)) => (format, saved),
+            _ => return,
+        };
+        match convert_image(&saved, format) {
+            Ok(Some(converted)) => {
+                debug!("Converted the image {} to {}", saved, converted);
+                if let Some(algorithm) = self.checksums {
+                    if let Err(e) = replace_checksum(&saved, &converted, algorithm) {
+                        warn!("Could not record the checksum of {}: {}", converted, e);
+                    }
+                }
+            }
+            Ok(None) => (),
+            Err(e) => warn!("Could not convert the image {}, keeping it as it is: {}", saved, e),
+        }
+    }
+
+    /// Check if too many downloads from the host have failed in a row for it to be up
+    fn is_host_down(&self, host: &str) -> bool {
+        self.max_host_failures > 0
+            && self
+                .host_failures
+                .lock()
+                .unwrap()
+                .get(host)
+                .is_some_and(|f| *f >= self.max_host_failures)
+    }
+
+    /// Keep track of the consecutive failed downloads from the host. A download that the host
+    /// answered, even for media that has been removed, shows that the host is up
+    fn record_host_status(&self, host: &str, status: MediaStatus) {
+        let mut host_failures = self.host_failures.lock().unwrap();
+        match status {
+            MediaStatus::Failed => {
+                let failures = host_failures.entry(String::from(host)).or_insert(0);
+                *failures += 1;
+                if *failures == self.max_host_failures {
+                    warn!(
+                        "{} appears to be down, the last {} downloads from it failed. \
+                         Skipping the rest of its media",
+                        host, failures
+                    );
+                }
+            }
+            MediaStatus::Downloaded | MediaStatus::Removed => {
+                host_failures.remove(host);
+            }
+            MediaStatus::Skipped => (),
+        }
+    }
+
+    /// Log a table of the downloads from each host, with the hosts that most was downloaded from first
+    fn report_host_stats(&self) {
+        let host_downloads = self.host_downloads.lock().unwrap();
+        let mut hosts: Vec<(&String, &HostStats)> = host_downloads.iter().collect();
+        hosts.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.bytes));
+
+        info!("Downloads by host:");
+        info!("{:<24} {:>9} {:>12} {:>10}", "HOST", "DOWNLOADS", "TOTAL (MB)", "AVG MB/s");
+        for (host, stats) in hosts {
+            let seconds = stats.duration.as_secs_f64();
+            let rate =
+                if seconds > 0.0 { stats.bytes as f64 / BYTES_PER_MB / seconds } else { 0.0 };
+            info!(
+                "{:<24} {:>9} {:>12.1} {:>10.2}",
+                host,
+                stats.downloads,
+                stats.bytes as f64 / BYTES_PER_MB,
+                rate
+            );
+        }
+    }
+
+    /// Path of the media relative to the data directory, which is how the storage refers to it
+    fn storage_path(&self, file_name: &str) -> String {
+        let path = Path::new(file_name);
+        path.strip_prefix(self.data_directory).unwrap_or(path).to_string_lossy().into_owned()
+    }
+
+    /// Whether the media has already been stored, possibly with a corrected extension
+    async fn is_stored(&self, file_name: &str) -> bool {
+        if self.existing_files.is_some() {
+            return self.existing_file_name(file_name).is_some();
+        }
+        for candidate in file_name_candidates(file_name) {
+            if self.storage.exists(&self.storage_path(&candidate)).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Name of the file the media was saved to, if present. The listing of the data directory
+    /// is used when there is one, otherwise the file is looked up on the filesystem
+    fn existing_file_name(&self, file_name: &str) -> Option<String> {
+        match &self.existing_files {
+            Some(existing_files) => {
+                let existing_files = existing_files.lock().unwrap();
+                file_name_candidates(file_name)
+                    .find(|candidate| existing_files.contains(Path::new(candidate)))
+            }
+            None => saved_file_name(file_name),
+        }
+    }
+
+    /// Move the downloaded media, along with its sidecars, from the data directory into the
+    /// archive or the storage. The path of the media in either is its path in the data directory
+    async fn move_media(&self, file_name: &str) {
+        let sidecars = [
+            format!("{}.url", file_name),
+            Path::new(file_name).with_extension("xmp").to_string_lossy().into_owned(),
+        ];
+        let files = std::iter::once(String::from(file_name))
+            .chain(sidecars.iter().cloned())
+            .filter(|f| check_path_present(f));
+        for file in files {
+            let name = self.storage_path(&file);
+            match &self.archive {
+                Some(archive) => match archive.add(&file, &name) {
+                    Ok(_) => {
+                        debug!("Moved {} into the archive", file);
+                        fs::remove_file(&file).ok();
+                    }
+                    Err(e) => warn!("Could not add {} to the archive: {}", file, e),
+                },
+                None => {
+                    if let Err(e) = self.storage.put(&name, &file).await {
+                        warn!("Could not store {}: {}", file, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Move the combined reddit video, or its components if they could not be combined
+    async fn move_video(&self, media_files: &[String], combined_file_name: &str) {
+        if check_path_present(combined_file_name) {
+            self.move_media(combined_file_name).await;
+            // the components are not needed once the combined video has been moved elsewhere
+            if !check_path_present(combined_file_name) {
+                for media_file in media_files {
+                    fs::remove_file(media_file).ok();
+                }
+            }
+        } else {
+            for media_file in media_files {
+                self.move_media(media_file).await;
+            }
+        }
+    }
+
+    /// Keep track of the media of the posts in the listing so that it is not pruned. The
+    /// extension is left out since it could have been corrected after downloading the media
+    fn remember_file(&self, file_name: &str) {
+        if self.prune {
+            self.known_files.lock().unwrap().insert(Path::new(file_name).with_extension(""));
+        }
+    }
+
+    /// Report, and optionally delete, the files in the data directory which do not belong to any of
+    /// the posts in the listing. Only the files named by reddsaver are considered. Nothing is
+    /// deleted unless the media of all the posts was saved
+    fn prune_orphans(&self, summary: &Summary) -> Result<(), ReddSaverError> {
+        let known_posts = self.known_posts.lock().unwrap();
+        let known_files = self.known_files.lock().unwrap();
+        // the media of the posts that were not saved, eg. since they were skipped or their media
+        // failed, could have been saved under another name in an earlier run and be reported too
+        let unsaved_posts = known_posts.len() - self.saved_posts.lock().unwrap().len();
+        let delete_orphans = self.delete_orphans && unsaved_posts == 0;
+        if self.delete_orphans && !delete_orphans {
+            warn!(
+                "Only reporting the orphaned files since the media of {} posts was not saved",
+                unsaved_posts
+            );
+        }
+        // the media of posts that were skipped, eg. since they link to dead hosts, is not resolved.
+        // it can not be told apart from that of the posts not in the listing if it has a hashed name
+        let check_hashed = summary.posts_removed + summary.posts_dead_host == 0;
+        if !check_hashed {
+            warn!("Not pruning media with hashed names since some posts were skipped");
+        }
+
+        let mut orphans = Vec::new();
+        for path in list_files(Path::new(self.data_directory))? {
+            // sidecars, such as img-<hash>.jpg.url or img-<hash>.xmp, belong to the media they are named after
+            let mut stem = path.clone();
+            let mut known = false;
+            while stem.extension().is_some() && !known {
+                stem = stem.with_extension("");
+                known = known_files.contains(&stem);
+            }
+            if known {
+                continue;
+            }
+            let orphaned = match post_name_of_file(&path) {
+                Some(name) => !known_posts.contains(&name),
+                None => check_hashed && is_hashed_file_name(&path),
+            };
+            if orphaned {
+                orphans.push(path);
+            }
+        }
+
+        for orphan in &orphans {
+            if delete_orphans {
+                info!("Deleting orphaned file: {}", orphan.display());
+                fs::remove_file(orphan)?;
+            } else {
+                info!("Orphaned file: {}", orphan.display());
+            }
+        }
+        if delete_orphans {
+            info!("Deleted {} orphaned files", orphans.len());
+        } else if !orphans.is_empty() {
+            info!("Found {} orphaned files. Use --prune --yes to delete them", orphans.len());
+        }
+
+        Ok(())
+    }
+
+    /// Update the summary statistics based on the status of the media
+    async fn update_summary(
+        &self,
+        summary: &Mutex<Summary>,
+        status: MediaStatus,
+        url: &str,
+        file_name: &str,
+        post: &PostData,
+        media_type: &MediaType,
+    ) {
+        // the result is written before the media is moved to the archive or the storage
+        if self.json_lines {
+            self.write_json_line(&status, url, file_name, post);
+        }
+
+        // the media already downloaded in an earlier run can be a duplicate as well
+        if self.dedupe_report.is_some()
+            && (status == MediaStatus::Downloaded || status == MediaStatus::Skipped)
+        {
+            if let Some(saved) = saved_file_name(file_name) {
+                self.remember_content(&saved, post);
+            }
+        }
+
+        // the components of reddit videos are mapped once they have been combined
+        if (status == MediaStatus::Downloaded || status == MediaStatus::Skipped)
+            && *media_type != MediaType::RedditVideoWithAudio
+        {
+            let saved = saved_file_name(file_name);
+            self.remember_mapping(post, url, saved.as_deref().unwrap_or(file_name));
+        }
+
+        self.send_event(DownloadEvent::of_media(&status, url, file_name));
+        match status {
+            MediaStatus::Downloaded => {
+                summary.lock().unwrap().media_downloaded += 1;
+                // the extension of the file might have been corrected after downloading it
+                let saved = saved_file_name(file_name);
+                let file_name = saved.as_deref().unwrap_or(file_name);
+                if self.record_source {
+                    record_source(file_name, url, &post.permalink);
+                }
+                if self.xmp_sidecar && media_type.is_image() {
+                    write_xmp_sidecar(file_name, post);
+                }
+                if let Some(hook) = self.post_download_hook {
+                    run_post_download_hook(hook, file_name, post).await;
+                }
+                // the components of reddit videos are moved once they have been combined
+                if *media_type != MediaType::RedditVideoWithAudio {
+                    self.move_media(file_name).await;
+                }
+            }
+            MediaStatus::Skipped => {
+                summary.lock().unwrap().media_skipped += 1;
+            }
+            // the reason has already been logged, the permalink is for finding the post on reddit
+            MediaStatus::Failed => {
+                warn!("Could not download media from url {} of post {}", url, permalink_url(post));
+                summary.lock().unwrap().media_failed += 1;
+                record_failure(self.data_directory, url, file_name);
+            }
+            MediaStatus::Removed => {
+                warn!("Media from url {} of post {} was removed", url, permalink_url(post));
+                summary.lock().unwrap().media_removed += 1;
+            }
+        }
+    }
+
+    /// Send the event to the channel of the events, if one was given. The events are dropped once
+    /// the receiver has been closed, which does not stop the run
+    fn send_event(&self, event: DownloadEvent) {
+        if let Some(events) = &self.events {
+            events.send(event).ok();
+        }
+    }
+
+    /// Write the result of the media to stdout as a single JSON line, flushing it immediately
+    /// so that the results can be followed while the run is in progress
+    fn write_json_line(&self, status: &MediaStatus, url: &str, file_name: &str, post: &PostData) {
+        // the extension of the file might have been corrected after downloading it
+        let saved = saved_file_name(file_name);
+        let result = MediaResult {
+            post_id: post.id.clone(),
+            subreddit: post.subreddit.clone(),
+            url: String::from(url),
+            path: saved.clone().unwrap_or_else(|| String::from(file_name)),
+            status: String::from(status.name()),
+            bytes: saved.and_then(|s| fs::metadata(s).ok()).map(|m| m.len()),
+        };
+        let line = match serde_json::to_string(&result) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Could not serialize the result of {}: {}", url, e);
+                return;
+            }
+        };
+        let mut stdout = self.stdout.lock().unwrap();
+        if let Err(e) = writeln!(stdout, "{}", line).and_then(|_| stdout.flush()) {
+            warn!("Could not write the result of {} to stdout: {}", url, e);
+        }
+    }
+
+    /// Keep track of the hash of the content of the media to find the posts with the same media
+    fn remember_content(&self, file_name: &str, post: &PostData) {
+        let mut context = md5::Context::new();
+        let hashed = File::open(file_name).and_then(|mut file| io::copy(&mut file, &mut context));
+        if let Err(e) = hashed {
+            warn!("Could not hash {} for the dedupe report: {}", file_name, e);
+            return;
+        }
+        let media = DuplicateMedia {
+            id: post.id.clone(),
+            permalink: permalink_url(post),
+            file_name: String::from(file_name),
+        };
+        let hash = format!("{:x}", context.compute());
+        self.content_hashes.lock().unwrap().entry(hash).or_default().push(media);
+    }
+
+    /// Write the groups of media with the same content that belong to more than one post
+    fn write_dedupe_report(&self, file_name: &str) -> Result<(), ReddSaverError> {
+        let content_hashes = self.content_hashes.lock().unwrap();
+        let mut groups: Vec<DuplicateGroup> = content_hashes
+            .iter()
+            .filter(|(_, media)| media.iter().any(|m| m.id != media[0].id))
+            .map(|(hash, media)| DuplicateGroup { hash: hash.clone(), media: media.clone() })
+            .collect();
+        // the same order in every run, so that the reports can be compared
+        groups.sort_by(|a, b| a.media[0].file_name.cmp(&b.media[0].file_name));
+
+        fs::write(file_name, serde_json::to_string_pretty(&groups)?)?;
+        info!("Saved {} groups of duplicate media to: {}", groups.len(), file_name);
+
+        Ok(())
+    }
+
+    /// Keep track of the path of the media for the mapping file
+    fn remember_mapping(&self, post: &PostData, url: &str, file_name: &str) {
+        if self.mapping_file.is_some() {
+            let mapping = (post.id.clone(), String::from(url), String::from(file_name));
+            self.mappings.lock().unwrap().push(mapping);
+        }
+    }
+
+    /// Write the mappings of the media to the file as tab separated values, sorted so that the
+    /// files of different runs can be compared
+    fn write_mapping_file(&self, file_name: &str) -> Result<(), ReddSaverError> {
+        let mut mappings = self.mappings.lock().unwrap();
+        mappings.sort();
+        mappings.dedup();
+
+        let mut contents = String::from("post_id\turl\tlocal_path\n");
+        for (id, url, path) in mappings.iter() {
+            contents.push_str(&format!("{}\t{}\t{}\n", id, url, path));
+        }
+        fs::write(file_name, contents)?;
+        info!("Saved the paths of {} media to: {}", mappings.len(), file_name);
+
+        Ok(())
+    }
+
+    /// Check if the maximum duration of the run is over
+    fn deadline_exceeded(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Wait till the next download can be started. Since the downloads run concurrently,
+    /// this spaces out the start of each download by at least the throttle duration
+    async fn wait_for_throttle(&self) {
+        if self.throttle.as_millis() == 0 {
+            return;
+        }
+
+        let start = {
+            let mut next_download = self.next_download.lock().unwrap();
+            let start = std::cmp::max(*next_download, Instant::now());
+            *next_download = start + self.throttle;
+            start
+        };
+        delay_until(start).await;
+    }
+
+    /// Generate a file name in the right format that Reddsaver expects, which is not given to
+    /// the media of another URL unless the collision policy allows it
+    fn generate_file_name(
+        &self,
+        url: &str,
+        post: &PostData,
+        media_type: &MediaType,
+        extension: &str,
+        index: &str,
+        is_gallery: bool,
+    ) -> String {
+        let file_name = self.base_file_name(url, post, media_type, extension, index, is_gallery);
+        self.claim_file_name(file_name, url, &post.name)
+    }
+
+    /// Remove the media saved with the file name if it has been claimed by the media of another
+    /// URL, so that the later media replaces it. This is only done when downloading
+    fn remove_overwritten(&self, file_name: &str) {
+        let key = Path::new(file_name).with_extension("");
+        if !self.overwritten_files.lock().unwrap().remove(&key) {
+            return;
+        }
+        if let Some(existing) = self.existing_file_name(file_name) {
+            debug!("Removing {} to replace it with the media of another URL", existing);
+            fs::remove_file(&existing).ok();
+            if let Some(existing_files) = &self.existing_files {
+                existing_files.lock().unwrap().remove(Path::new(&existing));
+            }
+        }
+    }
+
+    /// Claim the file name for the media at the URL. The same media is always given the same name,
+    /// while the names given to the media of other URLs are handled by the collision policy. The
+    /// names claimed by the media of other URLs that is still being downloaded are never shared
+    fn claim_file_name(&self, file_name: String, url: &str, post_name: &str) -> String {
+        let path = Path::new(&file_name);
+        let stem = path.with_extension("");
+        let extension = path.extension().map(|e| e.to_string_lossy().into_owned());
+
+        let mut file_claims = self.file_claims.lock().unwrap();
+        let mut in_flight_files = self.in_flight_files.lock().unwrap();
+        let mut candidate = file_name.clone();
+        let mut suffix = 0;
+        loop {
+            // the extension of the file might be corrected after downloading it
+            let key = Path::new(&candidate).with_extension("");
+            let claimed = match file_claims.get(&key) {
+                None => {
+                    file_claims.insert(key.clone(), String::from(url));
+                    in_flight_files.insert(key, String::from(post_name));
+                    return candidate;
+                }
+                Some(claimed) if claimed == url => return candidate,
+                Some(claimed) => claimed.clone(),
+            };
+            // the media still being downloaded would share its file, and the file it is
+            // downloaded to, with the later media, so it is never replaced or skipped for it
+            let policy = if in_flight_files.contains_key(&key) {
+                debug!("Media of {} is still being downloaded, adding a suffix", candidate);
+                CollisionPolicy::Suffix
+            } else {
+                self.on_collision
+            };
+            match policy {
+                CollisionPolicy::Overwrite => {
+                    warn!(
+                        "Media from url {} has the same file name as the media from url {}. \
+                         Overwriting {}",
+                        url, claimed, candidate
+                    );
+                    // the saved media is only removed right before the later media is downloaded
+                    self.overwritten_files.lock().unwrap().insert(key.clone());
+                    file_claims.insert(key.clone(), String::from(url));
+                    in_flight_files.insert(key, String::from(post_name));
+                    return candidate;
+                }
+                CollisionPolicy::Skip => {
+                    warn!(
+                        "Media from url {} has the same file name as the media from url {}. \
+                         Keeping {}",
+                        url, claimed, candidate
+                    );
+                    return candidate;
+                }
+                CollisionPolicy::Suffix => {
+                    suffix += 1;
+                    candidate = match &extension {
+                        Some(extension) => format!("{} ({}).{}", stem.display(), suffix, extension),
+                        None => format!("{} ({})", stem.display(), suffix),
+                    };
+                }
+            }
+        }
+    }
+
+    /// Release the file names claimed by the media of the post once it is done with, after which
+    /// the media of other URLs given the same names is handled by the collision policy
+    fn release_file_names(&self, post_name: &str) {
+        self.in_flight_files.lock().unwrap().retain(|_, claimed_by| claimed_by != post_name);
+    }
+
+    /// The file name of the media following the naming options, before any collisions
+    fn base_file_name(
+        &self,
+        url: &str,
+        post: &PostData,
+        media_type: &MediaType,
+        extension: &str,
+        index: &str,
+        is_gallery: bool,
+    ) -> String {
+        let subreddit: &str = &post.subreddit;
+        let name: &str = &post.name;
+        let title = post.title.as_deref().unwrap_or("");
+        // the title is transliterated before it is truncated and sanitized, eg: café to cafe
+        let title = if self.ascii_filenames { deunicode(title) } else { String::from(title) };
+
+        if let Some(template) = self.output_template {
+            let created = post
+                .created_utc
+                .as_f64()
+                .and_then(|created| Utc.timestamp_opt(created as i64, 0).single());
+            let path = expand_template(template, |token| {
+                let value = match token {
+                    "subreddit" => String::from(subreddit),
+                    "id" => String::from(&post.id),
+                    "name" => String::from(name),
+                    // truncate the title to make sure file names don't exceed operating
+                    // system maximums, similar to human readable names
+                    "title" => title.chars().take(200).collect(),
+                    "index" => String::from(index),
+                    "hash" => format!("{:x}", md5::compute(url)),
+                    "ext" => String::from(extension),
+                    "listing" => self.listing_type.to_string(),
+                    "year" => created.map_or(String::from("unknown"), |c| c.year().to_string()),
+                    "month" => {
+                        created.map_or(String::from("unknown"), |c| format!("{:02}", c.month()))
+                    }
+                    "day" => created.map_or(String::from("unknown"), |c| format!("{:02}", c.day())),
+                    _ => return None,
+                };
+                Some(sanitize_path_component(&value))
+            });
+            return format!("{}/{}", self.data_directory, path);
+        }
+
+        // optionally keep the media from each listing type (saved, upvoted, etc.) and each
+        // type of media (images, gifs, etc.) in its own folder, eg: data/saved/images/pics
+        let mut directory = String::from(self.data_directory);
+        if self.segregate_by_listing {
+            directory = format!("{}/{}", directory, self.listing_type);
+        }
+        if self.segregate_by_type {
+            directory = format!("{}/{}", directory, media_type.directory());
+        }
+        let directory = format!("{}/{}", directory, subreddit);
+
+        // the images of a gallery are numbered in their own directory, eg: data/pics/t3_abc/0.jpg
+        if self.gallery_subfolder && is_gallery {
+            return format!("{}/{}/{}.{}", directory, name.replace(".", "_"), index, extension);
+        }
+
+        return if !self.use_human_readable {
+            // create a hash for the media using the URL the media is located at
+            // this helps to make sure the media download always writes the same file
+            // name irrespective of how many times it's run. If run more than once, the
+            // media is overwritten by this method
+            let hash = md5::compute(url);
+            format!(
+                // TODO: Fixme, use appropriate prefix
+                "{}/img-{:x}.{}",
+                directory, hash, extension
+            )
+        } else {
+            let canonical_title: String = title
+                .to_lowercase()
+                .chars()
+                // to make sure file names don't exceed operating system maximums, truncate at 200
+                // you could possibly stretch beyond 200, but this is a conservative estimate that
+                // leaves 55 bytes for the name string
+                .take(200)
+                .enumerate()
+                .map(|(_, c)| {
+                    if c.is_whitespace()
+                        || c == '.'
+                        || c == '/'
+                        || c == '\\'
+                        || c == ':'
+                        || c == '='
+                    {
+                        '_'
+                    } else {
+                        c
+                    }
+                })
+                .collect();
+            // create a canonical human readable file name using the post's title
+            // note that the name of the post is something of the form t3_<randomstring>
+            let canonical_name: String =
+                if index == "0" { String::from(name) } else { format!("{}_{}", name, index) }
+                    .replace(".", "_");
+            format!("{}/{}_{}.{}", directory, canonical_title, canonical_name, extension)
+        };
+    }
+}
+
+/// Print the hosts the media can be downloaded from, and the ones which have shut down
+pub fn print_supported_hosts() {
+    println!("Supported hosts:");
+    for (hosts, media) in SUPPORTED_HOSTS.iter() {
+        println!("  {}: {}", hosts.join(", "), media);
+    }
+    println!("Hosts that have shut down, the posts linking to them are skipped:");
+    println!("  {}", DEAD_HOSTS.join(", "));
+}
+
+/// Check that the output template only uses known tokens. If the template does not contain
+/// the extension, it is appended to the template so that the media has the right extension
+pub fn check_output_template(template: &str) -> Result<String, ReddSaverError> {
+    let mut unknown_token = None;
+    expand_template(template, |token| {
+        if !OUTPUT_TEMPLATE_TOKENS.contains(&token) && unknown_token.is_none() {
+            unknown_token = Some(String::from(token));
+        }
+        None
+    });
+
+    match unknown_token {
+        Some(token) => Err(ReddSaverError::UnknownTemplateToken(token)),
+        None if template.contains("{ext}") => Ok(String::from(template)),
+        None => Ok(format!("{}.{{ext}}", template)),
+    }
+}
+
+/// Reddit hosted preview of the video linked from the post, if there is one
+fn video_preview(post: &PostData) -> Option<&RedditVideo> {
+    post.preview.as_ref()?.reddit_video_preview.as_ref()
+}
+
+/// The thumbnail of the post, unless it is a placeholder such as self or default instead of
+/// an http(s) URL
+fn thumbnail_url(post: &PostData) -> Option<String> {
+    let thumbnail = post.thumbnail.as_deref()?.trim().replace("&amp;", "&");
+    let scheme = Url::parse(&thumbnail).ok()?.scheme().to_lowercase();
+    Some(thumbnail).filter(|_| scheme == "http" || scheme == "https")
+}
+
+/// Extension of the thumbnail in the path of its URL, or jpg when the path has none
+fn thumbnail_extension(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| Path::new(u.path()).extension().map(|e| e.to_string_lossy().to_lowercase()))
+        .unwrap_or_else(|| String::from(JPG_EXTENSION))
+}
+
+/// Check if the URL of the post is only a placeholder, which cannot be parsed or downloaded
+fn has_placeholder_url(post: &PostData) -> bool {
+    match &post.url {
+        Some(u) => PLACEHOLDER_URLS.iter().any(|p| u.trim().eq_ignore_ascii_case(p)),
+        None => false,
+    }
+}
+
+/// Check if the content of the post was removed by the moderators or deleted by the author
+fn is_removed_post(post: &PostData) -> bool {
+    let is_marker = |text: &Option<String>| match text {
+        Some(t) => REMOVED_POST_MARKERS.contains(&t.trim()),
+        None => false,
+    };
+    let is_removed_media = match &post.url {
+        Some(u) => REMOVED_MEDIA_URLS.contains(&u.as_str()),
+        None => false,
+    };
+
+    is_marker(&post.title) || is_marker(&post.selftext) || is_removed_media
+}
+
+/// URL of the mp4 variant of an animated image from the previews of the post
+fn mp4_variant(post: &PostData) -> Option<String> {
+    let image = post.preview.as_ref()?.images.first()?;
+    let mp4 = image.variants.as_ref()?.mp4.as_ref()?;
+    Some(mp4.source.url.replace("&amp;", "&"))
+}
+
+/// URL of the full size preview image of the post, if any
+fn preview_source(post: &PostData) -> Option<String> {
+    let image = post.preview.as_ref()?.images.first()?;
+    Some(image.source.url.replace("&amp;", "&"))
+}
+
+/// Collect the URLs of all the images in a reddit gallery. The gallery data has the images in
+/// order, while the metadata has the format of each image. Some galleries only have the
+/// metadata, in which case the images are ordered by their media ID
+async fn gallery_media(
+    client: &Client,
+    post: &PostData,
+) -> Result<Option<SupportedMedia>, ReddSaverError> {
+    let metadata = post.media_metadata.as_ref();
+    let media_ids: Vec<String> = match (&post.gallery_data, metadata) {
+        (Some(gallery), _) => gallery.items.iter().map(|item| item.media_id.clone()).collect(),
+        (None, Some(metadata)) => {
+            let mut media_ids: Vec<String> = metadata.keys().cloned().collect();
+            media_ids.sort();
+            media_ids
+        }
+        (None, None) => return Ok(None),
+    };
+
+    let mut image_urls = Vec::new();
+    for media_id in media_ids {
+        let item = metadata.and_then(|metadata| metadata.get(&media_id));
+        if item.and_then(|item| item.status.as_deref()) == Some("failed") {
+            warn!(
+                "Skipping image {} of gallery {} ({}) since reddit could not process it",
+                media_id,
+                post.name,
+                permalink_url(post)
+            );
+            continue;
+        }
+        // reconstruct the image URL from the media ID of each gallery item
+        let extension = match item.and_then(|item| item.m.as_deref()) {
+            Some(mime) => gallery_image_extension(mime),
+            None => probe_gallery_image_extension(client, &media_id).await,
+        };
+        image_urls.push(format!("https://{}/{}.{}", REDDIT_IMAGE_SUBDOMAIN, media_id, extension));
+    }
+    if image_urls.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(SupportedMedia { components: image_urls, media_type: MediaType::RedditImage }))
+}
+
+/// Media embedded in a post that is not a gallery, from the source URL of the only entry
+/// in the metadata of the post
+fn embedded_media(post: &PostData) -> Option<SupportedMedia> {
+    let metadata = post.media_metadata.as_ref().filter(|metadata| metadata.len() == 1)?;
+    let item = metadata.values().next()?;
+    if item.status.as_deref() == Some("failed") {
+        return None;
+    }
+    let source = item.s.as_ref()?;
+    let (url, media_type) = match (&source.gif, &source.u) {
+        (Some(gif), _) => (gif, MediaType::RedditGif),
+        (None, Some(image)) => (image, MediaType::RedditImage),
+        (None, None) => return None,
+    };
+    Some(SupportedMedia { components: vec![url.replace("&amp;", "&")], media_type })
+}
+
+/// Extension of a gallery image from its mime type in the metadata, eg: image/png
+fn gallery_image_extension(mime: &str) -> &'static str {
+    match MEDIA_CONTENT_TYPES.iter().find(|(m, _)| *m == mime) {
+        Some((_, extension)) => extension,
+        // reddit uses image/jpg for jpeg images
+        None => JPG_EXTENSION,
+    }
+}
+
+/// Find the extension of a gallery image without metadata by checking which of the
+/// image URLs exists. Most of the images are jpg, so it is used when none of them exist
+async fn probe_gallery_image_extension(client: &Client, media_id: &str) -> &'static str {
+    for extension in [JPG_EXTENSION, PNG_EXTENSION, GIF_EXTENSION].iter() {
+        let url = format!("https://{}/{}.{}", REDDIT_IMAGE_SUBDOMAIN, media_id, extension);
+        match client.head(&url).send().await {
+            Ok(response) if response.status().is_success() => return extension,
+            Ok(response) => debug!("Gallery image {} is not available: {}", url, response.status()),
+            Err(e) => debug!("Could not check gallery image {}: {}", url, e),
+        }
+    }
+    JPG_EXTENSION
+}
+
+/// Request the reddit image at the URL with the other image extensions, when there is no image
+/// at the URL. The extension of the gallery images without metadata is only a guess, so the
+/// image could still be there as a png or a gif
+async fn alternate_reddit_image(client: &Client, url: &str) -> Option<Response> {
+    let extensions = [JPG_EXTENSION, PNG_EXTENSION, GIF_EXTENSION];
+    let (base, extension) = url.rsplit_once('.')?;
+    if !extensions.contains(&extension) {
+        return None;
+    }
+    for alternate in extensions.iter().filter(|e| **e != extension) {
+        let alternate_url = format!("{}.{}", base, alternate);
+        match client.get(&alternate_url).send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!("Image {} is available as {}", url, alternate_url);
+                return Some(response);
+            }
+            Ok(response) => {
+                debug!("Image {} is not available: {}", alternate_url, response.status())
+            }
+            Err(e) => debug!("Could not check image {}: {}", alternate_url, e),
+        }
+    }
+    None
+}
+
+/// Build the link to the gif of a giphy post or media link
+fn giphy_gif(url: &Url) -> Option<SupportedMedia> {
+    let media_id = giphy_media_id(url.path())?;
+    Some(SupportedMedia {
+        components: vec![format!(
+            "https://{}/{}/{}/giphy.{}",
+            GIPHY_MEDIA_SUBDOMAIN, GIPHY_MEDIA_PATH, media_id, GIF_EXTENSION
+        )],
+        media_type: MediaType::GiphyGif,
+    })
+}
+
+/// Extract the media ID from the path of a giphy link. Media links have the ID right after
+/// `/media/`, while post links like `/gifs/funny-cat-dancing-3oEjHGr1Fhz0kyv8Ig` end the slug
+/// with the ID, possibly followed by more path segments like `/fullscreen`
+fn giphy_media_id(path: &str) -> Option<&str> {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    if let Some(position) = segments.iter().position(|s| *s == GIPHY_MEDIA_PATH) {
+        return segments.get(position + 1).copied();
+    }
+
+    let looks_like_id = |word: &&str| {
+        word.len() >= GIPHY_MIN_ID_LENGTH
+            && word.chars().all(|c| c.is_ascii_alphanumeric())
+            && word.chars().any(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+    };
+    segments
+        .iter()
+        .rev()
+        .flat_map(|segment| segment.rsplit('-'))
+        .find(looks_like_id)
+        // fall back to the last word of the slug for IDs that look like plain words
+        .or_else(|| segments.last().and_then(|s| s.rsplit('-').next()))
+}
+
+/// Find the known dead host, if any, that the post links to
+fn dead_host(post: &PostData) -> Option<&'static str> {
+    let host = post.url.as_ref().and_then(|u| Url::parse(u).ok())?.host_str()?.to_lowercase();
+    DEAD_HOSTS.iter().find(|d| host == **d || host.ends_with(&format!(".{}", d))).copied()
+}
+
+/// The user to undo the post for. A dry run should not have any side effects, so nothing is
+/// undone when not downloading and what would be undone is only logged
+fn undo_user<T>(user: T, should_download: bool) -> Option<T> {
+    Some(user).filter(|_| should_download)
+}
+
+/// Print the events of the run to stdout as JSON lines, until the downloader is done with them
+pub async fn print_download_events(mut events: UnboundedReceiver<DownloadEvent>) {
+    while let Some(event) = events.recv().await {
+        if let Ok(line) = serde_json::to_string(&event) {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Check if all the media of a post ended up saved, in this run or an earlier one. Posts whose
+/// media could not be resolved have no statuses, so they are not saved either
+fn is_fully_saved(statuses: &[MediaStatus]) -> bool {
+    !statuses.is_empty()
+        && statuses.iter().all(|s| *s == MediaStatus::Downloaded || *s == MediaStatus::Skipped)
+}
+
+/// Helper function that downloads and saves a single media from Reddit or Imgur
+async fn save_or_skip(
+    client: &Client,
+    url: &str,
+    file_name: &str,
+    rate_limiter: &RateLimiter,
+    checksums: Option<ChecksumAlgorithm>,
+    failures: &FailureReasons,
+) -> Result<MediaStatus, ReddSaverError> {
+    if saved_file_name(file_name).is_some() {
+        debug!("Media from url {} already downloaded. Skipping...", url);
+        Ok(MediaStatus::Skipped)
+    } else {
+        download_media(client, &file_name, &url, rate_limiter, checksums, failures).await
+    }
+}
+
+/// Helper function that remuxes a HLS stream into a single mp4 file using ffmpeg
+async fn save_hls_or_skip(
+    url: &str,
+    file_name: &str,
+    ffmpeg_available: bool,
+    temp_dir: &str,
+    failures: &FailureReasons,
+) -> Result<MediaStatus, ReddSaverError> {
+    if check_path_present(file_name) {
+        debug!("Media from url {} already downloaded. Skipping...", url);
+        return Ok(MediaStatus::Skipped);
+    }
+    if !ffmpeg_available {
+        warn!("Skipping HLS video from url {} since ffmpeg is not installed", url);
+        return Ok(MediaStatus::Skipped);
+    }
+
+    let directory = Path::new(file_name).parent().unwrap();
+    match fs::create_dir_all(directory) {
+        Ok(_) => (),
+        Err(_e) => return Err(ReddSaverError::CouldNotCreateDirectory),
+    }
+
+    let temporary_dir = tempdir_in(temp_dir)?;
+    let temporary_file_name = temporary_dir.path().join("remuxed.mp4");
+
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command.arg("-i").arg(url).arg("-c").arg("copy").arg(&temporary_file_name);
+    debug!("Executing command: {:#?}", command);
+    let output = command.output().await?;
+
+    if output.status.success() {
+        debug!("Renaming file: {} -> {}", temporary_file_name.display(), file_name);
+        move_file(&temporary_file_name, Path::new(file_name))?;
+        info!("Successfully saved media: {} from url {}", file_name, url);
+        Ok(MediaStatus::Downloaded)
+    } else {
+        let err = String::from_utf8_lossy(&output.stderr);
+        warn!("Could not remux HLS video from url {}: {}", url, err);
+        failures.record("ffmpeg could not remux the HLS video");
+        Ok(MediaStatus::Failed)
+    }
+}
+
+/// Log the counts of the media handled so far and the rate at which the media is downloaded
+fn log_progress(summary: &Summary, started: Instant) {
+    let minutes = started.elapsed().as_secs_f64() / 60.0;
+    info!(
+        "Progress after {}m: {} media downloaded, {} skipped, {} failed ({:.1} per minute)",
+        minutes as u64,
+        summary.media_downloaded,
+        summary.media_skipped,
+        summary.media_failed,
+        summary.media_downloaded as f64 / minutes
+    );
+}
+
+/// Assemble the video and audio components of a reddit video into one file using ffmpeg.
+/// If ffmpeg is unable to combine them, its logs are saved next to the components
+async fn combine_components(
+    media_files: &[String],
+    combined_file_name: &str,
+    temp_dir: &str,
+) -> Result<(), ReddSaverError> {
+    let temporary_dir = tempdir_in(temp_dir)?;
+    let temporary_file_name = temporary_dir.path().join("combined.mp4");
+
+    let mut command = tokio::process::Command::new("ffmpeg");
+    for media_file in media_files {
+        command.arg("-i").arg(media_file);
+    }
+    command
+        .arg("-c")
+        .arg("copy")
+        .arg("-map")
+        .arg("1:a")
+        .arg("-map")
+        .arg("0:v")
+        .arg(&temporary_file_name);
+
+    debug!("Executing command: {:#?}", command);
+    let output = command.output().await?;
+
+    // check the status code of the ffmpeg command. if the command is unsuccessful,
+    // display the error and skip combining the media.
+    if output.status.success() {
+        debug!("Successfully combined into temporary file: {:?}", temporary_file_name);
+        debug!("Renaming file: {} -> {}", temporary_file_name.display(), combined_file_name);
+        move_file(&temporary_file_name, Path::new(combined_file_name))?;
+    } else {
+        // if we encountered an error, we will write logs from ffmpeg into a new log file
+        let log_file_name = Path::new(combined_file_name).with_extension("log");
+        let err = String::from_utf8_lossy(&output.stderr);
+        warn!(
+            "Could not combine video {} and audio {}. Saving log to: {}",
+            media_files[0],
+            media_files[1],
+            log_file_name.display()
+        );
+        fs::write(log_file_name, err.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Save the audio component of a reddit video on its own, either as it is or transcoded to mp3
+/// using ffmpeg. Returns if the audio was saved, the video is kept even if it could not be
+async fn extract_audio(
+    audio_component: &str,
+    audio_file_name: &str,
+    format: AudioFormat,
+    temp_dir: &str,
+) -> Result<bool, ReddSaverError> {
+    if check_path_present(audio_file_name) {
+        debug!("Audio {} already extracted. Skipping...", audio_file_name);
+        return Ok(false);
+    }
+    if !check_path_present(audio_component) {
+        return Ok(false);
+    }
+
+    match format {
+        AudioFormat::Mp4 => {
+            fs::copy(audio_component, audio_file_name)?;
+        }
+        AudioFormat::Mp3 => {
+            let temporary_dir = tempdir_in(temp_dir)?;
+            let temporary_file_name = temporary_dir.path().join("audio.mp3");
+
+            let mut command = tokio::process::Command::new("ffmpeg");
+            command
+                .arg("-i")
+                .arg(audio_component)
+                .arg("-vn")
+                .arg("-codec:a")
+                .arg("libmp3lame")
+                .arg("-q:a")
+                .arg("2")
+                .arg(&temporary_file_name);
+            debug!("Executing command: {:#?}", command);
+            let output = command.output().await?;
+            if !output.status.success() {
+                let err = String::from_utf8_lossy(&output.stderr);
+                warn!("Could not transcode the audio {} to mp3: {}", audio_component, err);
+                return Ok(false);
+            }
+            move_file(&temporary_file_name, Path::new(audio_file_name))?;
+        }
+    }
+    info!("Successfully saved the audio of the video to: {}", audio_file_name);
+
+    Ok(true)
+}
+
+/// Assemble the video and audio components of a reddit video into one file without ffmpeg.
+/// If they cannot be combined, the components are left as they are
+fn remux_components(media_files: &[String], combined_file_name: &str) {
+    match remux(&media_files[0], &media_files[1], combined_file_name) {
+        Ok(_) => debug!("Successfully combined into: {}", combined_file_name),
+        Err(e) => {
+            warn!(
+                "Could not combine video {} and audio {} without ffmpeg: {}. Keeping the components",
+                media_files[0], media_files[1], e
+            );
+            // remove the partially written file, otherwise it would be mistaken for the combined video
+            fs::remove_file(combined_file_name).ok();
+        }
+    }
+}
+
+/// Download the media from the given urls to the given files using aria2c.
+/// The status of downloading each of the media is returned in the same order
+async fn save_with_aria2c(
+    downloads: &[(String, String)],
+) -> Result<Vec<MediaStatus>, ReddSaverError> {
+    // aria2c reads the urls and the paths to save them to from an input file of the form:
+    // <url>
+    //   dir=<directory>
+    //   out=<file name>
+    let mut input = String::new();
+    for (url, file_name) in downloads {
+        let path = Path::new(file_name);
+        let directory = path.parent().unwrap();
+        match fs::create_dir_all(directory) {
+            Ok(_) => (),
+            Err(_e) => return Err(ReddSaverError::CouldNotCreateDirectory),
+        }
+        let name = path.file_name().unwrap().to_string_lossy();
+        input.push_str(&format!("{}\n  dir={}\n  out={}\n", url, directory.display(), name));
+    }
+
+    let temporary_dir = tempdir()?;
+    let input_file_name = temporary_dir.path().join("aria2c_input.txt");
+    fs::write(&input_file_name, input)?;
+
+    let mut command = tokio::process::Command::new("aria2c");
+    command
+        .arg("--input-file")
+        .arg(&input_file_name)
+        .arg("--auto-file-renaming=false")
+        .arg("--console-log-level=warn")
+        .arg("--summary-interval=0");
+    debug!("Executing command: {:#?}", command);
+    let output = command.output().await?;
+    if !output.status.success() {
+        warn!(
+            "aria2c could not download all the media: {}",
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+    }
+
+    let mut statuses = Vec::new();
+    for (url, file_name) in downloads {
+        // aria2c leaves a control file next to the downloads that did not complete
+        let control_file_name = format!("{}.aria2", file_name);
+        let length = fs::metadata(file_name).map(|m| m.len() as usize).unwrap_or(0);
+        if check_path_present(&control_file_name) || is_truncated(file_name, length) {
+            warn!("Could not save media from url {} to {}", url, file_name);
+            // remove the partially written file, otherwise it would be
+            // skipped as already downloaded in the next run
+            fs::remove_file(file_name).ok();
+            fs::remove_file(&control_file_name).ok();
+            statuses.push(MediaStatus::Failed);
+        } else {
+            info!("Successfully saved media: {} from url {}", file_name, url);
+            statuses.push(MediaStatus::Downloaded);
+        }
+    }
+
+    Ok(statuses)
+}
+
+/// Extension of the file the media at the URL is saved to
+fn media_extension(url: &str, media_type: &MediaType) -> String {
+    // preview images carry their signature in the query string
+    let path = url.split('?').next().unwrap_or(url);
+    let mut extension = String::from(path.split('.').last().unwrap_or("unknown")).replace("/", "_");
+    // some reddit videos don't have the mp4 extension, eg. DASH_<A>_<B>
+    // explicitly adding an mp4 extension to make it easy to recognize in the finder
+    if (*media_type == MediaType::RedditVideoWithoutAudio
+        || *media_type == MediaType::RedditVideoWithAudio)
+        && !extension.ends_with(".mp4")
+    {
+        extension = format!("{}.{}", extension, ".mp4");
+    }
+    // HLS playlists are remuxed into a single mp4 file using ffmpeg and
+    // the URLs of the mp4 variants of gifs end with the query parameters
+    if *media_type == MediaType::RedditVideoHls || *media_type == MediaType::RedditGifMp4 {
+        extension = String::from(MP4_EXTENSION);
+    }
+    extension
+}
+
+/// Name of the host of the media, without the www. prefix
+fn media_host(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| u.host_str().map(|h| h.trim_start_matches("www.").to_lowercase()))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+/// Download media from the given url and save to data directory. Also create data directory if not present already
+async fn download_media(
+    client: &Client,
+    file_name: &str,
+    url: &str,
+    rate_limiter: &RateLimiter,
+    checksums: Option<ChecksumAlgorithm>,
+    failures: &FailureReasons,
+) -> Result<MediaStatus, ReddSaverError> {
+    // create directory if it does not already exist
+    // the directory is created relative to the current working directory
+    let mut status = MediaStatus::Failed;
+    let directory = Path::new(file_name).parent().unwrap();
+    match fs::create_dir_all(directory) {
+        Ok(_) => (),
+        Err(_e) => return Err(ReddSaverError::CouldNotCreateDirectory),
+    }
+
+    // the media is written to a partial file till it is complete. an interrupted download
+    // leaves the partial file behind, which is resumed from where it stopped in the next run
+    let part_file_name = format!("{}.{}", file_name, PART_EXTENSION);
+    let mut resume_from = fs::metadata(&part_file_name).map_or(0, |m| m.len());
+
+    // imgur frequently rate limits the downloads, in which case retry after waiting for a while
+    let is_imgur = url.contains(IMGUR_DOMAIN);
+    let mut attempt = 1;
+    let maybe_response = loop {
+        let mut request = client.get(url);
+        if resume_from > 0 {
+            request = request.header(RANGE, format!("bytes={}-", resume_from));
+        }
+        let maybe_response = request.send().await;
+        match maybe_response {
+            // the partial file is larger than the media, eg: the media was replaced on the host
+            Ok(ref response)
+                if resume_from > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE =>
+            {
+                debug!("The partial download of {} cannot be resumed, restarting it", url);
+                resume_from = 0;
+            }
+            Ok(ref response)
+                if is_imgur
+                    && response.status() == StatusCode::TOO_MANY_REQUESTS
+                    && attempt < IMGUR_ATTEMPTS =>
+            {
+                let delay = rate_limit_delay(response);
+                warn!(
+                    "Imgur is rate limiting the downloads. Retrying url {} in {}s",
+                    url,
+                    delay.as_secs()
+                );
+                delay_for(delay).await;
+                attempt += 1;
+            }
+            _ => break maybe_response,
+        }
+    };
+    // the extension of the gallery images without metadata is only a guess
+    let maybe_response = match maybe_response {
+        Ok(response)
+            if url.contains(REDDIT_IMAGE_SUBDOMAIN)
+                && response.status() == StatusCode::NOT_FOUND =>
+        {
+            Ok(alternate_reddit_image(client, url).await.unwrap_or(response))
+        }
+        other => other,
+    };
+    // the connection errors are not returned since the rest of the media can still be downloaded
+    if let Err(e) = &maybe_response {
+        warn!("Could not download media from url {}: {}", url, e);
+        failures.record(if e.is_timeout() {
+            "Timed out"
+        } else if e.is_connect() {
+            "Could not connect to the host"
+        } else {
+            "Network error"
+        });
+    }
+    if let Ok(response) = maybe_response {
+        debug!("URL Response: {:#?}", response);
+        // imgur redirects the images that have been removed to a placeholder image
+        if REMOVED_MEDIA_URLS.contains(&response.url().as_str()) {
+            warn!("Media from url {} has been removed from the host. Skipping...", url);
+            return Ok(MediaStatus::Removed);
+        }
+        // do not save error pages, such as a 404 for media that has been removed, as media
+        if !response.status().is_success() {
+            warn!("Could not download media from url {}: {}", url, response.status());
+            failures.record(&response.status().to_string());
+            return Ok(status);
+        }
+        let content_type =
+            response.headers().get(CONTENT_TYPE).and_then(|c| c.to_str().ok()).map(String::from);
+        let resumed = resume_from > 0 && is_resumed_response(&response, resume_from);
+        if resume_from > 0 && !resumed {
+            debug!("The download of {} cannot be resumed, restarting it", url);
+        }
+        let mut hasher = checksums.map(|c| c.hasher());
+        let maybe_output = if resumed {
+            info!("Resuming the download of {} from {} bytes", url, resume_from);
+            // the checksum covers the part downloaded before as well
+            match hasher.as_mut().map(|h| h.update_from_file(&part_file_name)) {
+                Some(Err(e)) => Err(e),
+                _ => OpenOptions::new().append(true).open(&part_file_name),
+            }
+        } else {
+            File::create(&part_file_name)
+        };
+        match maybe_output {
+            Ok(mut output) => {
+                debug!("Created a file: {}", part_file_name);
+                let written = write_response(response, &mut output, rate_limiter, hasher.as_mut())
+                    .await
+                    .map(|length| if resumed { length + resume_from as usize } else { length });
+                match written {
+                    Ok(length) if is_truncated(file_name, length) => {
+                        // empty or truncated responses from the CDN would otherwise be counted
+                        // as downloaded and be skipped as already present in every later run
+                        warn!(
+                            "Media from url {} is only {} bytes, it might be truncated. Removing {}",
+                            url, length, part_file_name
+                        );
+                        fs::remove_file(&part_file_name).ok();
+                        failures.record("Empty or truncated media");
+                    }
+                    Ok(length) => {
+                        debug!("Bytes length of the data: {:#?}", length);
+                        move_file(Path::new(&part_file_name), Path::new(file_name))?;
+                        info!("Successfully saved media: {} from url {}", file_name, url);
+                        status = MediaStatus::Downloaded;
+                        // the URL does not always tell the format of the media, eg. redirects
+                        if let Some(corrected) =
+                            content_type.and_then(|c| corrected_file_name(file_name, &c))
+                        {
+                            info!("Media is not of its extension. Renaming to: {}", corrected);
+                            if let Err(e) = fs::rename(file_name, &corrected) {
+                                warn!("Could not rename {} to {}: {}", file_name, corrected, e);
+                            }
+                        }
+                        if let (Some(algorithm), Some(hasher)) = (checksums, hasher) {
+                            let saved = saved_file_name(file_name);
+                            let saved_name = saved.as_deref().unwrap_or(file_name);
+                            if let Err(e) = record_checksum(saved_name, &hasher.finish(), algorithm)
+                            {
+                                warn!("Could not record the checksum of {}: {}", saved_name, e);
+                            }
+                        }
+                    }
+                    Err(_e) => {
+                        // the partial file is kept so that the download can be resumed
+                        error!("Could not save media from url {} to {}", url, file_name);
+                        failures.record("Download interrupted");
+                    }
+                }
+            }
+            Err(_) => {
+                warn!("Could not create a file with the name: {}. Skipping", file_name);
+                failures.record("Could not create the file");
+            }
+        }
+    }
+
+    Ok(status)
+}
+
+/// Name of the file with the extension matching the content type of the media, if the
+/// extension of the file clearly does not match it
+fn corrected_file_name(file_name: &str, content_type: &str) -> Option<String> {
+    let mime = content_type.split(';').next()?.trim().to_lowercase();
+    let (_, extension) = MEDIA_CONTENT_TYPES.iter().find(|(m, _)| *m == mime)?;
+    let path = Path::new(file_name);
+    let current = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    match current.as_deref() {
+        Some(current) if current == *extension => None,
+        Some("jpeg") if *extension == JPG_EXTENSION => None,
+        _ => Some(path.with_extension(extension).to_string_lossy().into_owned()),
+    }
+}
+
+/// Replace the checksum of the original file with that of the file it has been replaced by
+fn replace_checksum(
+    original: &str,
+    file_name: &str,
+    algorithm: ChecksumAlgorithm,
+) -> Result<(), ReddSaverError> {
+    let mut hasher = algorithm.hasher();
+    hasher.update_from_file(file_name)?;
+    forget_checksum(original, algorithm)?;
+    record_checksum(file_name, &hasher.finish(), algorithm)
+}
+
+/// Name of the file the media was saved to, if present. The media might have been
+/// saved with a different extension if its content type did not match the expected one
+fn saved_file_name(file_name: &str) -> Option<String> {
+    file_name_candidates(file_name).find(|candidate| check_path_present(candidate))
+}
+
+/// Names the media could have been saved with, starting with the expected one
+fn file_name_candidates(file_name: &str) -> impl Iterator<Item = String> + '_ {
+    let path = Path::new(file_name);
+    std::iter::once(String::from(file_name)).chain(
+        MEDIA_CONTENT_TYPES.iter().map(move |(_, extension)| {
+            path.with_extension(extension).to_string_lossy().into_owned()
+        }),
+    )
+}
+
+/// Time to wait before retrying a rate limited request, based on the headers of the response
+fn rate_limit_delay(response: &Response) -> Duration {
+    let header =
+        |name: &str| response.headers().get(name)?.to_str().ok()?.trim().parse::<u64>().ok();
+    let now = Utc::now().timestamp() as u64;
+    let seconds = match header("retry-after").or_else(|| header("x-ratelimit-reset")) {
+        // the reset time is either the number of seconds to wait or the unix timestamp to wait till
+        Some(s) if s > now / 2 => s.saturating_sub(now),
+        Some(s) => s,
+        None => IMGUR_RATE_LIMIT_DELAY_SECS,
+    };
+    Duration::from_secs(seconds.min(IMGUR_MAX_RATE_LIMIT_DELAY_SECS))
+}
+
+/// Check if the size of the saved media is too small for it to be a complete media file
+fn is_truncated(file_name: &str, length: usize) -> bool {
+    let minimum_size = if file_name.ends_with(MP4_EXTENSION) { MIN_VIDEO_SIZE } else { 1 };
+    length < minimum_size
+}
+
+/// Write the body of the response to the file as it is received, one chunk at a time
+async fn write_response(
+    mut response: Response,
+    output: &mut File,
+    rate_limiter: &RateLimiter,
+    mut hasher: Option<&mut Hasher>,
+) -> Result<usize, ReddSaverError> {
+    let mut length = 0;
+    while let Some(chunk) = response.chunk().await? {
+        rate_limiter.acquire(chunk.len()).await;
+        output.write_all(&chunk)?;
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&chunk);
+        }
+        length += chunk.len();
+    }
+
+    Ok(length)
+}
+
+/// Check if the response continues the partial download from where it stopped. Servers
+/// that do not support ranges respond with the whole media instead
+fn is_resumed_response(response: &Response, resume_from: u64) -> bool {
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        return false;
+    }
+    // the range is of the form `bytes <start>-<end>/<total>`
+    let range = match response.headers().get(CONTENT_RANGE).and_then(|r| r.to_str().ok()) {
+        Some(range) => range,
+        None => return false,
+    };
+    let (start, total) = match range.trim_start_matches("bytes ").split_once('-') {
+        Some((start, rest)) => (start.parse::<u64>().ok(), rest.rsplit('/').next()),
+        None => (None, None),
+    };
+    start == Some(resume_from)
+        && total.and_then(|t| t.parse::<u64>().ok()).is_some_and(|t| t > resume_from)
+}
+
+/// Append a media that could not be downloaded to the failures log in the data directory
+fn record_failure(data_directory: &str, url: &str, file_name: &str) {
+    let failure = FailedDownload { url: String::from(url), file_name: String::from(file_name) };
+    let log_file_name = format!("{}/{}", data_directory, FAILURES_LOG_FILE_NAME);
+    // all the downloads are processed on the same task, so the writes will not interleave
+    let result = serde_json::to_string(&failure).map_err(io::Error::from).and_then(|line| {
+        let mut log_file = OpenOptions::new().create(true).append(true).open(&log_file_name)?;
+        writeln!(log_file, "{}", line)
+    });
+    if let Err(e) = result {
+        warn!("Could not record failed download of {} to {}: {}", url, log_file_name, e);
+    }
+}
+
+/// Full URL of the post on reddit, included in the warnings so that the post can be found
+/// to save its media manually
+fn permalink_url(post: &PostData) -> String {
+    format!("https://{}{}", REDDIT_PERMALINK_HOST, post.permalink)
+}
+
+/// Record where the media was downloaded from, as extended attributes of the file when
+/// the filesystem supports them and in a `.url` sidecar file otherwise
+fn record_source(file_name: &str, url: &str, permalink: &str) {
+    let permalink = format!("https://{}{}", REDDIT_PERMALINK_HOST, permalink);
+    let result = xattr::set(file_name, XATTR_ORIGIN_URL, url.as_bytes())
+        .and_then(|_| xattr::set(file_name, XATTR_REFERRER_URL, permalink.as_bytes()));
+    if let Err(e) = result {
+        debug!("Could not set extended attributes on {}: {}. Using a sidecar file", file_name, e);
+        let sidecar_file_name = format!("{}.url", file_name);
+        if let Err(e) = fs::write(&sidecar_file_name, format!("{}\n{}\n", url, permalink)) {
+            warn!("Could not record the source of {} to {}: {}", file_name, sidecar_file_name, e);
+        }
+    }
+}
+
+/// Run the post download hook with the path of the file, the ID of the post and its permalink
+/// as the arguments. These are also available to the hook in the environment variables
+/// REDDSAVER_FILE, REDDSAVER_POST_ID and REDDSAVER_PERMALINK. A failing hook does not fail the run
+async fn run_post_download_hook(hook: &str, file_name: &str, post: &PostData) {
+    let permalink = permalink_url(post);
+    let mut command = tokio::process::Command::new(hook);
+    command
+        .arg(file_name)
+        .arg(&post.id)
+        .arg(&permalink)
+        .env("REDDSAVER_FILE", file_name)
+        .env("REDDSAVER_POST_ID", &post.id)
+        .env("REDDSAVER_PERMALINK", &permalink);
+    debug!("Executing command: {:#?}", command);
+    match command.status().await {
+        Ok(status) if status.success() => (),
+        Ok(status) => warn!("Post download hook {} failed for {}: {}", hook, file_name, status),
+        Err(e) => warn!("Could not run the post download hook {} for {}: {}", hook, file_name, e),
+    }
+}
+
+/// Write a minimal XMP sidecar next to the image, eg: img-<hash>.xmp for img-<hash>.jpg, with the
+/// title of the post as the description, the permalink as the source and the creation date
+fn write_xmp_sidecar(file_name: &str, post: &PostData) {
+    let escape = |value: &str| {
+        value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+    };
+    let permalink = permalink_url(post);
+    let created = post
+        .created_utc
+        .as_f64()
+        .and_then(|created| Utc.timestamp_opt(created as i64, 0).single())
+        .map_or(String::new(), |c| {
+            format!("   <photoshop:DateCreated>{}</photoshop:DateCreated>\n", c.to_rfc3339())
+        });
+    let contents = format!(
+        r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/">
+   <dc:description>
+    <rdf:Alt>
+     <rdf:li xml:lang="x-default">{}</rdf:li>
+    </rdf:Alt>
+   </dc:description>
+   <dc:source>{}</dc:source>
+{}   <xmp:MetadataDate>{}</xmp:MetadataDate>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+"#,
+        escape(post.title.as_deref().unwrap_or("")),
+        escape(&permalink),
+        created,
+        Utc::now().to_rfc3339()
+    );
+
+    let sidecar_file_name = Path::new(file_name).with_extension("xmp");
+    if let Err(e) = fs::write(&sidecar_file_name, contents) {
+        warn!("Could not write the XMP sidecar {}: {}", sidecar_file_name.display(), e);
+    }
+}
+
+/// Download the media listed in a manifest saved by resolving only. The reddit videos
+/// are combined once both of their components are present
+pub async fn download_from_manifest(
+    data_directory: &str,
+    manifest_file_name: &str,
+    max_rate: Option<u64>,
+    checksums: Option<ChecksumAlgorithm>,
+    ffmpeg_available: bool,
+    temp_dir: &str,
+    client: &Client,
+) -> Result<Summary, ReddSaverError> {
+    let rate_limiter = RateLimiter::new(max_rate);
+    let failures = FailureReasons::default();
+    let mut summary = Summary::default();
+
+    let contents = fs::read_to_string(manifest_file_name)?;
+    let manifest: Vec<ManifestEntry> = serde_json::from_str(&contents)?;
+    for entry in manifest {
+        let mut local_skipped = 0;
+        for component in &entry.components {
+            summary.media_supported += 1;
+            let status = if entry.hls {
+                save_hls_or_skip(
+                    &component.url,
+                    &component.file_name,
+                    ffmpeg_available,
+                    temp_dir,
+                    &failures,
+                )
+                .await?
+            } else {
+                save_or_skip(
+                    client,
+                    &component.url,
+                    &component.file_name,
+                    &rate_limiter,
+                    checksums,
+                    &failures,
+                )
+                .await?
+            };
+            match status {
+                MediaStatus::Downloaded => summary.media_downloaded += 1,
+                MediaStatus::Skipped => {
+                    local_skipped += 1;
+                    summary.media_skipped += 1;
+                }
+                MediaStatus::Failed => {
+                    local_skipped += 1;
+                    summary.media_failed += 1;
+                    record_failure(data_directory, &component.url, &component.file_name);
+                }
+                MediaStatus::Removed => {
+                    local_skipped += 1;
+                    summary.media_removed += 1;
+                }
+            }
+        }
+
+        if let Some(combined_file_name) = entry.combined_file_name.as_ref() {
+            if local_skipped < entry.components.len() {
+                let media_files: Vec<String> =
+                    entry.components.iter().map(|c| c.file_name.clone()).collect();
+                if ffmpeg_available {
+                    combine_components(&media_files, combined_file_name, temp_dir).await?;
+                } else {
+                    remux_components(&media_files, combined_file_name);
+                }
+            }
+        }
+    }
 
-        let local_summary = *summary.lock().unwrap();
+    info!("#####################################");
+    info!("Download Summary:");
+    info!("Number of supported media: {}", summary.media_supported);
+    info!("Number of media downloaded: {}", summary.media_downloaded);
+    info!("Number of media skipped: {}", summary.media_skipped);
+    info!("Number of media failed: {}", summary.media_failed);
+    info!("Number of media removed from the host: {}", summary.media_removed);
+    failures.report();
+    info!("#####################################");
 
-        debug!("Collection statistics: ");
-        debug!("Number of supported media: {}", local_summary.media_supported);
-        debug!("Number of media downloaded: {}", local_summary.media_downloaded);
-        debug!("Number of media skipped: {}", local_summary.media_skipped);
+    Ok(summary)
+}
 
-        Ok(local_summary)
+/// Retry the downloads recorded in the failures log of the data directory.
+/// The downloads that succeed are removed from the log, while the rest are kept for later
+pub async fn retry_failures(
+    data_directory: &str,
+    max_rate: Option<u64>,
+    checksums: Option<ChecksumAlgorithm>,
+    client: &Client,
+) -> Result<Summary, ReddSaverError> {
+    let rate_limiter = RateLimiter::new(max_rate);
+    let failures = FailureReasons::default();
+    let mut summary = Summary::default();
+
+    let log_file_name = format!("{}/{}", data_directory, FAILURES_LOG_FILE_NAME);
+    if !check_path_present(&log_file_name) {
+        info!("No failed downloads recorded in {}", log_file_name);
+        return Ok(summary);
     }
 
-    /// Generate a file name in the right format that Reddsaver expects
-    fn generate_file_name(
-        &self,
-        url: &str,
-        subreddit: &str,
-        extension: &str,
-        name: &str,
-        title: &str,
-        index: &str,
-    ) -> String {
-        return if !self.use_human_readable {
-            // create a hash for the media using the URL the media is located at
-            // this helps to make sure the media download always writes the same file
-            // name irrespective of how many times it's run. If run more than once, the
-            // media is overwritten by this method
-            let hash = md5::compute(url);
-            format!(
-                // TODO: Fixme, use appropriate prefix
-                "{}/{}/img-{:x}.{}",
-                self.data_directory, subreddit, hash, extension
-            )
-        } else {
-            let canonical_title: String = title
-                .to_lowercase()
-                .chars()
-                // to make sure file names don't exceed operating system maximums, truncate at 200
-                // you could possibly stretch beyond 200, but this is a conservative estimate that
-                // leaves 55 bytes for the name string
-                .take(200)
-                .enumerate()
-                .map(|(_, c)| {
-                    if c.is_whitespace()
-                        || c == '.'
-                        || c == '/'
-                        || c == '\\'
-                        || c == ':'
-                        || c == '='
-                    {
-                        '_'
-                    } else {
-                        c
-                    }
-                })
-                .collect();
-            // create a canonical human readable file name using the post's title
-            // note that the name of the post is something of the form t3_<randomstring>
-            let canonical_name: String =
-                if index == "0" { String::from(name) } else { format!("{}_{}", name, index) }
-                    .replace(".", "_");
-            format!(
-                "{}/{}/{}_{}.{}",
-                self.data_directory, subreddit, canonical_title, canonical_name, extension
-            )
+    let contents = fs::read_to_string(&log_file_name)?;
+    let mut remaining = Vec::new();
+    for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+        let failure = match serde_json::from_str::<FailedDownload>(line) {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("Ignoring invalid entry in {}: {}", log_file_name, e);
+                continue;
+            }
         };
+        summary.media_supported += 1;
+
+        let status = save_or_skip(
+            client,
+            &failure.url,
+            &failure.file_name,
+            &rate_limiter,
+            checksums,
+            &failures,
+        )
+        .await?;
+        match status {
+            MediaStatus::Downloaded => summary.media_downloaded += 1,
+            MediaStatus::Skipped => summary.media_skipped += 1,
+            MediaStatus::Failed => {
+                summary.media_failed += 1;
+                remaining.push(line);
+            }
+            MediaStatus::Removed => summary.media_removed += 1,
+        }
     }
-}
 
-/// Helper function that downloads and saves a single media from Reddit or Imgur
-async fn save_or_skip(url: &str, file_name: &str) -> Result<MediaStatus, ReddSaverError> {
-    if check_path_present(&file_name) {
-        debug!("Media from url {} already downloaded. Skipping...", url);
-        Ok(MediaStatus::Skipped)
+    if remaining.is_empty() {
+        fs::remove_file(&log_file_name)?;
     } else {
-        let save_status = download_media(&file_name, &url).await?;
-        if save_status {
-            Ok(MediaStatus::Downloaded)
-        } else {
-            Ok(MediaStatus::Skipped)
-        }
+        fs::write(&log_file_name, format!("{}\n", remaining.join("\n")))?;
     }
+
+    info!("#####################################");
+    info!("Retry Summary:");
+    info!("Number of failed downloads retried: {}", summary.media_supported);
+    info!("Number of media downloaded: {}", summary.media_downloaded);
+    info!("Number of media already present: {}", summary.media_skipped);
+    info!("Number of media still failing: {}", summary.media_failed);
+    info!("Number of media removed from the host: {}", summary.media_removed);
+    failures.report();
+    info!("#####################################");
+
+    Ok(summary)
 }
 
-/// Download media from the given url and save to data directory. Also create data directory if not present already
-async fn download_media(file_name: &str, url: &str) -> Result<bool, ReddSaverError> {
-    // create directory if it does not already exist
-    // the directory is created relative to the current working directory
-    let mut status = false;
-    let directory = Path::new(file_name).parent().unwrap();
-    match fs::create_dir_all(directory) {
-        Ok(_) => (),
-        Err(_e) => return Err(ReddSaverError::CouldNotCreateDirectory),
-    }
+/// Name of the post that the file with a human readable name belongs to, eg: t3_k1ng2h for
+/// title_t3_k1ng2h_1.jpg, or for t3_k1ng2h/1.jpg when galleries are in their own directories
+fn post_name_of_file(path: &Path) -> Option<String> {
+    let parent = path.parent().and_then(|p| p.file_name()).map(|p| p.to_string_lossy());
+    let file_name = path.file_name()?.to_string_lossy();
+    let name = match parent {
+        Some(parent) if parent.starts_with("t3_") => parent,
+        _ => file_name,
+    };
+    let start = name.rfind("t3_")?;
+    let id: String = name[start + 3..].chars().take_while(|c| c.is_ascii_alphanumeric()).collect();
+    Some(format!("t3_{}", id)).filter(|_| !id.is_empty())
+}
 
-    let maybe_response = reqwest::get(url).await;
-    if let Ok(response) = maybe_response {
-        debug!("URL Response: {:#?}", response);
-        let maybe_data = response.bytes().await;
-        if let Ok(data) = maybe_data {
-            debug!("Bytes length of the data: {:#?}", data.len());
-            let maybe_output = File::create(&file_name);
-            match maybe_output {
-                Ok(mut output) => {
-                    debug!("Created a file: {}", file_name);
-                    match io::copy(&mut data.as_ref(), &mut output) {
-                        Ok(_) => {
-                            info!("Successfully saved media: {} from url {}", file_name, url);
-                            status = true;
-                        }
-                        Err(_e) => {
-                            error!("Could not save media from url {} to {}", url, file_name);
-                        }
-                    }
-                }
-                Err(_) => {
-                    warn!("Could not create a file with the name: {}. Skipping", file_name);
-                }
-            }
-        }
+/// Check if the file is named using the MD5 hash of the URL of the media, eg: img-<hash>.jpg
+fn is_hashed_file_name(path: &Path) -> bool {
+    let file_name = path.file_name().map(|f| f.to_string_lossy()).unwrap_or_default();
+    match file_name.strip_prefix("img-") {
+        Some(rest) => rest.len() > 32 && rest.chars().take(32).all(|c| c.is_ascii_hexdigit()),
+        None => false,
     }
+}
 
-    Ok(status)
+/// Name of the thumbnail of a gif, saved next to the gif, eg: img-<hash>-thumb.jpg
+fn thumbnail_file_name(gif_file_name: &str) -> String {
+    let path = Path::new(gif_file_name);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}-thumb.{}", stem, JPG_EXTENSION)).to_string_lossy().into_owned()
 }
 
-/// Convert Gfycat/Redgifs GIFs into mp4 URLs for download
-async fn gfy_to_mp4(url: &str) -> Result<Option<SupportedMedia>, ReddSaverError> {
-    let api_prefix =
-        if url.contains(GFYCAT_DOMAIN) { GFYCAT_API_PREFIX } else { REDGIFS_API_PREFIX };
+/// Get the information about Gfycat/Redgifs GIFs, including the mp4 URLs for download
+async fn gfy_item(client: &Client, url: &str) -> Result<Option<GfyItem>, ReddSaverError> {
     let maybe_media_id = url.split("/").last();
 
     if let Some(media_id) = maybe_media_id {
-        let api_url = format!("{}/{}", api_prefix, media_id);
+        let api_url = format!("{}/{}", GFYCAT_API_PREFIX, media_id);
         debug!("GFY API URL: {}", api_url);
-        let client = reqwest::Client::new();
 
         // talk to gfycat API and get GIF information
         let response = client.get(&api_url).send().await?;
         // if the gif is not available anymore, Gfycat might send
         // a 404 response. Proceed to get the mp4 URL only if the
         // response was HTTP 200
-        if response.status() == StatusCode::OK {
-            let data = response.json::<GfyData>().await?;
-            let supported_media = SupportedMedia {
-                components: vec![data.gfy_item.mp4_url],
-                media_type: MediaType::GfycatGif,
-            };
-            Ok(Some(supported_media))
-        } else {
-            Ok(None)
+        if response.status() != StatusCode::OK {
+            return Ok(None);
         }
-    } else {
-        Ok(None)
+
+        match response.json::<GfyResponse>().await? {
+            GfyResponse::Media(data) => return Ok(Some(data.gfy_item)),
+            // gfycat sends a 200 response with an error in the body for some gifs
+            GfyResponse::Error(e) => warn!("Skipping media from url {}: {}", url, e.description()),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Resolve the media of a redgifs watch page using the v2 API. The page can be of a single gif,
+/// or of a gif that is part of a gallery, in which case the media of the whole gallery is
+/// returned. The HD version of each media is used when available and the SD version otherwise
+async fn redgifs_media(
+    client: &Client,
+    url: &str,
+    redgifs_token: &Mutex<Option<String>>,
+    with_thumbnails: bool,
+) -> Result<Vec<SupportedMedia>, ReddSaverError> {
+    let mut media = Vec::new();
+    // the IDs are lowercase in the API, while the watch pages can use any case
+    let id = match url.rsplit('/').next().filter(|id| !id.is_empty()) {
+        Some(id) => id.to_lowercase(),
+        None => return Ok(media),
+    };
+
+    let api_url = format!("{}/gifs/{}", REDGIFS_API_PREFIX, id);
+    let gif = match redgifs_request::<RedgifsResponse>(client, &api_url, redgifs_token).await? {
+        Some(response) => response.gif,
+        None => return Ok(media),
+    };
+    let gifs = match &gif.gallery {
+        Some(gallery) => {
+            let api_url = format!("{}/gallery/{}", REDGIFS_API_PREFIX, gallery);
+            match redgifs_request::<RedgifsGallery>(client, &api_url, redgifs_token).await? {
+                Some(gallery) => gallery.gifs,
+                None => vec![gif.clone()],
+            }
+        }
+        None => vec![gif.clone()],
+    };
+
+    let components: Vec<String> =
+        gifs.into_iter().filter_map(|g| g.urls.hd.or(g.urls.sd)).collect();
+    if components.is_empty() {
+        return Ok(media);
+    }
+    media.push(SupportedMedia { components, media_type: MediaType::GfycatGif });
+    // the thumbnail directly follows its gif so that it can be named after it
+    if let Some(poster_url) = gif.urls.poster.filter(|_| with_thumbnails) {
+        media.push(SupportedMedia {
+            components: vec![poster_url],
+            media_type: MediaType::GfycatThumbnail,
+        });
+    }
+
+    Ok(media)
+}
+
+/// Request the redgifs v2 API with the temporary token, getting a new token when there is
+/// none yet or when it has expired. Rate limits and server errors are retried once after a
+/// short delay. Media that is not available anymore, or that could not be requested since
+/// redgifs is not reachable, is skipped
+async fn redgifs_request<T: DeserializeOwned>(
+    client: &Client,
+    api_url: &str,
+    redgifs_token: &Mutex<Option<String>>,
+) -> Result<Option<T>, ReddSaverError> {
+    for attempt in 1..=GFY_API_ATTEMPTS {
+        let cached_token = redgifs_token.lock().unwrap().clone();
+        let token = match cached_token {
+            Some(token) => token,
+            None => match temporary_redgifs_token(client).await {
+                Ok(token) => {
+                    *redgifs_token.lock().unwrap() = Some(token.clone());
+                    token
+                }
+                Err(e) => {
+                    warn!(
+                        "Skipping media from url {}, could not get a redgifs token: {}",
+                        api_url, e
+                    );
+                    return Ok(None);
+                }
+            },
+        };
+
+        debug!("Redgifs API URL: {}", api_url);
+        let response = match client.get(api_url).bearer_auth(&token).send().await {
+            Ok(response) => response,
+            Err(e) => {
+                warn!("Skipping media from url {}: {}", api_url, e);
+                return Ok(None);
+            }
+        };
+        match response.status() {
+            StatusCode::OK => {
+                return match response.json::<T>().await {
+                    Ok(media) => Ok(Some(media)),
+                    Err(e) => {
+                        warn!("Skipping media from url {}, could not parse it: {}", api_url, e);
+                        Ok(None)
+                    }
+                };
+            }
+            StatusCode::UNAUTHORIZED if attempt < GFY_API_ATTEMPTS => {
+                debug!("The redgifs token has expired. Getting a new one...");
+                *redgifs_token.lock().unwrap() = None;
+            }
+            // there is no point retrying if the gif is gone
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                warn!("Skipping media from url {}, it is not on redgifs anymore", api_url);
+                return Ok(None);
+            }
+            status
+                if (status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error())
+                    && attempt < GFY_API_ATTEMPTS =>
+            {
+                let delay = if status == StatusCode::TOO_MANY_REQUESTS {
+                    rate_limit_delay(&response)
+                } else {
+                    Duration::from_secs(GFY_API_RETRY_DELAY_SECS)
+                };
+                warn!(
+                    "Temporary error from {}: {}. Retrying in {} seconds...",
+                    api_url,
+                    status,
+                    delay.as_secs()
+                );
+                delay_for(delay).await;
+            }
+            status => {
+                warn!("Skipping media from url {}: {}", api_url, status);
+                return Ok(None);
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Get a temporary token for the redgifs v2 API, which does not need an account
+async fn temporary_redgifs_token(client: &Client) -> Result<String, reqwest::Error> {
+    let token = client
+        .get(&format!("{}/auth/temporary", REDGIFS_API_PREFIX))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<RedgifsToken>()
+        .await?;
+    Ok(token.token)
+}
+
+/// Resolve a reddit video from the fallback URL in the post data, falling back to the HLS
+/// playlist when the video is not available as DASH streams
+async fn reddit_video_fallback(
+    client: &Client,
+    video: &RedditVideo,
+    probe_cache: &Mutex<HashMap<String, Option<bool>>>,
+    no_audio: bool,
+) -> Result<Option<SupportedMedia>, ReddSaverError> {
+    let fallback_url = String::from(&video.fallback_url).replace("?source=fallback", "");
+    let without_audio = video.is_gif || no_audio;
+    if let Some(supported_media) =
+        get_reddit_video(client, &fallback_url, without_audio, probe_cache).await?
+    {
+        return Ok(Some(supported_media));
     }
+
+    // some videos are not available as DASH streams, but only as a
+    // HLS playlist which can be remuxed into an mp4 file
+    Ok(video.hls_url.as_ref().map(|hls_url| SupportedMedia {
+        components: vec![hls_url.clone()],
+        media_type: MediaType::RedditVideoHls,
+    }))
 }
 
-// Get reddit video information and optionally the audio track if it exists
-async fn get_reddit_video(url: &str) -> Result<Option<SupportedMedia>, ReddSaverError> {
+// Get reddit video information and optionally the audio track if it exists.
+// Videos marked as gifs by reddit do not have an audio track, so the audio is not probed for them
+// or when the audio is not wanted
+async fn get_reddit_video(
+    client: &Client,
+    url: &str,
+    without_audio: bool,
+    probe_cache: &Mutex<HashMap<String, Option<bool>>>,
+) -> Result<Option<SupportedMedia>, ReddSaverError> {
     let maybe_dash_video = url.split("/").last();
     if let Some(dash_video) = maybe_dash_video {
         let present = dash_video.contains("DASH");
         // todo: find exhaustive collection of these, or figure out if they are (x, x*2) pairs
         let dash_video_only = vec!["DASH_1_2_M", "DASH_2_4_M", "DASH_4_8_M"];
         if present {
-            return if dash_video_only.contains(&dash_video) {
+            return if without_audio || dash_video_only.contains(&dash_video) {
                 let supported_media = SupportedMedia {
                     components: vec![String::from(url)],
                     media_type: MediaType::RedditVideoWithoutAudio,
@@ -510,7 +3712,19 @@ async fn get_reddit_video(url: &str) -> Result<Option<SupportedMedia>, ReddSaver
                 // Check the mime type to see the generated URL contains an audio file
                 // This can be done by checking the content type header for the given URL
                 // Reddit API response does not seem to expose any easy way to figure this out
-                if let Some(audio_present) = check_url_is_mp4(&audio_url).await? {
+                let cached_probe = probe_cache.lock().unwrap().get(&audio_url).cloned();
+                let probe = match cached_probe {
+                    Some(probe) => {
+                        debug!("Using cached content-type probe for audio URL {}", audio_url);
+                        probe
+                    }
+                    None => {
+                        let probe = check_url_is_mp4(client, &audio_url).await?;
+                        probe_cache.lock().unwrap().insert(audio_url.clone(), probe);
+                        probe
+                    }
+                };
+                if let Some(audio_present) = probe {
                     if audio_present {
                         debug!("Found audio at URL {} for video {}", audio_url, dash_video);
                         let supported_media = SupportedMedia {
@@ -544,24 +3758,72 @@ async fn get_reddit_video(url: &str) -> Result<Option<SupportedMedia>, ReddSaver
     Ok(None)
 }
 
+/// Options for resolving the media of a post
+#[derive(Debug, Copy, Clone)]
+struct ResolveOptions {
+    /// Use the mp4 variant of reddit gifs when available
+    prefer_mp4: bool,
+    /// Also resolve the poster image of gfycat/redgifs gifs
+    with_thumbnails: bool,
+    /// Only resolve the video track of reddit videos
+    no_audio: bool,
+    /// Resolve the reddit preview of videos from dead or unsupported hosts instead
+    preview_fallback: bool,
+    /// Log every decision made in resolving the media
+    traced: bool,
+}
+
 /// Check if a particular URL contains supported media.
-async fn get_media(data: &PostData) -> Result<Vec<SupportedMedia>, ReddSaverError> {
+async fn get_media(
+    client: &Client,
+    data: &PostData,
+    probe_cache: &Mutex<HashMap<String, Option<bool>>>,
+    redgifs_token: &Mutex<Option<String>>,
+    options: ResolveOptions,
+) -> Result<Vec<SupportedMedia>, ReddSaverError> {
+    let ResolveOptions { prefer_mp4, with_thumbnails, no_audio, preview_fallback, traced } =
+        options;
     let original = data.url.as_ref().unwrap();
     let mut media: Vec<SupportedMedia> = Vec::new();
+    // every decision is logged for the post being traced, which is more targeted than debug logs
+    let trace = |message: String| {
+        if traced {
+            info!("[trace {}] {}", data.id, message);
+        }
+    };
+    trace(format!("resolving the media from {}", original));
+
+    // the media cannot be fetched from a dead host, so go straight to the reddit preview
+    if preview_fallback && dead_host(data).is_some() {
+        if let Some(v) = video_preview(data) {
+            media.extend(reddit_video_fallback(client, v, probe_cache, no_audio).await?);
+        }
+        trace(format!("dead host, using the reddit preview of the video: {:?}", media));
+        return Ok(media);
+    }
 
     if let Ok(u) = Url::parse(original) {
         let mut parsed = u.clone();
 
         match parsed.path_segments_mut() {
             Ok(mut p) => p.pop_if_empty(),
-            Err(_) => return Ok(media),
+            Err(_) => {
+                trace(String::from("the URL cannot have a path, no media"));
+                return Ok(media);
+            }
         };
 
         let url = &parsed[..Position::AfterPath];
-        let gallery_info = data.gallery_data.borrow();
+        trace(format!("matching the URL without its query: {}", url));
 
         // reddit images and gifs
         if url.contains(REDDIT_IMAGE_SUBDOMAIN) {
+            trace(format!(
+                "matched {}, jpg or png: {}, gif: {}",
+                REDDIT_IMAGE_SUBDOMAIN,
+                url.ends_with(JPG_EXTENSION) || url.ends_with(PNG_EXTENSION),
+                url.ends_with(GIF_EXTENSION)
+            ));
             // if the URL uses the reddit image subdomain and if the extension is
             // jpg, png or gif, then we can use the URL as is.
             if url.ends_with(JPG_EXTENSION) || url.ends_with(PNG_EXTENSION) {
@@ -573,10 +3835,17 @@ async fn get_media(data: &PostData) -> Result<Vec<SupportedMedia>, ReddSaverErro
                 media.push(supported_media);
             }
             if url.ends_with(GIF_EXTENSION) {
-                let translated = String::from(url);
-                let translated = SupportedMedia {
-                    components: vec![translated],
-                    media_type: MediaType::RedditGif,
+                trace(format!("mp4 variant of the gif: {:?}", mp4_variant(data)));
+                // the mp4 variant of the gif is much smaller, so use it when available
+                let translated = match mp4_variant(data).filter(|_| prefer_mp4) {
+                    Some(mp4) => SupportedMedia {
+                        components: vec![mp4],
+                        media_type: MediaType::RedditGifMp4,
+                    },
+                    None => SupportedMedia {
+                        components: vec![String::from(url)],
+                        media_type: MediaType::RedditGif,
+                    },
                 };
                 media.push(translated);
             }
@@ -584,50 +3853,87 @@ async fn get_media(data: &PostData) -> Result<Vec<SupportedMedia>, ReddSaverErro
 
         // reddit mp4 videos
         if url.contains(REDDIT_VIDEO_SUBDOMAIN) {
+            trace(format!(
+                "matched {}, mp4: {}",
+                REDDIT_VIDEO_SUBDOMAIN,
+                url.ends_with(MP4_EXTENSION)
+            ));
             // if the URL uses the reddit video subdomain and if the extension is
             // mp4, then we can use the URL as is.
             if url.ends_with(MP4_EXTENSION) {
                 let video_url = String::from(url);
-                if let Some(supported_media) = get_reddit_video(&video_url).await? {
+                let is_gif = match &data.media {
+                    Some(PostMedia { reddit_video: Some(v), .. }) => v.is_gif,
+                    _ => false,
+                };
+                let video =
+                    get_reddit_video(client, &video_url, is_gif || no_audio, probe_cache).await?;
+                trace(format!("reddit video, gif: {}, probed: {:?}", is_gif, video));
+                if let Some(supported_media) = video {
                     media.push(supported_media);
                 }
             } else {
                 // if the URL uses the reddit video subdomain, but the link does not
                 // point directly to the mp4, then use the fallback URL to get the
                 // appropriate link. The video quality might range from 96p to 720p
-                if let Some(m) = &data.media {
-                    if let Some(v) = &m.reddit_video {
-                        let fallback_url =
-                            String::from(&v.fallback_url).replace("?source=fallback", "");
-                        if let Some(supported_media) = get_reddit_video(&fallback_url).await? {
-                            media.push(supported_media);
-                        }
+                if let Some(PostMedia { reddit_video: Some(v), .. }) = &data.media {
+                    let video = reddit_video_fallback(client, v, probe_cache, no_audio).await?;
+                    trace(format!("fallback of the reddit video, probed: {:?}", video));
+                    if let Some(supported_media) = video {
+                        media.push(supported_media);
                     }
+                } else {
+                    trace(String::from("the post has no reddit video to fall back to"));
                 }
             }
         }
 
         // reddit image galleries
         if url.contains(REDDIT_DOMAIN) && url.contains(REDDIT_GALLERY_PATH) {
-            if let Some(gallery) = gallery_info {
-                // collect all the URLs for the images in the album
-                let mut image_urls = Vec::new();
-                for item in gallery.items.iter() {
-                    // extract the media ID from each gallery item and reconstruct the image URL
-                    let image_url = format!(
-                        "https://{}/{}.{}",
-                        REDDIT_IMAGE_SUBDOMAIN, item.media_id, JPG_EXTENSION
-                    );
-                    image_urls.push(image_url);
-                }
-                let supported_media =
-                    SupportedMedia { components: image_urls, media_type: MediaType::RedditImage };
+            let gallery = gallery_media(client, data).await?;
+            trace(format!("matched a reddit gallery: {:?}", gallery));
+            if let Some(supported_media) = gallery {
                 media.push(supported_media);
             }
         }
 
+        // posts linking to their own comments page, where the media is only
+        // available from the post data itself
+        if media.is_empty() && url.contains(REDDIT_DOMAIN) && url.contains(REDDIT_COMMENTS_PATH) {
+            trace(String::from("matched the comments page of a post"));
+            if let Some(PostMedia { reddit_video: Some(v), .. }) = &data.media {
+                let video = reddit_video_fallback(client, v, probe_cache, no_audio).await?;
+                trace(format!("using the reddit video of the post, probed: {:?}", video));
+                if let Some(supported_media) = video {
+                    media.push(supported_media);
+                }
+            } else if data.gallery_data.is_some() {
+                let gallery = gallery_media(client, data).await?;
+                trace(format!("using the gallery of the post: {:?}", gallery));
+                if let Some(supported_media) = gallery {
+                    media.push(supported_media);
+                }
+            } else if let Some(mp4) = mp4_variant(data) {
+                trace(String::from("using the mp4 variant of the preview"));
+                // animated images only have a still image as the preview source
+                media.push(SupportedMedia {
+                    components: vec![mp4],
+                    media_type: MediaType::RedditGifMp4,
+                });
+            } else if let Some(image) = preview_source(data) {
+                trace(String::from("using the source of the preview"));
+                media.push(SupportedMedia {
+                    components: vec![image],
+                    media_type: MediaType::RedditImage,
+                });
+            } else {
+                trace(String::from("the post has no video, gallery or preview"));
+            }
+        }
+
         // gfycat and redgifs
         if url.contains(GFYCAT_DOMAIN) || url.contains(REDGIFS_DOMAIN) {
+            trace(format!("matched gfycat or redgifs, mp4: {}", url.ends_with(MP4_EXTENSION)));
             // if the Gfycat/Redgifs URL points directly to the mp4, download as is
             if url.ends_with(MP4_EXTENSION) {
                 let supported_media = SupportedMedia {
@@ -635,19 +3941,42 @@ async fn get_media(data: &PostData) -> Result<Vec<SupportedMedia>, ReddSaverErro
                     media_type: MediaType::GfycatGif,
                 };
                 media.push(supported_media);
+            } else if url.contains(REDGIFS_DOMAIN) {
+                let gifs = redgifs_media(client, url, redgifs_token, with_thumbnails).await?;
+                trace(format!("response of the redgifs API: {:?}", gifs));
+                media.extend(gifs);
             } else {
                 // if the provided link is a gfycat post link, use the gfycat API
                 // to get the URL. gfycat likes to use lowercase names in their posts
                 // but the ID for the GIF is Pascal-cased. The case-conversion info
                 // can only be obtained from the API at the moment
-                if let Some(supported_media) = gfy_to_mp4(url).await? {
-                    media.push(supported_media);
+                let item = gfy_item(client, url).await?;
+                trace(format!("response of the gfycat API: {:?}", item));
+                if let Some(item) = item {
+                    media.push(SupportedMedia {
+                        components: vec![item.mp4_url],
+                        media_type: MediaType::GfycatGif,
+                    });
+                    // the thumbnail directly follows its gif so that it can be named after it
+                    if let Some(poster_url) = item.poster_url.filter(|_| with_thumbnails) {
+                        media.push(SupportedMedia {
+                            components: vec![poster_url],
+                            media_type: MediaType::GfycatThumbnail,
+                        });
+                    }
                 }
             }
         }
 
         // giphy
         if url.contains(GIPHY_DOMAIN) {
+            trace(format!(
+                "matched {}, gif, mp4 or gifv: {}",
+                GIPHY_DOMAIN,
+                url.ends_with(GIF_EXTENSION)
+                    || url.ends_with(MP4_EXTENSION)
+                    || url.ends_with(GIFV_EXTENSION)
+            ));
             // giphy has multiple CDN networks named {media0, .., media5}
             // links can point to the canonical media subdomain or any content domains
             if url.contains(GIPHY_MEDIA_SUBDOMAIN)
@@ -667,19 +3996,13 @@ async fn get_media(data: &PostData) -> Result<Vec<SupportedMedia>, ReddSaverErro
                         media_type: MediaType::GiphyGif,
                     };
                     media.push(supported_media);
+                } else if let Some(supported_media) = giphy_gif(&parsed) {
+                    // other media links like `/media/<id>/giphy.webp` still carry the ID
+                    media.push(supported_media);
                 }
-            } else {
+            } else if let Some(supported_media) = giphy_gif(&parsed) {
                 // if the link points to the giphy post rather than the media link,
-                // use the scheme below to get the actual URL for the gif.
-                let path = &parsed[Position::AfterHost..Position::AfterPath];
-                let media_id = path.split("-").last().unwrap();
-                let supported_media = SupportedMedia {
-                    components: vec![format!(
-                        "https://{}/media/{}.gif",
-                        GIPHY_MEDIA_SUBDOMAIN, media_id
-                    )],
-                    media_type: MediaType::GiphyGif,
-                };
+                // use the ID from the post link to get the actual URL for the gif.
                 media.push(supported_media);
             }
         }
@@ -688,6 +4011,13 @@ async fn get_media(data: &PostData) -> Result<Vec<SupportedMedia>, ReddSaverErro
         // NOTE: only support direct links for gifv and images
         // *No* support for image and gallery posts.
         if url.contains(IMGUR_DOMAIN) {
+            trace(format!(
+                "matched {}, direct link: {}, gifv: {}, jpg or png: {}",
+                IMGUR_DOMAIN,
+                url.contains(IMGUR_SUBDOMAIN),
+                url.ends_with(GIFV_EXTENSION),
+                url.ends_with(PNG_EXTENSION) || url.ends_with(JPG_EXTENSION)
+            ));
             if url.contains(IMGUR_SUBDOMAIN) && url.ends_with(GIFV_EXTENSION) {
                 // if the extension is gifv, then replace gifv->mp4 to get the video URL
                 let supported_media = SupportedMedia {
@@ -706,7 +4036,530 @@ async fn get_media(data: &PostData) -> Result<Vec<SupportedMedia>, ReddSaverErro
                 media.push(supported_media);
             }
         }
+    } else {
+        trace(String::from("the URL could not be parsed"));
+    }
+
+    // newer image posts might only have their image in the metadata of the post
+    if media.is_empty() {
+        media.extend(embedded_media(data));
+        trace(format!("media embedded in the metadata of the post: {:?}", media));
+    }
+
+    // the media of posts linking to unsupported video hosts might still be previewed by reddit
+    if media.is_empty() && preview_fallback {
+        if let Some(v) = video_preview(data) {
+            debug!("Using the reddit preview of the video from {}", original);
+            media.extend(reddit_video_fallback(client, v, probe_cache, no_audio).await?);
+            trace(format!("using the reddit preview of the video: {:?}", media));
+        }
     }
 
+    trace(format!("resolved the media: {:?}", media));
     Ok(media)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undo_user_is_none_in_dry_runs() {
+        assert_eq!(undo_user("user", false), None);
+    }
+
+    #[test]
+    fn undo_user_when_downloading() {
+        assert_eq!(undo_user("user", true), Some("user"));
+    }
+
+    /// A post of the listing, with the given URL
+    fn listing_post(id: &str, url: Option<&str>) -> serde_json::Value {
+        serde_json::json!({
+            "kind": "t3",
+            "data": {
+                "subreddit": "pics",
+                "id": id,
+                "score": 1,
+                "subreddit_id": "t5_2qh0u",
+                "saved": true,
+                "permalink": format!("/r/pics/comments/{}/post/", id),
+                "name": format!("t3_{}", id),
+                "created": 1606400000.0,
+                "created_utc": 1606400000.0,
+                "url": url
+            }
+        })
+    }
+
+    /// The options of a run downloading into the directory, with everything else turned off
+    fn test_options(data_directory: &str) -> DownloadOptions<'_> {
+        DownloadOptions {
+            user: None,
+            listing_type: &ListingType::Saved,
+            data_directory,
+            subreddits: &None,
+            skipped_subreddits: Vec::new(),
+            excluded_extensions: Vec::new(),
+            flair: None,
+            excluded_flair: None,
+            since: None,
+            newest_first: false,
+            max_per_subreddit: None,
+            should_download: true,
+            print_urls: false,
+            summary_only: false,
+            refresh_metadata: false,
+            resolve_only: None,
+            use_human_readable: false,
+            ascii_filenames: false,
+            segregate_by_listing: false,
+            segregate_by_type: false,
+            gallery_subfolder: false,
+            output_template: None,
+            on_collision: CollisionPolicy::Overwrite,
+            undo: false,
+            continue_without_undo: false,
+            ffmpeg_available: false,
+            temp_dir: data_directory,
+            prefer_mp4: false,
+            with_thumbnails: false,
+            no_audio: false,
+            extract_audio: None,
+            preview_fallback: false,
+            thumbnail_fallback: false,
+            use_aria2c: false,
+            record_source: false,
+            xmp_sidecar: false,
+            post_download_hook: None,
+            throttle: Duration::from_secs(0),
+            max_rate: None,
+            events: None,
+            checksums: None,
+            convert_images: None,
+            deadline: None,
+            stats_interval: None,
+            prune: false,
+            delete_orphans: false,
+            dedupe_report: None,
+            mapping_file: None,
+            host_stats: false,
+            max_host_failures: 0,
+            json_lines: false,
+            with_comments: None,
+            trace_post: None,
+        }
+    }
+
+    /// Run the downloader over a single page of the listing with the posts
+    async fn run_posts(
+        options: DownloadOptions<'_>,
+        posts: Vec<serde_json::Value>,
+    ) -> Result<Summary, ReddSaverError> {
+        let data_directory = options.data_directory;
+        let downloader = Downloader::new(
+            options,
+            Client::new(),
+            None,
+            Box::new(crate::storage::FileSystem::new(data_directory)),
+            None,
+        );
+        let listing: Listing = serde_json::from_value(serde_json::json!({
+            "kind": "Listing",
+            "data": {"before": null, "after": null, "dist": posts.len(), "children": posts}
+        }))
+        .unwrap();
+        let (pages, receiver) = tokio::sync::mpsc::unbounded_channel();
+        pages.send(listing).unwrap();
+        drop(pages);
+        downloader.run(receiver).await
+    }
+
+    #[tokio::test]
+    async fn events_of_a_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_directory = dir.path().to_str().unwrap();
+        // the media is already present, so nothing is downloaded
+        let present = format!("{}/k1ng2h.jpg", data_directory);
+        fs::write(&present, "media").unwrap();
+        let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+        let mut options = test_options(data_directory);
+        options.output_template = Some("{id}.{ext}");
+        options.events = Some(sender);
+        let posts = vec![
+            listing_post("k1ng2h", Some("https://i.redd.it/k1ng2h.jpg")),
+            listing_post("k1ng2i", None),
+        ];
+        run_posts(options, posts).await.unwrap();
+
+        let mut received = Vec::new();
+        while let Some(event) = events.recv().await {
+            received.push(event);
+        }
+        assert_eq!(received.len(), 3);
+        assert!(received.contains(&DownloadEvent::MediaSkipped {
+            url: String::from("https://i.redd.it/k1ng2h.jpg"),
+            path: present,
+        }));
+        assert!(
+            received.contains(&DownloadEvent::PostProcessed { post_id: String::from("k1ng2h") })
+        );
+        assert!(
+            received.contains(&DownloadEvent::PostProcessed { post_id: String::from("k1ng2i") })
+        );
+    }
+
+    #[test]
+    fn download_event_as_json() {
+        let event =
+            DownloadEvent::MediaFailed { url: String::from("https://i.redd.it/k1ng2h.jpg") };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"media_failed","url":"https://i.redd.it/k1ng2h.jpg"}"#
+        );
+    }
+
+    #[test]
+    fn giphy_media_id_of_slug() {
+        assert_eq!(
+            giphy_media_id("/gifs/funny-cat-dancing-3oEjHGr1Fhz0kyv8Ig"),
+            Some("3oEjHGr1Fhz0kyv8Ig")
+        );
+    }
+
+    #[test]
+    fn giphy_media_id_of_slug_with_trailing_word() {
+        assert_eq!(
+            giphy_media_id("/gifs/cat-3oEjHGr1Fhz0kyv8Ig-dancing"),
+            Some("3oEjHGr1Fhz0kyv8Ig")
+        );
+        assert_eq!(
+            giphy_media_id("/gifs/funny-cat-3oEjHGr1Fhz0kyv8Ig/fullscreen"),
+            Some("3oEjHGr1Fhz0kyv8Ig")
+        );
+    }
+
+    #[test]
+    fn giphy_media_id_of_id_only() {
+        assert_eq!(giphy_media_id("/gifs/3oEjHGr1Fhz0kyv8Ig"), Some("3oEjHGr1Fhz0kyv8Ig"));
+        // ids that look like plain words are taken from the end of the slug
+        assert_eq!(giphy_media_id("/gifs/funny-cat-abcdefghij"), Some("abcdefghij"));
+    }
+
+    #[test]
+    fn giphy_media_id_of_media_path() {
+        assert_eq!(
+            giphy_media_id("/media/3oEjHGr1Fhz0kyv8Ig/giphy.gif"),
+            Some("3oEjHGr1Fhz0kyv8Ig")
+        );
+    }
+
+    /// A URL on a local port that nothing listens on, so that downloading from it fails at once
+    fn unreachable_url(path: &str) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("http://{}{}", listener.local_addr().unwrap(), path)
+    }
+
+    #[test]
+    fn fully_saved_media() {
+        assert!(is_fully_saved(&[MediaStatus::Downloaded, MediaStatus::Skipped]));
+        assert!(!is_fully_saved(&[MediaStatus::Downloaded, MediaStatus::Failed]));
+        assert!(!is_fully_saved(&[MediaStatus::Removed]));
+        assert!(!is_fully_saved(&[]));
+    }
+
+    /// Run with --prune --yes over the posts, with the media of the first post already present
+    /// and an orphaned file with a hashed name, returning whether the orphan is left
+    async fn orphan_left_by_prune(posts: Vec<serde_json::Value>) -> bool {
+        let dir = tempfile::tempdir().unwrap();
+        let data_directory = dir.path().to_str().unwrap();
+        fs::create_dir(format!("{}/pics", data_directory)).unwrap();
+        let present = md5::compute("https://i.redd.it/k1ng2h.jpg");
+        fs::write(format!("{}/pics/img-{:x}.jpg", data_directory, present), "media").unwrap();
+        let orphan = format!("{}/pics/img-{:x}.jpg", data_directory, md5::compute("orphan"));
+        fs::write(&orphan, "media").unwrap();
+
+        let mut options = test_options(data_directory);
+        options.prune = true;
+        options.delete_orphans = true;
+        run_posts(options, posts).await.unwrap();
+        Path::new(&orphan).exists()
+    }
+
+    #[tokio::test]
+    async fn prune_deletes_orphans_once_all_posts_are_saved() {
+        let posts = vec![listing_post("k1ng2h", Some("https://i.redd.it/k1ng2h.jpg"))];
+        assert!(!orphan_left_by_prune(posts).await);
+    }
+
+    #[tokio::test]
+    async fn prune_keeps_orphans_when_a_post_is_not_saved() {
+        // the orphan could be the media of the post that failed, saved under another name
+        let failed = unreachable_url("/i.redd.it/k1ng2i.jpg");
+        let posts = vec![
+            listing_post("k1ng2h", Some("https://i.redd.it/k1ng2h.jpg")),
+            listing_post("k1ng2i", Some(&failed)),
+        ];
+        assert!(orphan_left_by_prune(posts).await);
+    }
+
+    /// A gallery post with the metadata of its images, but without the gallery data
+    fn metadata_only_gallery() -> PostData {
+        serde_json::from_value(serde_json::json!({
+            "subreddit": "EarthPorn",
+            "id": "k1ng2h",
+            "score": 1,
+            "subreddit_id": "t5_2sbq3",
+            "saved": true,
+            "permalink": "/r/EarthPorn/comments/k1ng2h/gallery/",
+            "name": "t3_k1ng2h",
+            "created": 1606400000.0,
+            "created_utc": 1606400000.0,
+            "url": "https://www.reddit.com/gallery/k1ng2h",
+            "media_metadata": {
+                "zx1b2c3d4e5f": { "status": "valid", "m": "image/png" },
+                "ab1b2c3d4e5f": { "status": "valid", "m": "image/jpg" },
+                "mm1b2c3d4e5f": { "status": "failed" }
+            }
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn gallery_media_from_metadata_only() {
+        let post = metadata_only_gallery();
+        assert!(post.gallery_data.is_none());
+
+        let gallery = gallery_media(&Client::new(), &post).await.unwrap().unwrap();
+        assert_eq!(gallery.media_type, MediaType::RedditImage);
+        // the images are ordered by their media ID and the failed ones are left out
+        assert_eq!(
+            gallery.components,
+            vec![
+                format!("https://{}/ab1b2c3d4e5f.jpg", REDDIT_IMAGE_SUBDOMAIN),
+                format!("https://{}/zx1b2c3d4e5f.png", REDDIT_IMAGE_SUBDOMAIN),
+            ]
+        );
+    }
+
+    /// Serve each of the responses once, in order, to the requests for its path on a local port,
+    /// returning the address to request. Requests for any other path get a 404
+    fn mock_server(responses: Vec<(&'static str, u16, &'static str, &'static str)>) -> String {
+        use std::io::{BufRead, BufReader};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut responses = responses;
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let path = request_line.split(' ').nth(1).unwrap_or_default();
+                let (_, status, headers, body) = match responses.iter().position(|r| r.0 == path) {
+                    Some(position) => responses.remove(position),
+                    None => ("", 404, "", ""),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                )
+                .ok();
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    #[tokio::test]
+    async fn reddit_image_of_another_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_directory = dir.path().to_str().unwrap();
+        // the image is guessed to be a jpg, while it is a png
+        let server = mock_server(vec![(
+            "/i.redd.it/k1ng2h.png",
+            200,
+            "Content-Type: image/png\r\n",
+            "media",
+        )]);
+        let url = format!("{}/i.redd.it/k1ng2h.jpg", server);
+        let posts = vec![listing_post("k1ng2h", Some(&url))];
+        let summary = run_posts(test_options(data_directory), posts).await.unwrap();
+        assert_eq!(summary.media_downloaded, 1);
+        let file_name = format!("{}/pics/img-{:x}.png", data_directory, md5::compute(&url));
+        assert_eq!(fs::read_to_string(file_name).unwrap(), "media");
+    }
+
+    #[tokio::test]
+    async fn undo_only_posts_that_were_saved() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_directory = dir.path().to_str().unwrap();
+        fs::create_dir(format!("{}/pics", data_directory)).unwrap();
+        let present = md5::compute("https://i.redd.it/k1ng2h.jpg");
+        fs::write(format!("{}/pics/img-{:x}.jpg", data_directory, present), "media").unwrap();
+        let client = crate::auth::Client::new(
+            "id",
+            "secret",
+            "user",
+            "password",
+            "agent",
+            reqwest::Client::new(),
+        );
+        let auth = serde_json::from_value(serde_json::json!({
+            "access_token": "token",
+            "token_type": "bearer",
+            "expires_in": 3600,
+            "scope": "*"
+        }))
+        .unwrap();
+        let user = User::new(&client, auth, "user");
+        // submitted posts can not be undone, so the run fails once any of the posts is undone
+        let undo_options = || {
+            let mut options = test_options(data_directory);
+            options.user = Some(&user);
+            options.listing_type = &ListingType::Submitted;
+            options.undo = true;
+            options
+        };
+        let saved = || vec![listing_post("k1ng2h", Some("https://i.redd.it/k1ng2h.jpg"))];
+
+        let failed = unreachable_url("/i.redd.it/k1ng2i.jpg");
+        assert!(run_posts(undo_options(), vec![listing_post("k1ng2i", Some(&failed))])
+            .await
+            .is_ok());
+        let other_subreddits = Some(vec!["funny"]);
+        let mut options = undo_options();
+        options.subreddits = &other_subreddits;
+        assert!(run_posts(options, saved()).await.is_ok());
+        assert!(run_posts(undo_options(), saved()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn redgifs_request_retries_rate_limits() {
+        let server = mock_server(vec![
+            ("/v2/gifs/k1ng2h", 429, "Retry-After: 0\r\n", ""),
+            ("/v2/gifs/k1ng2h", 200, "Content-Type: application/json\r\n", r#"{"id":"k1ng2h"}"#),
+        ]);
+        let token = Mutex::new(Some(String::from("token")));
+        let api_url = format!("{}/v2/gifs/k1ng2h", server);
+        let response = redgifs_request::<serde_json::Value>(&Client::new(), &api_url, &token).await;
+        assert_eq!(response.unwrap(), Some(serde_json::json!({"id": "k1ng2h"})));
+    }
+
+    #[tokio::test]
+    async fn redgifs_request_skips_gone_media() {
+        let server = mock_server(vec![
+            ("/v2/gifs/k1ng2h", 410, "", ""),
+            ("/v2/gifs/k1ng2h", 200, "Content-Type: application/json\r\n", r#"{"id":"k1ng2h"}"#),
+        ]);
+        let token = Mutex::new(Some(String::from("token")));
+        let api_url = format!("{}/v2/gifs/k1ng2h", server);
+        let response = redgifs_request::<serde_json::Value>(&Client::new(), &api_url, &token).await;
+        assert_eq!(response.unwrap(), None);
+    }
+
+    #[test]
+    fn thumbnail_extension_from_url_path() {
+        assert_eq!(thumbnail_extension("https://b.thumbs.redditmedia.com/abc.png?s=123"), "png");
+        assert_eq!(thumbnail_extension("https://b.thumbs.redditmedia.com/abc.JPG"), "jpg");
+    }
+
+    #[test]
+    fn thumbnail_extension_falls_back_to_jpg() {
+        assert_eq!(thumbnail_extension("https://external-preview.redd.it/abc?width=140"), "jpg");
+        assert_eq!(thumbnail_extension("https://i.redd.it/"), "jpg");
+    }
+
+    #[test]
+    fn progress_of_pages_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_directory = dir.path().to_str().unwrap();
+        let downloader = Downloader::new(
+            test_options(data_directory),
+            Client::new(),
+            None,
+            Box::new(crate::storage::FileSystem::new(data_directory)),
+            None,
+        );
+        let page = Arc::new(Mutex::new(Summary::default()));
+        downloader.pages_in_progress.lock().unwrap().push(page.clone());
+        page.lock().unwrap().media_downloaded += 2;
+        let completed = Summary { media_downloaded: 1, ..Summary::default() };
+        assert_eq!(downloader.progress(&completed).media_downloaded, 3);
+    }
+
+    #[test]
+    fn file_names_in_flight_are_not_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_directory = dir.path().to_str().unwrap();
+        let file_name = format!("{}/cat.jpg", data_directory);
+        for policy in [CollisionPolicy::Overwrite, CollisionPolicy::Skip].iter() {
+            let mut options = test_options(data_directory);
+            options.on_collision = *policy;
+            let downloader = Downloader::new(
+                options,
+                Client::new(),
+                None,
+                Box::new(crate::storage::FileSystem::new(data_directory)),
+                None,
+            );
+            let claim = |url, post| downloader.claim_file_name(file_name.clone(), url, post);
+            assert_eq!(claim("https://i.redd.it/k1ng2h.jpg", "t3_k1ng2h"), file_name);
+            // the media of the first post is still being downloaded
+            assert_eq!(
+                claim("https://i.redd.it/k1ng2i.jpg", "t3_k1ng2i"),
+                format!("{}/cat (1).jpg", data_directory)
+            );
+            downloader.release_file_names("t3_k1ng2h");
+            assert_eq!(claim("https://i.redd.it/k1ng2j.jpg", "t3_k1ng2j"), file_name);
+        }
+    }
+
+    #[tokio::test]
+    async fn prune_keeps_the_media_of_older_posts() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_directory = dir.path().to_str().unwrap();
+        fs::create_dir(format!("{}/pics", data_directory)).unwrap();
+        let present = md5::compute("https://i.redd.it/k1ng2h.jpg");
+        fs::write(format!("{}/pics/img-{:x}.jpg", data_directory, present), "media").unwrap();
+        let older = md5::compute("https://i.redd.it/k1ng2i.jpg");
+        let older = format!("{}/pics/img-{:x}.jpg", data_directory, older);
+        fs::write(&older, "media").unwrap();
+
+        let mut options = test_options(data_directory);
+        options.prune = true;
+        options.delete_orphans = true;
+        options.since = Some(1606400001);
+        let mut newer = listing_post("k1ng2h", Some("https://i.redd.it/k1ng2h.jpg"));
+        newer["data"]["created_utc"] = serde_json::json!(1606400002.0);
+        let posts = vec![newer, listing_post("k1ng2i", Some("https://i.redd.it/k1ng2i.jpg"))];
+        run_posts(options, posts).await.unwrap();
+        assert!(Path::new(&older).exists());
+    }
+
+    #[tokio::test]
+    async fn media_is_moved_out_of_its_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_directory = dir.path().to_str().unwrap();
+        let server = mock_server(vec![(
+            "/i.redd.it/k1ng2h.jpg",
+            200,
+            "Content-Type: image/jpeg\r\n",
+            "media",
+        )]);
+        let url = format!("{}/i.redd.it/k1ng2h.jpg", server);
+        let posts = vec![listing_post("k1ng2h", Some(&url))];
+        let summary = run_posts(test_options(data_directory), posts).await.unwrap();
+        assert_eq!(summary.media_downloaded, 1);
+        let file_name = format!("{}/pics/img-{:x}.jpg", data_directory, md5::compute(&url));
+        assert_eq!(fs::read_to_string(&file_name).unwrap(), "media");
+        assert!(!Path::new(&format!("{}.{}", file_name, PART_EXTENSION)).exists());
+    }
+}
@@ -21,14 +21,94 @@ pub enum ReddSaverError {
     ParsingIntError(#[from] std::num::ParseIntError),
     #[error("Could not save usize to int")]
     TryFromIntError(#[from] std::num::TryFromIntError),
+    #[error("Reddit rejected the login: {0}")]
+    LoginFailed(String),
+    #[error("Reddit rejected the access token even after logging in again")]
+    AuthenticationFailed,
+    #[error("`{0}` configuration checks failed")]
+    ConfigValidationFailed(i32),
     #[error("Data directory not found, please check if it exists")]
     DataDirNotFound,
+    #[error("Saved posts are only accessible for the logged in user, use --submitted or --upvoted with --target-user")]
+    SavedNotAccessibleForTargetUser,
+    #[error("Undo is not supported for `{0}` posts")]
+    UndoNotSupported(String),
+    #[error("The app is not authorized with the `{0}` scope needed to undo, use --continue-without-undo to download without undoing")]
+    InsufficientScope(String),
+    #[error("Could not undo for post `{0}`, reddit responded with {1}")]
+    UndoFailed(String, String),
+    #[error("Could not undo `{0}` posts")]
+    UndoIncomplete(i32),
+    #[error("Invalid fullname `{0}`, expected something of the form t3_<id>")]
+    InvalidFullname(String),
+    #[error("Invalid post URL `{0}`, expected a permalink or a redd.it link to the post")]
+    InvalidPostUrl(String),
+    #[error("Unsupported archive `{0}`, the archive must be a .tar or .zip file")]
+    UnsupportedArchiveFormat(String),
+    #[error("The archive `{0}` already exists")]
+    ArchiveExists(String),
+    #[error("Media downloaded with aria2c cannot be moved into an archive or a bucket")]
+    MediaNotMovableWithAria2c,
+    #[error("Checksums cannot be computed for media downloaded with aria2c")]
+    ChecksumsWithAria2c,
+    #[error("Invalid S3 region `{0}`")]
+    InvalidS3Region(String),
+    #[error("Could not upload {0} to the bucket: {1}")]
+    S3Error(String, String),
+    #[error("Only {0} MB are free in the data directory, while {1} MB are required")]
+    InsufficientDiskSpace(u64, u64),
+    #[error("Temporary directory `{0}` not found, please check if it exists")]
+    TempDirNotFound(String),
+    #[error("Invalid extension `{0}`, expected something like gif or mp4")]
+    InvalidExtension(String),
+    #[error("Invalid age `{0}`, expected something like 24h or 7d")]
+    InvalidAge(String),
+    #[error("Invalid bind address `{0}`, expected an IPv4 or IPv6 address")]
+    InvalidBindAddress(String),
+    #[error("The bind address `{0}` is not an IPv4 address, while only IPv4 is to be used")]
+    BindAddressNotIpv4(String),
+    #[error("Could not read the certificate `{0}`: {1}")]
+    InvalidCaCert(String, String),
+    #[error("The image `{0}` is already present, not replacing it with the converted image")]
+    ConvertedImagePresent(String),
+    #[error("Page size must be at least 1")]
+    InvalidPageSize,
+    #[error("Unknown token `{0}` in the output template")]
+    UnknownTemplateToken(String),
+    #[error("{0} cannot be used with aria2c, which is run for the media of each post on its own")]
+    UnsupportedWithAria2c(&'static str),
+    #[error("Could not download `{0}` media")]
+    MediaDownloadFailed(i32),
     #[error("Could not create or save image")]
     IoError(#[from] std::io::Error),
     #[error("Unable to parse URL")]
     UrlError(#[from] url::ParseError),
+    #[error("Could not read or write mp4 file: {0}")]
+    Mp4Error(#[from] mp4::Error),
+    #[error("Could not read or write image: {0}")]
+    ImageError(#[from] image::ImageError),
+    #[error("Could not write zip archive: {0}")]
+    ZipError(#[from] zip::result::ZipError),
+    #[error("Could not serialize or deserialize JSON")]
+    JsonError(#[from] serde_json::Error),
     #[error("Could not convert to string")]
     ToStringConversionError(#[from] ToStrError),
     #[error("Could not convert from string")]
     FromStringConversionError(#[from] FromStrError),
 }
+
+impl ReddSaverError {
+    /// Whether the error is likely to go away when tried again, such as a failed connection or
+    /// an error of the reddit servers. Rejected credentials are never transient
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ReddSaverError::ReqwestError(e) => {
+                e.is_connect()
+                    || e.is_timeout()
+                    || e.is_request()
+                    || e.status().is_some_and(|s| s.is_server_error())
+            }
+            _ => false,
+        }
+    }
+}
@@ -1,32 +1,273 @@
+use std::collections::HashSet;
 use std::env;
+use std::ffi::OsString;
+use std::future::Future;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use clap::{crate_version, App, Arg};
+use chrono::Utc;
+use clap::{crate_version, App, Arg, ArgMatches};
 use env_logger::Env;
+use futures::try_join;
 use log::{debug, info, warn};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio::time::{delay_for, Instant};
 
 use auth::Client;
 
-use crate::download::Downloader;
+use crate::archive::Archive;
+use crate::checksum::ChecksumAlgorithm;
+use crate::convert::ImageFormat;
+use crate::download::{
+    check_output_template, download_from_manifest, print_download_events, print_supported_hosts,
+    retry_failures, AudioFormat, CollisionPolicy, DownloadOptions, Downloader,
+};
 use crate::errors::ReddSaverError;
-use crate::errors::ReddSaverError::DataDirNotFound;
-use crate::user::{ListingType, User};
+use crate::errors::ReddSaverError::{
+    ConfigValidationFailed, DataDirNotFound, InvalidFullname, InvalidPageSize, InvalidPostUrl,
+    MediaDownloadFailed, SavedNotAccessibleForTargetUser, TempDirNotFound, UndoNotSupported,
+};
+use crate::storage::{FileSystem, S3Storage, StorageBackend};
+use crate::user::{load_listing, ListingType, User, MAX_PAGE_SIZE};
 use crate::utils::*;
 
+mod archive;
 mod auth;
+mod checksum;
+mod comments;
+mod convert;
 mod download;
 mod errors;
+mod remux;
+mod storage;
 mod structures;
 mod user;
 mod utils;
 
+/// Environment variables that can be used to set the arguments instead of passing them.
+/// The arguments passed take precedence over the environment variables
+static ARG_ENV_VARS: &[(&str, &str)] = &[
+    ("environment", "REDDSAVER_FROM_ENV"),
+    ("data_directory", "REDDSAVER_DATA_DIR"),
+    ("show_config", "REDDSAVER_SHOW_CONFIG"),
+    ("dry_run", "REDDSAVER_DRY_RUN"),
+    ("print_urls", "REDDSAVER_PRINT_URLS"),
+    ("print_events", "REDDSAVER_PRINT_EVENTS"),
+    ("summary_only", "REDDSAVER_SUMMARY_ONLY"),
+    ("refresh_metadata", "REDDSAVER_REFRESH_METADATA"),
+    ("human_readable", "REDDSAVER_HUMAN_READABLE"),
+    ("ascii_filenames", "REDDSAVER_ASCII_FILENAMES"),
+    ("segregate_by_listing", "REDDSAVER_SEGREGATE_BY_LISTING"),
+    ("segregate_by_type", "REDDSAVER_SEGREGATE_BY_TYPE"),
+    ("gallery_subfolder", "REDDSAVER_GALLERY_SUBFOLDER"),
+    ("output_template", "REDDSAVER_OUTPUT_TEMPLATE"),
+    ("on_collision", "REDDSAVER_ON_COLLISION"),
+    ("subreddits", "REDDSAVER_SUBREDDITS"),
+    ("exclude_extensions", "REDDSAVER_EXCLUDE_EXTENSIONS"),
+    ("skip_subreddits_file", "REDDSAVER_SKIP_SUBREDDITS_FILE"),
+    ("flair", "REDDSAVER_FLAIR"),
+    ("max_per_subreddit", "REDDSAVER_MAX_PER_SUBREDDIT"),
+    ("exclude_flair", "REDDSAVER_EXCLUDE_FLAIR"),
+    ("since", "REDDSAVER_SINCE"),
+    ("upvoted", "REDDSAVER_UPVOTED"),
+    ("submitted", "REDDSAVER_SUBMITTED"),
+    ("target_user", "REDDSAVER_TARGET_USER"),
+    ("multireddit", "REDDSAVER_MULTIREDDIT"),
+    ("after", "REDDSAVER_AFTER"),
+    ("throttle", "REDDSAVER_THROTTLE"),
+    ("max_duration", "REDDSAVER_MAX_DURATION"),
+    ("stats_interval", "REDDSAVER_STATS_INTERVAL"),
+    ("min_karma", "REDDSAVER_MIN_KARMA"),
+    ("downloader", "REDDSAVER_DOWNLOADER"),
+    ("order", "REDDSAVER_ORDER"),
+    ("prefer_mp4", "REDDSAVER_PREFER_MP4"),
+    ("record_source", "REDDSAVER_RECORD_SOURCE"),
+    ("log_format", "REDDSAVER_LOG_FORMAT"),
+    ("xmp_sidecar", "REDDSAVER_XMP_SIDECAR"),
+    ("with_thumbnails", "REDDSAVER_WITH_THUMBNAILS"),
+    ("no_audio", "REDDSAVER_NO_AUDIO"),
+    ("extract_audio", "REDDSAVER_EXTRACT_AUDIO"),
+    ("preview_fallback", "REDDSAVER_PREVIEW_FALLBACK"),
+    ("thumbnail_fallback", "REDDSAVER_THUMBNAIL_FALLBACK"),
+    ("post_download_hook", "REDDSAVER_POST_DOWNLOAD_HOOK"),
+    ("max_rate", "REDDSAVER_MAX_RATE"),
+    ("checksums", "REDDSAVER_CHECKSUMS"),
+    ("convert_images", "REDDSAVER_CONVERT_IMAGES"),
+    ("host_stats", "REDDSAVER_HOST_STATS"),
+    ("max_host_failures", "REDDSAVER_MAX_HOST_FAILURES"),
+    ("json_lines", "REDDSAVER_JSON_LINES"),
+    ("with_comments", "REDDSAVER_WITH_COMMENTS"),
+    ("trace_post", "REDDSAVER_TRACE_POST"),
+    ("dedupe_report", "REDDSAVER_DEDUPE_REPORT"),
+    ("mapping_file", "REDDSAVER_MAPPING_FILE"),
+    ("connect_timeout", "REDDSAVER_CONNECT_TIMEOUT"),
+    ("ipv4_only", "REDDSAVER_IPV4_ONLY"),
+    ("bind_address", "REDDSAVER_BIND_ADDRESS"),
+    ("ca_cert", "REDDSAVER_CA_CERT"),
+    ("insecure", "REDDSAVER_INSECURE"),
+    ("read_timeout", "REDDSAVER_READ_TIMEOUT"),
+    ("startup_retries", "REDDSAVER_STARTUP_RETRIES"),
+    ("temp_dir", "REDDSAVER_TEMP_DIR"),
+    ("require_space", "REDDSAVER_REQUIRE_SPACE"),
+    ("retry_failures", "REDDSAVER_RETRY_FAILURES"),
+    ("resolve_only", "REDDSAVER_RESOLVE_ONLY"),
+    ("archive", "REDDSAVER_ARCHIVE"),
+    ("s3_bucket", "REDDSAVER_S3_BUCKET"),
+    ("no_existing_check", "REDDSAVER_NO_EXISTING_CHECK"),
+    ("s3_endpoint", "REDDSAVER_S3_ENDPOINT"),
+    ("s3_region", "REDDSAVER_S3_REGION"),
+    ("from_manifest", "REDDSAVER_FROM_MANIFEST"),
+    ("page_size", "REDDSAVER_PAGE_SIZE"),
+    ("dump_listing", "REDDSAVER_DUMP_LISTING"),
+    ("input_listing", "REDDSAVER_INPUT_LISTING"),
+    ("url", "REDDSAVER_URL"),
+    ("undo", "REDDSAVER_UNDO"),
+    ("continue_without_undo", "REDDSAVER_CONTINUE_WITHOUT_UNDO"),
+    ("strict", "REDDSAVER_STRICT"),
+    ("prune", "REDDSAVER_PRUNE"),
+    ("yes", "REDDSAVER_YES"),
+    ("absolute_paths", "REDDSAVER_ABSOLUTE_PATHS"),
+    ("list_supported", "REDDSAVER_LIST_SUPPORTED"),
+    ("validate", "REDDSAVER_VALIDATE"),
+];
+
+/// Delay between starting each download for accounts with lesser karma than the minimum
+static LOW_KARMA_THROTTLE: Duration = Duration::from_millis(1000);
+/// Time to wait before retrying a request at startup for the first time
+static STARTUP_RETRY_DELAY: Duration = Duration::from_secs(2);
+/// Number of top comments saved for each post when the number is not given
+static DEFAULT_COMMENTS: u32 = 10;
+
+/// Check that the configuration works before starting a run. Each of the checks is reported as
+/// passed or failed, and an error is returned if any of them failed
+async fn validate_config(
+    data_directory: &str,
+    ffmpeg_available: bool,
+    api_client: reqwest::Client,
+) -> Result<(), ReddSaverError> {
+    let mut failed = 0;
+    let mut report = |check: &str, result: Result<(), String>| match result {
+        Ok(_) => info!("[PASS] {}", check),
+        Err(e) => {
+            warn!("[FAIL] {}: {}", check, e);
+            failed += 1;
+        }
+    };
+
+    // the data directory is writable if a file can be created in it, which is deleted when dropped
+    let writable =
+        tempfile::NamedTempFile::new_in(data_directory).map(|_| ()).map_err(|e| e.to_string());
+    report("Data directory is writable", writable);
+
+    let credentials: Result<Vec<String>, String> =
+        ["CLIENT_ID", "CLIENT_SECRET", "USERNAME", "PASSWORD"]
+            .iter()
+            .map(|name| env::var(name).map_err(|_| format!("{} is not set", name)))
+            .collect();
+    match credentials {
+        Ok(c) => {
+            report("Credentials are present", Ok(()));
+            let user_agent = get_user_agent_string(None, None);
+            let client = Client::new(&c[0], &c[1], &c[2], &c[3], &user_agent, api_client);
+            let login = client.login().await;
+            report("Logged in to Reddit", login.map(|_| ()).map_err(|e| e.to_string()));
+        }
+        Err(e) => report("Credentials are present", Err(e)),
+    }
+
+    // videos can still be combined without ffmpeg, so this is not treated as a failure
+    if ffmpeg_available {
+        info!("[PASS] ffmpeg is installed");
+    } else {
+        warn!("[WARN] ffmpeg is not installed, some videos cannot be downloaded");
+    }
+
+    if failed > 0 {
+        return Err(ConfigValidationFailed(failed));
+    }
+    Ok(())
+}
+
+/// Retry the request made at startup when it fails with a transient error, waiting twice as
+/// long after each attempt. This keeps unattended runs from failing on a network hiccup
+async fn retry_startup<T, F, Fut>(what: &str, retries: u32, request: F) -> Result<T, ReddSaverError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, ReddSaverError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Err(e) if e.is_transient() && attempt < retries => {
+                let delay = STARTUP_RETRY_DELAY * 2u32.pow(attempt);
+                attempt += 1;
+                warn!(
+                    "Could not {}: {}. Retrying in {}s (attempt {} of {})",
+                    what,
+                    e,
+                    delay.as_secs(),
+                    attempt,
+                    retries
+                );
+                delay_for(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Display the timeout in the configuration
+fn format_timeout(timeout: Option<Duration>) -> String {
+    timeout.map_or(String::from("<NONE>"), |t| format!("{}s", t.as_secs()))
+}
+
+/// Name of the environment variable for the argument
+fn arg_env_var(name: &str) -> &'static str {
+    match ARG_ENV_VARS.iter().find(|(arg, _)| *arg == name) {
+        Some((_, var)) => var,
+        // an argument missing from the table is still given a variable named after it
+        None => Box::leak(format!("REDDSAVER_{}", name.to_uppercase()).into_boxed_str()),
+    }
+}
+
+/// Flags whose environment variable is set to a true value, eg: 1 or true, and which are not
+/// passed. clap only reads the environment variables of the arguments taking a value, so these
+/// are passed as arguments for their conflicts and requirements to be checked like the others
+fn env_flags(matches: &ArgMatches) -> Vec<String> {
+    ARG_ENV_VARS
+        .iter()
+        .filter(|(arg, _)| !matches.is_present(arg))
+        .filter(|(_, var)| {
+            env::var(var)
+                .map(|v| ["1", "true", "yes"].contains(&v.to_lowercase().as_str()))
+                .unwrap_or(false)
+        })
+        .map(|(arg, _)| format!("--{}", arg.replace('_', "-")))
+        .collect()
+}
+
+/// Environment variables with the prefix of the arguments that are not the variable of any of
+/// them, eg: when mistyped
+fn unknown_env_vars() -> Vec<String> {
+    env::vars_os()
+        .filter_map(|(var, _)| var.into_string().ok())
+        .filter(|var| var.starts_with("REDDSAVER_"))
+        .filter(|var| !ARG_ENV_VARS.iter().any(|(_, v)| v == var))
+        .collect()
+}
+
 #[tokio::main]
 async fn main() -> Result<(), ReddSaverError> {
-    let matches = App::new("ReddSaver")
+    let app = App::new("ReddSaver")
         .version(crate_version!())
         .author("Manoj Karthick Selva Kumar")
         .about("Simple CLI tool to download saved media from Reddit")
         .arg(
             Arg::with_name("environment")
+                .env(arg_env_var("environment"))
                 .short("e")
                 .long("from-env")
                 .value_name("ENV_FILE")
@@ -36,6 +277,7 @@ async fn main() -> Result<(), ReddSaverError> {
         )
         .arg(
             Arg::with_name("data_directory")
+                .env(arg_env_var("data_directory"))
                 .short("d")
                 .long("data-dir")
                 .value_name("DATA_DIR")
@@ -55,7 +297,35 @@ async fn main() -> Result<(), ReddSaverError> {
                 .short("r")
                 .long("dry-run")
                 .takes_value(false)
-                .help("Dry run and print the URLs of saved media to download"),
+                .help("Dry run and print the URLs of saved media, labelled as new or already downloaded"),
+        )
+        .arg(
+            Arg::with_name("print_urls")
+                .short("p")
+                .long("print-urls")
+                .takes_value(false)
+                .help("Print only the URLs of saved media to stdout without downloading"),
+        )
+        .arg(
+            Arg::with_name("print_events")
+                .long("print-events")
+                .takes_value(false)
+                .conflicts_with("print_urls")
+                .help("Print the progress of the run to stdout as JSON lines"),
+        )
+        .arg(
+            Arg::with_name("summary_only")
+                .long("summary-only")
+                .takes_value(false)
+                .conflicts_with_all(&["dry_run", "print_urls"])
+                .help("Only report how much of the saved media is new without downloading"),
+        )
+        .arg(
+            Arg::with_name("refresh_metadata")
+                .long("refresh-metadata")
+                .takes_value(false)
+                .conflicts_with_all(&["dry_run", "print_urls", "summary_only"])
+                .help("Only rewrite the XMP sidecars of the images already downloaded"),
         )
         .arg(
             Arg::with_name("human_readable")
@@ -64,8 +334,55 @@ async fn main() -> Result<(), ReddSaverError> {
                 .takes_value(false)
                 .help("Use human readable names for files"),
         )
+        .arg(
+            Arg::with_name("ascii_filenames")
+                .long("ascii-filenames")
+                .takes_value(false)
+                .help("Transliterate the titles in the names of files to ASCII, eg: café to cafe"),
+        )
+        .arg(
+            Arg::with_name("segregate_by_listing")
+                .long("segregate-by-listing")
+                .takes_value(false)
+                .help("Save media under a separate directory for each listing type"),
+        )
+        .arg(
+            Arg::with_name("segregate_by_type")
+                .long("segregate-by-type")
+                .takes_value(false)
+                .help("Save images, gifs and videos under separate directories"),
+        )
+        .arg(
+            Arg::with_name("gallery_subfolder")
+                .long("gallery-subfolder")
+                .takes_value(false)
+                .help("Save the images of each gallery in a directory named after the post"),
+        )
+        .arg(
+            Arg::with_name("output_template")
+                .env(arg_env_var("output_template"))
+                .short("o")
+                .long("output-template")
+                .value_name("TEMPLATE")
+                .help(
+                    "Template for the path of the media in the data directory, \
+                    eg: {year}/{subreddit}/{id}-{title}.{ext}",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on_collision")
+                .env(arg_env_var("on_collision"))
+                .long("on-collision")
+                .value_name("POLICY")
+                .help("What to do when the media of different URLs are given the same file name")
+                .possible_values(&["overwrite", "skip", "suffix"])
+                .default_value("overwrite")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("subreddits")
+                .env(arg_env_var("subreddits"))
                 .short("S")
                 .long("subreddits")
                 .multiple(true)
@@ -74,6 +391,56 @@ async fn main() -> Result<(), ReddSaverError> {
                 .help("Download media from these subreddits only")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("exclude_extensions")
+                .env(arg_env_var("exclude_extensions"))
+                .long("exclude-extensions")
+                .multiple(true)
+                .value_name("EXTENSIONS")
+                .value_delimiter(",")
+                .help("Do not download the media saved with these extensions, eg: gif,mp4")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("skip_subreddits_file")
+                .env(arg_env_var("skip_subreddits_file"))
+                .long("skip-subreddits-file")
+                .value_name("FILE")
+                .help("Never download media from the subreddits in this file, one on each line")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flair")
+                .env(arg_env_var("flair"))
+                .long("flair")
+                .value_name("FLAIR")
+                .help("Download media from the posts with this flair only, ignoring case")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("exclude_flair")
+                .env(arg_env_var("exclude_flair"))
+                .long("exclude-flair")
+                .value_name("FLAIR")
+                .help("Never download media from the posts with this flair, ignoring case")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("since")
+                .env(arg_env_var("since"))
+                .long("since")
+                .value_name("AGE")
+                .help("Only download media from the posts created within this age, eg: 24h or 7d")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_per_subreddit")
+                .env(arg_env_var("max_per_subreddit"))
+                .long("max-per-subreddit")
+                .value_name("N")
+                .help("Download media from at most this many posts of each subreddit")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("upvoted")
                 .short("u")
@@ -81,6 +448,435 @@ async fn main() -> Result<(), ReddSaverError> {
                 .takes_value(false)
                 .help("Download media from upvoted posts"),
         )
+        .arg(
+            Arg::with_name("submitted")
+                .long("submitted")
+                .takes_value(false)
+                .conflicts_with("upvoted")
+                .help("Download media from submitted posts"),
+        )
+        .arg(
+            Arg::with_name("multireddit")
+                .env(arg_env_var("multireddit"))
+                .long("multireddit")
+                .value_name("NAME")
+                .help("Download media from the newest posts of this multireddit, eg: art or user/art")
+                .conflicts_with_all(&["upvoted", "submitted"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target_user")
+                .env(arg_env_var("target_user"))
+                .short("t")
+                .long("target-user")
+                .value_name("USERNAME")
+                .help("Download media from the public listings of this user instead")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("after")
+                .env(arg_env_var("after"))
+                .short("a")
+                .long("after")
+                .value_name("FULLNAME")
+                .help("Start from the listing page after this post, eg: t3_k1ng2h")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("throttle")
+                .env(arg_env_var("throttle"))
+                .long("throttle")
+                .value_name("MILLISECONDS")
+                .help("Minimum delay between starting each download")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_duration")
+                .env(arg_env_var("max_duration"))
+                .long("max-duration")
+                .value_name("MINUTES")
+                .help("Stop starting new downloads once the run has taken this long")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stats_interval")
+                .env(arg_env_var("stats_interval"))
+                .long("stats-interval")
+                .value_name("SECONDS")
+                .help("Log the number of media downloaded, skipped and failed so far at this interval")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min_karma")
+                .env(arg_env_var("min_karma"))
+                .long("min-karma")
+                .value_name("KARMA")
+                .help("Throttle the downloads for accounts with lesser karma to avoid rate limits")
+                .default_value("100")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("downloader")
+                .env(arg_env_var("downloader"))
+                .long("downloader")
+                .value_name("DOWNLOADER")
+                .help("Program to download the media with, aria2c needs to be installed")
+                .possible_values(&["builtin", "aria2c"])
+                .default_value("builtin")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("order")
+                .env(arg_env_var("order"))
+                .long("order")
+                .value_name("ORDER")
+                .help("Order in which the posts are processed, the downloads still complete in any order")
+                .possible_values(&["newest", "oldest"])
+                .default_value("newest")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("prefer_mp4")
+                .long("prefer-mp4")
+                .takes_value(false)
+                .help("Download the smaller mp4 version of reddit gifs when available"),
+        )
+        .arg(
+            Arg::with_name("record_source")
+                .long("record-source")
+                .takes_value(false)
+                .help("Record the source URL of the media in extended attributes or a .url file"),
+        )
+        .arg(
+            Arg::with_name("xmp_sidecar")
+                .long("xmp-sidecar")
+                .takes_value(false)
+                .help("Write the title, permalink and date of the post to an .xmp file next to images"),
+        )
+        .arg(
+            Arg::with_name("with_thumbnails")
+                .long("with-thumbnails")
+                .takes_value(false)
+                .help("Also download the thumbnail of gfycat and redgifs gifs"),
+        )
+        .arg(
+            Arg::with_name("no_audio")
+                .long("no-audio")
+                .takes_value(false)
+                .help("Download reddit videos without their audio track"),
+        )
+        .arg(
+            Arg::with_name("extract_audio")
+                .env(arg_env_var("extract_audio"))
+                .long("extract-audio")
+                .value_name("FORMAT")
+                .help("Also save the audio of reddit videos on its own, mp3 is transcoded with ffmpeg")
+                .possible_values(&["mp4", "mp3"])
+                .conflicts_with("no_audio")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("preview_fallback")
+                .long("preview-fallback")
+                .takes_value(false)
+                .help("Download the reddit preview of videos from dead or unsupported hosts"),
+        )
+        .arg(
+            Arg::with_name("thumbnail_fallback")
+                .long("thumbnail-fallback")
+                .takes_value(false)
+                .help("Download the thumbnail of the posts none of whose media could be downloaded"),
+        )
+        .arg(
+            Arg::with_name("post_download_hook")
+                .env(arg_env_var("post_download_hook"))
+                .long("post-download-hook")
+                .value_name("PROGRAM")
+                .help(
+                    "Program to run for each downloaded file, with the path of the file, \
+                    the ID of the post and its permalink as the arguments",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log_format")
+                .env(arg_env_var("log_format"))
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Format of the logs, json writes one JSON object per line")
+                .possible_values(&["text", "json"])
+                .default_value("text")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_rate")
+                .env(arg_env_var("max_rate"))
+                .long("max-rate")
+                .value_name("BYTES_PER_SECOND")
+                .help("Maximum combined download rate of all the downloads")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dedupe_report")
+                .env(arg_env_var("dedupe_report"))
+                .long("dedupe-report")
+                .value_name("FILE")
+                .help("Save the groups of posts with the same media to this file as JSON")
+                .conflicts_with_all(&["dry_run", "print_urls", "summary_only", "resolve_only"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mapping_file")
+                .env(arg_env_var("mapping_file"))
+                .long("mapping-file")
+                .value_name("FILE")
+                .help("Save the ID of the post, the URL and the path of each media to this file as TSV")
+                .conflicts_with_all(&["dry_run", "print_urls", "summary_only", "resolve_only"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("host_stats")
+                .long("host-stats")
+                .takes_value(false)
+                .help("Report the downloads, their total size and throughput for each host"),
+        )
+        .arg(
+            Arg::with_name("max_host_failures")
+                .env(arg_env_var("max_host_failures"))
+                .long("max-host-failures")
+                .value_name("N")
+                .help("Skip the rest of the media of a host after this many failed downloads in a row, 0 to never skip")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("json_lines")
+                .long("json-lines")
+                .takes_value(false)
+                .conflicts_with_all(&["dry_run", "print_urls", "summary_only", "resolve_only"])
+                .help("Write the result of each media to stdout as a JSON line as soon as it is known"),
+        )
+        .arg(
+            Arg::with_name("with_comments")
+                .env(arg_env_var("with_comments"))
+                .long("with-comments")
+                .value_name("N")
+                .help("Save the top comments of each downloaded post as Markdown next to its media, 10 by default")
+                .conflicts_with_all(&["dry_run", "print_urls", "summary_only", "resolve_only"])
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1),
+        )
+        .arg(
+            Arg::with_name("trace_post")
+                .env(arg_env_var("trace_post"))
+                .long("trace-post")
+                .value_name("ID")
+                .help("Log every decision made in resolving the media of this post, eg: k1ng2h")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("checksums")
+                .env(arg_env_var("checksums"))
+                .long("checksums")
+                .value_name("ALGORITHM")
+                .help("Record the checksums of the downloaded media in the folder of each media")
+                .possible_values(&["md5", "sha256"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("convert_images")
+                .env(arg_env_var("convert_images"))
+                .long("convert-images")
+                .value_name("FORMAT")
+                .help("Convert the downloaded images to this format, replacing the originals")
+                .possible_values(&["jpg", "png"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect_timeout")
+                .env(arg_env_var("connect_timeout"))
+                .long("connect-timeout")
+                .value_name("SECONDS")
+                .help("Time to wait for connecting to the host of the media, 0 waits forever")
+                .default_value("30")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("read_timeout")
+                .env(arg_env_var("read_timeout"))
+                .long("read-timeout")
+                .value_name("SECONDS")
+                .help("Time to wait for each download to complete, 0 waits forever")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("require_space")
+                .env(arg_env_var("require_space"))
+                .long("require-space")
+                .value_name("MB")
+                .help("Do not start downloading unless this much space is free in the data directory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("temp_dir")
+                .env(arg_env_var("temp_dir"))
+                .long("temp-dir")
+                .value_name("DIR")
+                .help("Directory to combine the videos in with ffmpeg, the data directory by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("startup_retries")
+                .env(arg_env_var("startup_retries"))
+                .long("startup-retries")
+                .value_name("N")
+                .help("Number of times logging in and fetching the user are retried on network errors")
+                .default_value("3")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ipv4_only")
+                .long("ipv4-only")
+                .takes_value(false)
+                .help("Only connect to the hosts of the media over IPv4"),
+        )
+        .arg(
+            Arg::with_name("bind_address")
+                .env(arg_env_var("bind_address"))
+                .long("bind-address")
+                .value_name("IP")
+                .help("Connect to the hosts of the media from this local address, eg: of a VPN")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ca_cert")
+                .env(arg_env_var("ca_cert"))
+                .long("ca-cert")
+                .value_name("FILE")
+                .help("Also trust this root certificate in PEM or DER format, eg: of a corporate proxy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("insecure")
+                .long("insecure")
+                .takes_value(false)
+                .help("INSECURE: Do not verify the certificates of reddit and the hosts, only for debugging"),
+        )
+        .arg(
+            Arg::with_name("retry_failures")
+                .long("retry-failures")
+                .takes_value(false)
+                .help("Retry only the downloads that failed in previous runs"),
+        )
+        .arg(
+            Arg::with_name("archive")
+                .env(arg_env_var("archive"))
+                .long("archive")
+                .value_name("ARCHIVE_FILE")
+                .help("Save the media to this new .tar or .zip archive instead of the data directory")
+                .conflicts_with_all(&["dry_run", "print_urls", "summary_only", "resolve_only"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("s3_bucket")
+                .env(arg_env_var("s3_bucket"))
+                .long("s3-bucket")
+                .value_name("BUCKET")
+                .help("Upload the media to this S3 bucket instead of keeping it in the data directory")
+                .conflicts_with_all(&[
+                    "dry_run",
+                    "print_urls",
+                    "summary_only",
+                    "resolve_only",
+                    "archive",
+                    "prune",
+                    "retry_failures",
+                    "from_manifest",
+                ])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no_existing_check")
+                .long("no-existing-check")
+                .takes_value(false)
+                .help("List the data directory once instead of checking if each media is already downloaded")
+                .conflicts_with("s3_bucket"),
+        )
+        .arg(
+            Arg::with_name("s3_endpoint")
+                .env(arg_env_var("s3_endpoint"))
+                .long("s3-endpoint")
+                .value_name("URL")
+                .help("Endpoint of an S3-compatible storage to upload the media to")
+                .requires("s3_bucket")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("s3_region")
+                .env(arg_env_var("s3_region"))
+                .long("s3-region")
+                .value_name("REGION")
+                .help("Region of the S3 bucket, read from the AWS environment if not given")
+                .requires("s3_bucket")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resolve_only")
+                .env(arg_env_var("resolve_only"))
+                .long("resolve-only")
+                .visible_alias("plan-only")
+                .value_name("MANIFEST_FILE")
+                .help("Only resolve the media and save the URLs and paths to this file")
+                .conflicts_with_all(&["dry_run", "print_urls", "summary_only", "refresh_metadata"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("from_manifest")
+                .env(arg_env_var("from_manifest"))
+                .long("from-manifest")
+                .value_name("MANIFEST_FILE")
+                .help("Download the media from a file saved with --resolve-only")
+                .conflicts_with_all(&["resolve_only", "retry_failures"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("page_size")
+                .env(arg_env_var("page_size"))
+                .long("page-size")
+                .value_name("PAGE_SIZE")
+                .help("Number of posts to fetch in each request to Reddit, up to 100")
+                .default_value("100")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dump_listing")
+                .env(arg_env_var("dump_listing"))
+                .long("dump-listing")
+                .value_name("FILE")
+                .help("Save the listing fetched from Reddit as JSON to this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("input_listing")
+                .env(arg_env_var("input_listing"))
+                .long("input-listing")
+                .value_name("FILE")
+                .help("Download the media from a listing saved with --dump-listing without logging in")
+                .conflicts_with_all(&["dump_listing", "after", "undo"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("url")
+                .env(arg_env_var("url"))
+                .long("url")
+                .value_name("POST_URL")
+                .help("Download the media of only this post, eg: https://redd.it/k1ng2h")
+                .conflicts_with_all(&["input_listing", "dump_listing", "after"])
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("undo")
                 .short("U")
@@ -88,38 +884,436 @@ async fn main() -> Result<(), ReddSaverError> {
                 .takes_value(false)
                 .help("Unsave or remote upvote for post after processing"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("continue_without_undo")
+                .long("continue-without-undo")
+                .takes_value(false)
+                .requires("undo")
+                .help("Keep downloading without undoing if the app is not authorized to undo"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .takes_value(false)
+                .help("Exit with an error if any media was skipped, not only if it failed"),
+        )
+        .arg(
+            Arg::with_name("prune")
+                .long("prune")
+                .takes_value(false)
+                .help("Report the files in the data directory of the posts not in the listing")
+                .conflicts_with_all(&[
+                    "subreddits",
+                    "skip_subreddits_file",
+                    "flair",
+                    "exclude_flair",
+                    "max_per_subreddit",
+                    "exclude_extensions",
+                    "since",
+                    "after",
+                    "url",
+                    "max_duration",
+                    "output_template",
+                ]),
+        )
+        .arg(
+            Arg::with_name("yes")
+                .long("yes")
+                .takes_value(false)
+                .requires("prune")
+                .help("Delete the files reported by --prune"),
+        )
+        .arg(
+            Arg::with_name("absolute_paths")
+                .long("absolute-paths")
+                .takes_value(false)
+                .help("Use absolute paths for the media in the logs and reports"),
+        )
+        .arg(
+            Arg::with_name("list_supported")
+                .long("list-supported")
+                .takes_value(false)
+                .help("List the hosts the media can be downloaded from and exit"),
+        )
+        .arg(
+            Arg::with_name("validate")
+                .long("validate")
+                .takes_value(false)
+                .help("Check the credentials, the data directory and ffmpeg, then exit"),
+        );
+    let matches = app.clone().get_matches();
+    let env_flags = env_flags(&matches);
+    let matches = if env_flags.is_empty() {
+        matches
+    } else {
+        app.get_matches_from(env::args_os().chain(env_flags.into_iter().map(OsString::from)))
+    };
+
+    if matches.is_present("list_supported") {
+        print_supported_hosts();
+        return Ok(());
+    }
 
     let env_file = matches.value_of("environment").unwrap();
-    let data_directory = String::from(matches.value_of("data_directory").unwrap());
+    let mut data_directory = String::from(matches.value_of("data_directory").unwrap());
     // generate the URLs to download from without actually downloading the media
-    let should_download = !matches.is_present("dry_run");
+    let print_urls = matches.is_present("print_urls");
+    // stream the progress of the run to stdout for other tools to follow
+    let print_events = matches.is_present("print_events");
+    // only count the media that are new or already present on disk
+    let summary_only = matches.is_present("summary_only");
+    // backfill the metadata of the media downloaded before, without downloading anything
+    let refresh_metadata = matches.is_present("refresh_metadata");
+    // resolve the media and save it to a manifest to download it later using --from-manifest
+    let resolve_only = matches.value_of("resolve_only");
+    let should_download = !matches.is_present("dry_run")
+        && !print_urls
+        && !summary_only
+        && !refresh_metadata
+        && resolve_only.is_none();
     // check if ffmpeg is present for combining video streams
     let ffmpeg_available = application_present(String::from("ffmpeg"));
     // generate human readable file names instead of MD5 Hashed file names
     let use_human_readable = matches.is_present("human_readable");
+    // portable file names for filesystems and tools which do not handle unicode well
+    let ascii_filenames = matches.is_present("ascii_filenames");
+    // save media to <data_dir>/<listing_type>/<subreddit> instead of <data_dir>/<subreddit>
+    let segregate_by_listing = matches.is_present("segregate_by_listing");
+    // save media to <data_dir>/<media_type>/<subreddit>, eg: data/images/pics
+    let segregate_by_type = matches.is_present("segregate_by_type");
+    // save gallery images to <subreddit>/<post_name>/<index> instead of next to the other media
+    let gallery_subfolder = matches.is_present("gallery_subfolder");
+    // control the whole path of the media, including the directories, using a template
+    let output_template = match matches.value_of("output_template") {
+        Some(t) => Some(check_output_template(t)?),
+        None => None,
+    };
+    // different media can end up with the same name, eg: posts with the same title
+    let on_collision =
+        CollisionPolicy::from_name(matches.value_of("on_collision").unwrap()).unwrap();
     // restrict downloads to these subreddits
     let subreddits: Option<Vec<&str>> = if matches.is_present("subreddits") {
         Some(matches.values_of("subreddits").unwrap().collect())
     } else {
         None
     };
+    // formats that are not wanted, eg: animated content, or that other tools cannot handle
+    let excluded_extensions = match matches.values_of("exclude_extensions") {
+        Some(extensions) => extensions.map(check_extension).collect::<Result<Vec<String>, _>>()?,
+        None => Vec::new(),
+    };
+    // routine backups of the recent posts, without keeping track of the last run
+    let since = match matches.value_of("since") {
+        Some(age) => Some(Utc::now().timestamp() - parse_age(age)?.as_secs() as i64),
+        None => None,
+    };
+    // a reusable list of subreddits to exclude, instead of passing them in every run
+    let skipped_subreddits = match matches.value_of("skip_subreddits_file") {
+        Some(file_name) => read_subreddits(file_name)?,
+        None => Vec::new(),
+    };
     let upvoted = matches.is_present("upvoted");
-    let listing_type = if upvoted { &ListingType::Upvoted } else { &ListingType::Saved };
+    let submitted = matches.is_present("submitted");
+    // a multireddit of another user can be given as <user>/<multireddit>
+    let multireddit = matches.value_of("multireddit").map(|m| match m.rsplit_once('/') {
+        Some((owner, name)) => ListingType::Multireddit {
+            owner: Some(String::from(owner.trim_start_matches("user/"))),
+            name: String::from(name),
+        },
+        None => ListingType::Multireddit { owner: None, name: String::from(m) },
+    });
+    let listing_type = &if let Some(multireddit) = multireddit {
+        multireddit
+    } else if upvoted {
+        ListingType::Upvoted
+    } else if submitted {
+        ListingType::Submitted
+    } else {
+        ListingType::Saved
+    };
+    // archive the public listings of another user instead of the logged in user
+    let target_user = matches.value_of("target_user");
+
+    // resume the listing from the page after this post instead of from the start
+    let after = matches.value_of("after");
+    if let Some(a) = after {
+        if !is_valid_fullname(a) {
+            return Err(InvalidFullname(String::from(a)));
+        }
+    }
+
+    // hand over the downloads to aria2c if it is requested and installed
+    let use_aria2c = matches.value_of("downloader") == Some("aria2c");
+    // the media downloaded by aria2c cannot be moved into the archive or the bucket
+    if use_aria2c && (matches.is_present("archive") || matches.is_present("s3_bucket")) {
+        return Err(ReddSaverError::MediaNotMovableWithAria2c);
+    }
+    // find out which of the hosts is slowing down the run
+    let host_stats = matches.is_present("host_stats");
+    if use_aria2c && host_stats {
+        return Err(ReddSaverError::UnsupportedWithAria2c("--host-stats"));
+    }
+    // look up the media already downloaded in a listing of the data directory made at the start
+    let no_existing_check = matches.is_present("no_existing_check");
+    // a host that is down would otherwise be requested for every one of its media
+    let max_host_failures = matches.value_of("max_host_failures").unwrap().parse::<u32>()?;
+    // the failures are only known once aria2c is done with all the media of the post
+    let max_host_failures_set = matches.occurrences_of("max_host_failures") > 0
+        || env::var_os(arg_env_var("max_host_failures")).is_some();
+    if use_aria2c && max_host_failures_set {
+        return Err(ReddSaverError::UnsupportedWithAria2c("--max-host-failures"));
+    }
+    // the logs are written to stderr, so stdout only has the results
+    let json_lines = matches.is_present("json_lines");
+    // the number of comments is optional
+    let with_comments = if matches.is_present("with_comments") {
+        match matches.value_of("with_comments") {
+            Some(n) => Some(n.parse::<u32>()?),
+            None => Some(DEFAULT_COMMENTS),
+        }
+    } else {
+        None
+    };
+    // the checksums are computed from the media as it is downloaded
+    let checksums = matches.value_of("checksums").and_then(ChecksumAlgorithm::from_name);
+    if use_aria2c && checksums.is_some() {
+        return Err(ReddSaverError::ChecksumsWithAria2c);
+    }
+    let aria2c_available = use_aria2c && application_present(String::from("aria2c"));
+    // a uniform format for photo libraries. videos and animated gifs are left as they are
+    let convert_images = matches.value_of("convert_images").and_then(ImageFormat::from_name);
+
+    // process the oldest posts first instead of the newest ones
+    let oldest_first = matches.value_of("order") == Some("oldest");
+    // animated reddit images are also available as mp4, which are much smaller
+    let prefer_mp4 = matches.is_present("prefer_mp4");
+    // keep track of where each of the downloaded media came from
+    let record_source = matches.is_present("record_source");
+    // photo library applications read the description and source of images from XMP sidecars
+    let xmp_sidecar = matches.is_present("xmp_sidecar");
+    // a lightweight preview of each gif for galleries and indexing
+    let with_thumbnails = matches.is_present("with_thumbnails");
+    // skip probing for and combining the audio of reddit videos, which also makes them smaller
+    let no_audio = matches.is_present("no_audio");
+    // for archiving the audio of clips and podcasts, besides the video
+    let mut extract_audio =
+        matches.value_of("extract_audio").map(|f| AudioFormat::from_name(f).unwrap());
+    // reddit keeps a copy of some of the videos linked from other hosts
+    let preview_fallback = matches.is_present("preview_fallback");
+    // a small preview of the post is better than nothing at all
+    let thumbnail_fallback = matches.is_present("thumbnail_fallback");
+    // hand over each downloaded file to other tools, eg: for tagging or indexing
+    let post_download_hook = matches.value_of("post_download_hook");
+
+    // space out the downloads to go easy on the servers
+    let mut throttle = Duration::from_millis(matches.value_of("throttle").unwrap().parse::<u64>()?);
+    // stop cleanly after the time budget so that scheduled runs don't pile up
+    let max_duration = match matches.value_of("max_duration") {
+        Some(minutes) => Some(Duration::from_secs(minutes.parse::<u64>()? * 60)),
+        None => None,
+    };
+    let deadline = max_duration.map(|d| Instant::now() + d);
+    // feedback for unattended runs, where the logs are all there is
+    let stats_interval = match matches.value_of("stats_interval") {
+        Some(seconds) => Some(Duration::from_secs(seconds.parse::<u64>()?.max(1))),
+        None => None,
+    };
+    // reddit is stricter with the rate limits of new accounts, which usually have low karma
+    let min_karma = matches.value_of("min_karma").unwrap().parse::<i64>()?;
+
+    let max_per_subreddit = match matches.value_of("max_per_subreddit") {
+        Some(max) => Some(max.parse::<usize>()?),
+        None => None,
+    };
+
+    // limit the bandwidth used by the downloads. zero is treated as no limit
+    let max_rate = match matches.value_of("max_rate") {
+        Some(rate) => Some(rate.parse::<u64>()?).filter(|rate| *rate > 0),
+        None => None,
+    };
+    if use_aria2c && max_rate.is_some() {
+        return Err(ReddSaverError::UnsupportedWithAria2c("--max-rate"));
+    }
+
+    // fail fast on hosts that are unreachable while still allowing large downloads to complete
+    let timeout = |name| -> Result<Option<Duration>, ReddSaverError> {
+        let seconds = matches.value_of(name).unwrap().parse::<u64>()?;
+        Ok(Some(Duration::from_secs(seconds)).filter(|d| !d.is_zero()))
+    };
+    let connect_timeout = timeout("connect_timeout")?;
+    let read_timeout = timeout("read_timeout")?;
+    // work around networks where the IPv6 routes to the media hosts are broken
+    let ipv4_only = matches.is_present("ipv4_only");
+    // route the downloads over a specific interface, eg: with split tunneling
+    let bind_address = match matches.value_of("bind_address") {
+        Some(address) => Some(
+            address
+                .parse::<IpAddr>()
+                .map_err(|_| ReddSaverError::InvalidBindAddress(String::from(address)))?,
+        ),
+        None => None,
+    };
+    if let Some(address) = bind_address.filter(|a| ipv4_only && a.is_ipv6()) {
+        return Err(ReddSaverError::BindAddressNotIpv4(address.to_string()));
+    }
+    // networks with their own certificates, eg: behind a TLS-intercepting proxy
+    let ca_cert = match matches.value_of("ca_cert") {
+        Some(file_name) => Some(read_ca_cert(file_name)?),
+        None => None,
+    };
+    let insecure = matches.is_present("insecure");
+    let http_client = http_client(
+        connect_timeout,
+        read_timeout,
+        ipv4_only,
+        bind_address,
+        ca_cert.as_ref(),
+        insecure,
+    )?;
+    let api_client = api_client(ca_cert.as_ref(), insecure)?;
+    let startup_retries = matches.value_of("startup_retries").unwrap().parse::<u32>()?;
+    let require_space = match matches.value_of("require_space") {
+        Some(mb) => Some(mb.parse::<u64>()?),
+        None => None,
+    };
+
+    // number of posts in each page of the listing. values above the API maximum are clamped
+    let page_size = matches.value_of("page_size").unwrap().parse::<u32>()?;
+    if page_size == 0 {
+        return Err(InvalidPageSize);
+    }
+    let page_size = page_size.min(MAX_PAGE_SIZE);
+
+    // save a snapshot of the listing for processing with other tools
+    let dump_listing = matches.value_of("dump_listing");
+    // process a snapshot of the listing instead of fetching it from reddit
+    let input_listing = matches.value_of("input_listing");
+    // download a single post instead of a whole listing
+    let post_id = match matches.value_of("url") {
+        Some(url) => Some(post_id_from_url(url).ok_or_else(|| InvalidPostUrl(String::from(url)))?),
+        None => None,
+    };
 
     let undo = matches.is_present("undo");
+    // apps created with a limited set of scopes can still download, only not undo
+    let continue_without_undo = matches.is_present("continue_without_undo");
+    // treat skipped media as failures when deciding the exit status
+    let strict = matches.is_present("strict");
+    // keep the data directory in sync with the listing. files are only deleted when confirmed
+    // and never in a dry run
+    let prune = matches.is_present("prune");
+    let delete_orphans = prune && matches.is_present("yes") && should_download;
+
+    // saved posts are private to each user, so only public listings can be archived for others
+    if target_user.is_some() {
+        if let ListingType::Saved = listing_type {
+            return Err(SavedNotAccessibleForTargetUser);
+        }
+    }
+    if undo && (target_user.is_some() || submitted || matches.is_present("multireddit")) {
+        return Err(UndoNotSupported(listing_type.to_string()));
+    }
 
     // initialize environment from the .env file
     dotenv::from_filename(env_file).ok();
 
     // initialize logger for the app and set logging level to info if no environment variable present
     let env = Env::default().filter("RS_LOG").default_filter_or("info");
-    env_logger::Builder::from_env(env).init();
+    let mut logger = env_logger::Builder::from_env(env);
+    // write one JSON object per line so that the logs can be ingested by log aggregators
+    if matches.value_of("log_format") == Some("json") {
+        logger.format(|buf, record| {
+            let line = json!({
+                "timestamp": Utc::now().to_rfc3339(),
+                "level": record.level().to_string(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    logger.init();
+    for var in unknown_env_vars() {
+        warn!("{} is not the environment variable of any of the arguments, ignoring it", var);
+    }
+
+    // reddit cannot filter the listings by subreddit, so all of the listing is fetched unless it
+    // can be told that none of the rest of its posts are to be downloaded
+    let newest_first = !oldest_first && listing_type.is_newest_first();
+    if subreddits.is_some()
+        && max_per_subreddit.is_none()
+        && !(since.is_some() && newest_first)
+        && input_listing.is_none()
+        && post_id.is_none()
+    {
+        warn!("The posts are filtered by their subreddit after fetching them, so the whole listing is fetched from reddit. Use --max-per-subreddit to stop once enough posts have been downloaded");
+    }
+
+    if insecure {
+        warn!("!!! The certificates are not verified with --insecure. The connections to reddit and the hosts of the media, including the credentials sent to reddit, can be intercepted. Only use this for debugging !!!");
+    }
 
-    let client_id = env::var("CLIENT_ID")?;
-    let client_secret = env::var("CLIENT_SECRET")?;
-    let username = env::var("USERNAME")?;
-    let password = env::var("PASSWORD")?;
+    // all the paths of the media are built from the data directory, so making it absolute
+    // makes the paths in the logs, the failures log and the reports independent of the working directory
+    if matches.is_present("absolute_paths") && check_path_present(&data_directory) {
+        data_directory = dunce::canonicalize(&data_directory)?.to_string_lossy().into_owned();
+    }
+
+    // the videos are combined on the filesystem of the data directory by default, so that they
+    // can be renamed into place. the system temporary directory can be too small for them
+    let temp_dir = String::from(matches.value_of("temp_dir").unwrap_or(&data_directory));
+    if matches.is_present("temp_dir") && !check_path_present(&temp_dir) {
+        return Err(TempDirNotFound(temp_dir));
+    }
+
+    if matches.is_present("validate") {
+        return validate_config(&data_directory, ffmpeg_available, api_client).await;
+    }
+
+    // the manifest already has the resolved media, so there is no need to login
+    if let Some(manifest_file_name) = matches.value_of("from_manifest") {
+        if !check_path_present(&data_directory) {
+            return Err(DataDirNotFound);
+        }
+        check_free_space(&data_directory, require_space)?;
+        let summary = download_from_manifest(
+            &data_directory,
+            manifest_file_name,
+            max_rate,
+            checksums,
+            ffmpeg_available,
+            &temp_dir,
+            &http_client,
+        )
+        .await?;
+        if summary.media_failed > 0 {
+            return Err(MediaDownloadFailed(summary.media_failed));
+        }
+        return Ok(());
+    }
+
+    // retrying the failed downloads does not need the listing, so there is no need to login
+    if matches.is_present("retry_failures") {
+        if !check_path_present(&data_directory) {
+            return Err(DataDirNotFound);
+        }
+        check_free_space(&data_directory, require_space)?;
+        let summary = retry_failures(&data_directory, max_rate, checksums, &http_client).await?;
+        if summary.media_failed > 0 {
+            return Err(MediaDownloadFailed(summary.media_failed));
+        }
+        return Ok(());
+    }
+
+    // the credentials are only needed for fetching the listing from reddit
+    let credential = |name| match env::var(name) {
+        Err(_) if input_listing.is_some() => Ok(String::new()),
+        result => result,
+    };
+    let client_id = credential("CLIENT_ID")?;
+    let client_secret = credential("CLIENT_SECRET")?;
+    let username = credential("USERNAME")?;
+    let password = credential("PASSWORD")?;
     let user_agent = get_user_agent_string(None, None);
 
     if !check_path_present(&data_directory) {
@@ -137,55 +1331,331 @@ async fn main() -> Result<(), ReddSaverError> {
         info!("PASSWORD = {}", mask_sensitive(&password));
         info!("USER_AGENT = {}", &user_agent);
         info!("SUBREDDITS = {}", print_subreddits(&subreddits));
+        info!(
+            "SKIP_SUBREDDITS_FILE = {} ({} subreddits)",
+            matches.value_of("skip_subreddits_file").unwrap_or("<NONE>"),
+            skipped_subreddits.len()
+        );
+        info!(
+            "EXCLUDE_EXTENSIONS = {}",
+            if excluded_extensions.is_empty() {
+                String::from("<NONE>")
+            } else {
+                excluded_extensions.join(",")
+            }
+        );
+        info!("FLAIR = {}", matches.value_of("flair").unwrap_or("<NONE>"));
+        info!("MAX_PER_SUBREDDIT = {}", matches.value_of("max_per_subreddit").unwrap_or("<NONE>"));
+        info!("EXCLUDE_FLAIR = {}", matches.value_of("exclude_flair").unwrap_or("<NONE>"));
+        info!("SINCE = {}", matches.value_of("since").unwrap_or("<NONE>"));
         info!("UPVOTED = {}", upvoted);
+        info!("SUBMITTED = {}", submitted);
+        info!("TARGET_USER = {}", target_user.unwrap_or("<NONE>"));
+        info!("MULTIREDDIT = {}", matches.value_of("multireddit").unwrap_or("<NONE>"));
+        info!("SEGREGATE_BY_LISTING = {}", segregate_by_listing);
+        info!("SEGREGATE_BY_TYPE = {}", segregate_by_type);
+        info!("GALLERY_SUBFOLDER = {}", gallery_subfolder);
+        info!("OUTPUT_TEMPLATE = {}", output_template.as_deref().unwrap_or("<NONE>"));
+        info!("ON_COLLISION = {:?}", on_collision);
+        info!("ASCII_FILENAMES = {}", ascii_filenames);
+        info!("AFTER = {}", after.unwrap_or("<NONE>"));
+        info!("THROTTLE = {}ms", throttle.as_millis());
+        info!(
+            "MAX_DURATION = {}",
+            max_duration.map_or(String::from("<UNLIMITED>"), |d| format!("{}m", d.as_secs() / 60))
+        );
+        info!(
+            "STATS_INTERVAL = {}",
+            stats_interval.map_or(String::from("<NONE>"), |d| format!("{}s", d.as_secs()))
+        );
+        info!("MIN_KARMA = {}", min_karma);
+        info!(
+            "MAX_RATE = {}",
+            max_rate.map_or(String::from("<UNLIMITED>"), |r| format!("{} bytes/s", r))
+        );
+        info!("HOST_STATS = {}", host_stats);
+        info!("MAX_HOST_FAILURES = {}", max_host_failures);
+        info!("JSON_LINES = {}", json_lines);
+        info!(
+            "WITH_COMMENTS = {}",
+            with_comments.map_or(String::from("<NONE>"), |n| n.to_string())
+        );
+        info!("TRACE_POST = {}", matches.value_of("trace_post").unwrap_or("<NONE>"));
+        info!("DEDUPE_REPORT = {}", matches.value_of("dedupe_report").unwrap_or("<NONE>"));
+        info!("MAPPING_FILE = {}", matches.value_of("mapping_file").unwrap_or("<NONE>"));
+        info!("CHECKSUMS = {}", matches.value_of("checksums").unwrap_or("<NONE>"));
+        info!("CONVERT_IMAGES = {}", matches.value_of("convert_images").unwrap_or("<NONE>"));
+        info!("CONNECT_TIMEOUT = {}", format_timeout(connect_timeout));
+        info!("READ_TIMEOUT = {}", format_timeout(read_timeout));
+        info!("IPV4_ONLY = {}", ipv4_only);
+        info!("BIND_ADDRESS = {}", bind_address.map_or(String::from("<NONE>"), |a| a.to_string()));
+        info!("CA_CERT = {}", matches.value_of("ca_cert").unwrap_or("<NONE>"));
+        info!("INSECURE = {}", insecure);
+        info!("STARTUP_RETRIES = {}", startup_retries);
+        info!("REQUIRE_SPACE = {}", matches.value_of("require_space").unwrap_or("<NONE>"));
+        info!("PAGE_SIZE = {}", page_size);
+        info!("DUMP_LISTING = {}", dump_listing.unwrap_or("<NONE>"));
+        info!("INPUT_LISTING = {}", input_listing.unwrap_or("<NONE>"));
+        info!("POST_ID = {}", post_id.as_deref().unwrap_or("<NONE>"));
+        info!("SUMMARY_ONLY = {}", summary_only);
+        info!("REFRESH_METADATA = {}", refresh_metadata);
+        info!("RESOLVE_ONLY = {}", resolve_only.unwrap_or("<NONE>"));
+        info!("ARCHIVE = {}", matches.value_of("archive").unwrap_or("<NONE>"));
+        info!("S3_BUCKET = {}", matches.value_of("s3_bucket").unwrap_or("<NONE>"));
+        info!("NO_EXISTING_CHECK = {}", no_existing_check);
+        info!("S3_ENDPOINT = {}", matches.value_of("s3_endpoint").unwrap_or("<NONE>"));
+        info!("S3_REGION = {}", matches.value_of("s3_region").unwrap_or("<NONE>"));
+        info!("ORDER = {}", matches.value_of("order").unwrap());
+        info!("PREFER_MP4 = {}", prefer_mp4);
+        info!("RECORD_SOURCE = {}", record_source);
+        info!("XMP_SIDECAR = {}", xmp_sidecar);
+        info!("WITH_THUMBNAILS = {}", with_thumbnails);
+        info!("NO_AUDIO = {}", no_audio);
+        info!("EXTRACT_AUDIO = {}", matches.value_of("extract_audio").unwrap_or("<NONE>"));
+        info!("PREVIEW_FALLBACK = {}", preview_fallback);
+        info!("THUMBNAIL_FALLBACK = {}", thumbnail_fallback);
+        info!("POST_DOWNLOAD_HOOK = {}", post_download_hook.unwrap_or("<NONE>"));
         info!("UNDO = {}", undo);
+        info!("CONTINUE_WITHOUT_UNDO = {}", continue_without_undo);
+        info!("STRICT = {}", strict);
+        info!(
+            "PRUNE = {}",
+            if delete_orphans {
+                "delete"
+            } else if prune {
+                "report"
+            } else {
+                "false"
+            }
+        );
         info!("FFMPEG AVAILABLE = {}", ffmpeg_available);
+        info!("TEMP_DIR = {}", &temp_dir);
+        info!("DOWNLOADER = {}", if aria2c_available { "aria2c" } else { "builtin" });
+        info!("Environment variables for the arguments:");
+        for (arg, var) in ARG_ENV_VARS.iter() {
+            info!(
+                "{} = {} ({})",
+                var,
+                env::var(var).unwrap_or_else(|_| String::from("<NONE>")),
+                arg
+            );
+        }
 
         return Ok(());
     }
 
+    if use_aria2c && !aria2c_available {
+        warn!("aria2c is not installed. Falling back to the built-in downloader.");
+    }
+
     if !ffmpeg_available {
         warn!(
             "No ffmpeg Installation available. \
             Videos hosted by Reddit use separate video and audio streams. \
-            Ffmpeg needs be installed to combine the audio and video into a single mp4."
+            Without ffmpeg, only H.264 videos with AAC audio can be combined into a single mp4 \
+            and videos using HLS cannot be downloaded."
         );
+        if extract_audio == Some(AudioFormat::Mp3) {
+            warn!("The audio of reddit videos cannot be transcoded to mp3 without ffmpeg. Saving it as mp4 instead.");
+            extract_audio = Some(AudioFormat::Mp4);
+        }
+    }
+
+    // the saved listing already has all the posts, so there is no need to login
+    let input_pages = match input_listing {
+        Some(file_name) => Some(load_listing(file_name, oldest_first)?),
+        None => None,
+    };
+
+    if should_download {
+        check_free_space(&data_directory, require_space)?;
     }
 
     // login to reddit using the credentials provided and get API bearer token
-    let auth =
-        Client::new(&client_id, &client_secret, &username, &password, &user_agent).login().await?;
-    info!("Successfully logged in to Reddit as {}", username);
-    debug!("Authentication details: {:#?}", auth);
-
-    // get information about the user to display
-    let user = User::new(&auth, &username);
-
-    let user_info = user.about().await?;
-    info!("The user details are: ");
-    info!("Account name: {:#?}", user_info.data.name);
-    info!("Account ID: {:#?}", user_info.data.id);
-    info!("Comment Karma: {:#?}", user_info.data.comment_karma);
-    info!("Link Karma: {:#?}", user_info.data.link_karma);
-
-    info!("Starting data gathering from Reddit. This might take some time. Hold on....");
-    // get the saved/upvoted posts for this particular user
-    let listing = user.listing(listing_type).await?;
-    debug!("Posts: {:#?}", listing);
-
-    let downloader = Downloader::new(
-        &user,
-        &listing,
-        &listing_type,
-        &data_directory,
-        &subreddits,
+    let client =
+        Client::new(&client_id, &client_secret, &username, &password, &user_agent, api_client);
+    let user = if input_pages.is_none() {
+        let auth = retry_startup("log in", startup_retries, || client.login()).await?;
+        info!("Successfully logged in to Reddit as {}", username);
+        debug!("Authentication details: {:#?}", auth);
+
+        // get information about the user to display
+        // the listings are fetched for the target user, if provided, using the logged in user's token
+        // the client is kept around to login again if the access token expires
+        let user = User::new(&client, auth, target_user.unwrap_or(&username));
+
+        let user_info = retry_startup("fetch the user", startup_retries, || user.about()).await?;
+        info!("The user details are: ");
+        info!("Account name: {:#?}", user_info.data.name);
+        info!("Account ID: {:#?}", user_info.data.id);
+        info!("Comment Karma: {:#?}", user_info.data.comment_karma);
+        info!("Link Karma: {:#?}", user_info.data.link_karma);
+
+        // the karma is of the logged in user only if the listings are not fetched for a target user
+        let karma = user_info.data.comment_karma + user_info.data.link_karma;
+        if target_user.is_none() && karma < min_karma && throttle < LOW_KARMA_THROTTLE {
+            warn!(
+                "Account karma {} is below {}. Throttling downloads to one every {}ms to avoid being rate limited",
+                karma,
+                min_karma,
+                LOW_KARMA_THROTTLE.as_millis()
+            );
+            throttle = LOW_KARMA_THROTTLE;
+        }
+        Some(user)
+    } else {
+        None
+    };
+
+    // the media is moved into the archive as soon as it is downloaded
+    let archive = match matches.value_of("archive") {
+        Some(file_name) => Some(Archive::create(file_name)?),
+        None => None,
+    };
+    let storage: Box<dyn StorageBackend> = match matches.value_of("s3_bucket") {
+        Some(bucket) => Box::new(S3Storage::new(
+            bucket,
+            matches.value_of("s3_endpoint"),
+            matches.value_of("s3_region"),
+        )?),
+        None => Box::new(FileSystem::new(&data_directory)),
+    };
+    // checking for each file in turn can be slow on network filesystems
+    let existing_files = if no_existing_check {
+        match list_files(Path::new(&data_directory)) {
+            Ok(files) => {
+                info!("Found {} files in the data directory {}", files.len(), &data_directory);
+                Some(files.into_iter().collect::<HashSet<PathBuf>>())
+            }
+            Err(e) => {
+                warn!("Could not list the data directory, checking for each file instead: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let (events, events_printer) = if print_events {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Some(sender), Some(tokio::spawn(print_download_events(receiver))))
+    } else {
+        (None, None)
+    };
+    let options = DownloadOptions {
+        user: user.as_ref(),
+        listing_type: &listing_type,
+        data_directory: &data_directory,
+        subreddits: &subreddits,
+        skipped_subreddits,
+        excluded_extensions,
+        flair: matches.value_of("flair"),
+        excluded_flair: matches.value_of("exclude_flair"),
+        since,
+        newest_first,
+        max_per_subreddit,
         should_download,
+        print_urls,
+        summary_only,
+        refresh_metadata,
+        resolve_only,
         use_human_readable,
+        ascii_filenames,
+        segregate_by_listing,
+        segregate_by_type,
+        gallery_subfolder,
+        output_template: output_template.as_deref(),
+        on_collision,
         undo,
+        continue_without_undo,
         ffmpeg_available,
-    );
+        temp_dir: &temp_dir,
+        prefer_mp4,
+        with_thumbnails,
+        no_audio,
+        extract_audio,
+        preview_fallback,
+        thumbnail_fallback,
+        use_aria2c: aria2c_available,
+        record_source,
+        xmp_sidecar,
+        post_download_hook,
+        throttle,
+        max_rate,
+        checksums,
+        convert_images,
+        deadline,
+        stats_interval,
+        prune,
+        delete_orphans,
+        dedupe_report: matches.value_of("dedupe_report"),
+        mapping_file: matches.value_of("mapping_file"),
+        host_stats,
+        max_host_failures,
+        json_lines,
+        with_comments,
+        trace_post: matches.value_of("trace_post"),
+        events,
+    };
+    let downloader = Downloader::new(options, http_client, archive, storage, existing_files);
+
+    // get the saved/upvoted posts for this particular user
+    // each page of the listing is sent to the downloader as soon as it is fetched
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let summary = match &user {
+        None => {
+            // the downloader stops once all the pages of the saved listing are processed
+            for page in input_pages.unwrap_or_default() {
+                sender.send(page).ok();
+            }
+            drop(sender);
+            downloader.run(receiver).await?
+        }
+        Some(user) if post_id.is_some() => {
+            let post_id = post_id.as_deref().unwrap();
+            let post = user.post(post_id).await?;
+            if post.data.children.is_empty() {
+                warn!("Could not find the post {}", post_id);
+            }
+            sender.send(post).ok();
+            drop(sender);
+            downloader.run(receiver).await?
+        }
+        Some(user) if undo => {
+            info!("Starting data gathering from Reddit. This might take some time. Hold on....");
+            // unsaving or removing the upvote for a post while the listing is still being fetched
+            // can invalidate the pagination cursor, so the whole listing is fetched up front
+            user.listing(listing_type, after, dump_listing, page_size, oldest_first, sender)
+                .await?;
+            downloader.run(receiver).await?
+        }
+        Some(user) => {
+            info!("Starting data gathering from Reddit. This might take some time. Hold on....");
+            try_join!(
+                user.listing(listing_type, after, dump_listing, page_size, oldest_first, sender),
+                downloader.run(receiver)
+            )?
+            .1
+        }
+    };
+    // the downloader has closed the channel once it is done, so all the events are printed
+    if let Some(events_printer) = events_printer {
+        events_printer.await.ok();
+    }
 
-    downloader.run().await?;
+    // exit with a non-zero status so that scripts can tell if any of the media was not saved.
+    // in a dry run, all the media is reported as skipped so only failures are considered
+    let mut unsuccessful = summary.media_failed;
+    if strict && should_download {
+        unsuccessful += summary.media_skipped;
+    }
+    if unsuccessful > 0 {
+        return Err(MediaDownloadFailed(unsuccessful));
+    }
+    // the posts which could not be undone are still in the listing, which has to be known
+    if summary.posts_undo_failed > 0 {
+        return Err(ReddSaverError::UndoIncomplete(summary.posts_undo_failed));
+    }
 
     Ok(())
 }
@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use mp4::{
+    AacConfig, AvcConfig, MediaConfig, Mp4Config, Mp4Reader, Mp4Writer, TrackConfig, TrackType,
+};
+
+use crate::errors::ReddSaverError;
+
+/// Combine the video track of one mp4 file and the audio track of another into a single mp4
+/// file without transcoding. This is used in place of ffmpeg when it is not installed and only
+/// supports H.264 video and AAC audio, which is what reddit videos use
+pub fn remux(
+    video_file_name: &str,
+    audio_file_name: &str,
+    output_file_name: &str,
+) -> Result<(), ReddSaverError> {
+    let mut video = open(video_file_name)?;
+    let mut audio = open(audio_file_name)?;
+
+    let config = Mp4Config {
+        major_brand: *video.major_brand(),
+        minor_version: video.minor_version(),
+        compatible_brands: video.compatible_brands().to_vec(),
+        timescale: video.timescale(),
+    };
+    let mut writer = Mp4Writer::write_start(File::create(output_file_name)?, &config)?;
+
+    // the tracks are numbered from 1 in the order they are added to the output
+    copy_track(&mut video, TrackType::Video, &mut writer, 1)?;
+    copy_track(&mut audio, TrackType::Audio, &mut writer, 2)?;
+    writer.write_end()?;
+
+    Ok(())
+}
+
+fn open(file_name: &str) -> Result<Mp4Reader<BufReader<File>>, ReddSaverError> {
+    let file = File::open(file_name)?;
+    let size = file.metadata()?.len();
+    Ok(Mp4Reader::read_header(BufReader::new(file), size)?)
+}
+
+/// Copy all the samples of the first track of the given type to the output
+fn copy_track(
+    reader: &mut Mp4Reader<BufReader<File>>,
+    track_type: TrackType,
+    writer: &mut Mp4Writer<File>,
+    output_track_id: u32,
+) -> Result<(), ReddSaverError> {
+    let track = reader
+        .tracks()
+        .values()
+        .find(|t| t.track_type().ok() == Some(track_type))
+        .ok_or(mp4::Error::InvalidData("no track of the required type"))?;
+
+    let media_conf = match track.media_type()? {
+        mp4::MediaType::H264 => MediaConfig::AvcConfig(AvcConfig {
+            width: track.width(),
+            height: track.height(),
+            seq_param_set: track.sequence_parameter_set()?.to_vec(),
+            pic_param_set: track.picture_parameter_set()?.to_vec(),
+        }),
+        mp4::MediaType::AAC => MediaConfig::AacConfig(AacConfig {
+            bitrate: track.bitrate(),
+            profile: track.audio_profile()?,
+            freq_index: track.sample_freq_index()?,
+            chan_conf: track.channel_config()?,
+        }),
+        _ => return Err(mp4::Error::InvalidData("only H.264 video and AAC audio are supported"))?,
+    };
+    let config = TrackConfig {
+        track_type,
+        timescale: track.timescale(),
+        language: String::from(track.language()),
+        media_conf,
+    };
+    let input_track_id = track.track_id();
+    writer.add_track(&config)?;
+
+    for sample_id in 1..=reader.sample_count(input_track_id)? {
+        if let Some(sample) = reader.read_sample(input_track_id, sample_id)? {
+            writer.write_sample(output_track_id, &sample)?;
+        }
+    }
+
+    Ok(())
+}
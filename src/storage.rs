@@ -0,0 +1,137 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use log::{debug, warn};
+use rusoto_core::{ByteStream, Region, RusotoError};
+use rusoto_s3::{HeadObjectError, HeadObjectRequest, PutObjectRequest, S3Client, S3};
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use crate::errors::ReddSaverError;
+use crate::utils::check_path_present;
+
+/// Region of the S3-compatible storage when only an endpoint is given
+static DEFAULT_S3_REGION: &str = "us-east-1";
+
+/// Where the downloaded media ends up. The media is always downloaded into the data directory
+/// first, since reddit videos have to be combined and the sidecars are written next to it, and
+/// is then handed over to the backend. The paths are relative to the data directory
+#[async_trait]
+pub trait StorageBackend: fmt::Debug + Send + Sync {
+    /// Whether the media has already been stored at the path
+    async fn exists(&self, path: &str) -> bool;
+
+    /// Store the media downloaded to the file at the path
+    async fn put(&self, path: &str, file_name: &str) -> Result<(), ReddSaverError>;
+}
+
+/// Keeps the media in the data directory, which is where it has been downloaded to
+#[derive(Debug)]
+pub struct FileSystem<'a> {
+    data_directory: &'a str,
+}
+
+impl<'a> FileSystem<'a> {
+    pub fn new(data_directory: &'a str) -> FileSystem<'a> {
+        FileSystem { data_directory }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for FileSystem<'_> {
+    async fn exists(&self, path: &str) -> bool {
+        check_path_present(&Path::new(self.data_directory).join(path).to_string_lossy())
+    }
+
+    async fn put(&self, _path: &str, _file_name: &str) -> Result<(), ReddSaverError> {
+        Ok(())
+    }
+}
+
+/// Uploads the media to a bucket of S3 or an S3-compatible storage, such as MinIO. The
+/// credentials are read from the standard AWS environment variables or profile
+pub struct S3Storage {
+    client: S3Client,
+    bucket: String,
+}
+
+impl fmt::Debug for S3Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Storage").field("bucket", &self.bucket).finish()
+    }
+}
+
+impl S3Storage {
+    pub fn new(
+        bucket: &str,
+        endpoint: Option<&str>,
+        region: Option<&str>,
+    ) -> Result<S3Storage, ReddSaverError> {
+        let region = match (endpoint, region) {
+            (Some(endpoint), region) => Region::Custom {
+                name: String::from(region.unwrap_or(DEFAULT_S3_REGION)),
+                endpoint: String::from(endpoint),
+            },
+            (None, Some(region)) => Region::from_str(region)
+                .map_err(|_| ReddSaverError::InvalidS3Region(String::from(region)))?,
+            (None, None) => Region::default(),
+        };
+
+        Ok(S3Storage { client: S3Client::new(region), bucket: String::from(bucket) })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn exists(&self, path: &str) -> bool {
+        let request = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: object_key(path),
+            ..Default::default()
+        };
+        match self.client.head_object(request).await {
+            Ok(_) => true,
+            // the response to a HEAD request has no body, so a missing object is usually only
+            // told apart by its status
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => false,
+            Err(RusotoError::Unknown(response)) if response.status.as_u16() == 404 => false,
+            Err(e) => {
+                // eg. with the wrong credentials every object would be uploaded again, so the
+                // media is left to be checked in the next run instead
+                warn!("Could not check if media {} is in the bucket {}: {}", path, self.bucket, e);
+                true
+            }
+        }
+    }
+
+    /// The file is uploaded as it is read and removed once the upload has completed
+    async fn put(&self, path: &str, file_name: &str) -> Result<(), ReddSaverError> {
+        let file = tokio::fs::File::open(file_name).await?;
+        let size = file.metadata().await?.len() as usize;
+        let body = FramedRead::new(file, BytesCodec::new()).map_ok(|b| b.freeze());
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: object_key(path),
+            content_length: Some(size as i64),
+            body: Some(ByteStream::new_with_size(body, size)),
+            ..Default::default()
+        };
+        self.client
+            .put_object(request)
+            .await
+            .map_err(|e| ReddSaverError::S3Error(path.to_string(), e.to_string()))?;
+
+        debug!("Uploaded {} to the bucket {}", file_name, self.bucket);
+        fs::remove_file(file_name)?;
+
+        Ok(())
+    }
+}
+
+/// Keys of the objects always use forward slashes, whatever the platform
+fn object_key(path: &str) -> String {
+    path.replace('\\', "/")
+}
@@ -1,6 +1,8 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::ops::Add;
+use std::time::Duration;
 
 /// Data structure that represents a user's info
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,7 +43,7 @@ pub struct UserAbout {
     pub data: AboutData,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Listing {
     /// The kind of object this is. eg: Comment, Account, Subreddit, etc.
     pub kind: String,
@@ -51,7 +53,7 @@ pub struct Listing {
 }
 
 /// The contents of a call to a 'listing' endpoint.
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ListingData {
     /// A modhash (essentially a CSRF token) generated for this request. This is generally
     /// not required for any use-case, but is provided nevertheless.
@@ -62,7 +64,7 @@ pub struct ListingData {
     pub dist: i32,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Post {
     /// The kind of object this is. eg: Comment, Account, Subreddit, etc.
     pub kind: String,
@@ -71,7 +73,7 @@ pub struct Post {
 }
 
 /// Represents all types of link posts and self posts on Reddit.
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PostData {
     pub subreddit: String,
     /// The ID of the post in base-36 form, as used in Reddit's links.
@@ -105,34 +107,94 @@ pub struct PostData {
     pub url: Option<String>,
     /// The title of the post.
     pub title: Option<String>,
+    /// The text of the post, if this is a self post.
+    pub selftext: Option<String>,
     /// A timestamp of the time when the post was created, in **UTC**.
     pub created_utc: Value,
+    /// The text of the flair of the post, if it has one
+    pub link_flair_text: Option<String>,
     /// Gallery metadata
     pub gallery_data: Option<GalleryItems>,
+    /// Metadata of the images in the post, such as the images of a gallery, keyed by the media id
+    pub media_metadata: Option<HashMap<String, MediaMetadata>>,
     /// Is post a video?
     pub is_video: Option<bool>,
     /// Reddit Media info
     pub media: Option<PostMedia>,
+    /// Previews of the linked media generated by reddit
+    pub preview: Option<Preview>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Preview {
+    pub images: Vec<PreviewImage>,
+    /// Video hosted by reddit for the media of posts linking to external video hosts
+    pub reddit_video_preview: Option<RedditVideo>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreviewImage {
+    pub source: PreviewSource,
+    /// Other formats of the image, available for animated images
+    pub variants: Option<PreviewVariants>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreviewVariants {
+    /// The animated image encoded as an mp4 video
+    pub mp4: Option<PreviewVariant>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreviewVariant {
+    pub source: PreviewSource,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PreviewSource {
+    /// URL of the preview, with the ampersands HTML escaped
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PostMedia {
     pub reddit_video: Option<RedditVideo>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RedditVideo {
     pub fallback_url: String,
+    /// HLS playlist for the video, which contains both the video and the audio
+    pub hls_url: Option<String>,
+    #[serde(default)]
     pub is_gif: bool,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GalleryItems {
     /// Representation containing a list of gallery items
     pub items: Vec<GalleryItem>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MediaMetadata {
+    /// Processing status of the media, eg: valid, failed
+    pub status: Option<String>,
+    /// Mime type of the media, eg: image/png
+    pub m: Option<String>,
+    /// Source of the media at its original size
+    pub s: Option<MediaMetadataSource>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MediaMetadataSource {
+    /// URL of the image, with the ampersands HTML escaped
+    pub u: Option<String>,
+    /// URL of the animated image, with the ampersands HTML escaped
+    pub gif: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GalleryItem {
     /// The reddit media id, can be used to construct a redd.it URL
     pub media_id: String,
@@ -146,15 +208,204 @@ pub struct GfyData {
     pub gfy_item: GfyItem,
 }
 
+/// The Gfycat/Redgifs API can respond with a 200 OK status even when the media is not available,
+/// in which case the body of the response contains an error instead of the media information
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum GfyResponse {
+    Media(GfyData),
+    Error(GfyError),
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GfyError {
+    /// Error returned by the API, either a plain message or an object
+    pub error: Option<Value>,
+    /// Error returned by the API, usually an object containing a code and a description
+    #[serde(rename = "errorMessage")]
+    pub error_message: Option<Value>,
+}
+
+impl GfyError {
+    /// Human readable description of the error returned by the API
+    pub fn description(&self) -> String {
+        match self.error_message.as_ref().or(self.error.as_ref()) {
+            Some(Value::String(message)) => message.clone(),
+            Some(value) => match value.get("description").or_else(|| value.get("message")) {
+                Some(Value::String(message)) => message.clone(),
+                _ => value.to_string(),
+            },
+            None => String::from("unknown error"),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct GfyItem {
     #[serde(rename = "gifUrl")]
     pub gif_url: String,
     #[serde(rename = "mp4Url")]
     pub mp4_url: String,
+    /// Still image of the first frame of the gif
+    #[serde(rename = "posterUrl")]
+    pub poster_url: Option<String>,
+}
+
+/// Temporary token needed to use the redgifs v2 API
+#[derive(Deserialize, Debug, Clone)]
+pub struct RedgifsToken {
+    pub token: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RedgifsResponse {
+    pub gif: RedgifsGif,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+/// A gif or an image of redgifs
+#[derive(Deserialize, Debug, Clone)]
+pub struct RedgifsGif {
+    pub urls: RedgifsUrls,
+    /// ID of the gallery the gif is part of
+    pub gallery: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RedgifsUrls {
+    pub hd: Option<String>,
+    pub sd: Option<String>,
+    /// Still image of the first frame of the gif
+    pub poster: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RedgifsGallery {
+    pub gifs: Vec<RedgifsGif>,
+}
+
+/// Downloads from one host, used to report the throughput of each host
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct HostStats {
+    /// Number of media downloaded from the host
+    pub downloads: i32,
+    /// Total size of the media downloaded from the host
+    pub bytes: u64,
+    /// Total time spent downloading the media, including waiting for the host to respond
+    pub duration: Duration,
+}
+
+impl Add for HostStats {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            downloads: self.downloads + rhs.downloads,
+            bytes: self.bytes + rhs.bytes,
+            duration: self.duration + rhs.duration,
+        }
+    }
+}
+
+/// A media that could not be downloaded, recorded so that it can be retried later
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailedDownload {
+    /// The URL the media is located at
+    pub url: String,
+    /// The path the media should be saved to
+    pub file_name: String,
+}
+
+/// The comments of a post, as returned by the comments endpoint after the listing of the post
+#[derive(Deserialize, Debug, Clone)]
+pub struct CommentListing {
+    pub data: CommentListingData,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CommentListingData {
+    pub children: Vec<CommentChild>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CommentChild {
+    /// Either t1 for a comment or more for a placeholder of the comments that were not sent
+    pub kind: String,
+    pub data: CommentData,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CommentData {
+    /// The username of the author, unless the comment has been deleted
+    pub author: Option<String>,
+    /// The text of the comment in Markdown
+    pub body: Option<String>,
+    pub score: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_replies")]
+    pub replies: Option<Box<CommentListing>>,
+}
+
+/// Reddit sends an empty string instead of a listing for the comments without replies
+fn deserialize_replies<'de, D>(deserializer: D) -> Result<Option<Box<CommentListing>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(serde_json::from_value(Value::deserialize(deserializer)?).ok())
+}
+
+/// Result of processing a single media, written to stdout as a JSON line as soon as it is known
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MediaResult {
+    /// The ID of the post the media belongs to, eg: k1ng2h
+    pub post_id: String,
+    pub subreddit: String,
+    /// The URL the media is located at
+    pub url: String,
+    /// The path the media was saved to
+    pub path: String,
+    /// One of downloaded, skipped, failed or removed
+    pub status: String,
+    /// Size of the saved media, if it is present on disk
+    pub bytes: Option<u64>,
+}
+
+/// Media of posts that have the same content, written to the dedupe report
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DuplicateGroup {
+    /// MD5 hash of the content of the media
+    pub hash: String,
+    pub media: Vec<DuplicateMedia>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DuplicateMedia {
+    /// The ID of the post in base-36 form
+    pub id: String,
+    pub permalink: String,
+    pub file_name: String,
+}
+
+/// A post with its resolved media, saved to the manifest so that it can be downloaded later
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    /// The full name of the post, eg: t3_k1ng2h
+    pub name: String,
+    /// The media of the post, eg: the images of a gallery or the video and audio of a reddit video
+    pub components: Vec<ManifestComponent>,
+    /// The path the components should be combined into, for reddit videos with audio
+    pub combined_file_name: Option<String>,
+    /// If the components are HLS playlists, which have to be remuxed using ffmpeg
+    pub hls: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestComponent {
+    /// The URL the media is located at
+    pub url: String,
+    /// The path the media should be saved to
+    pub file_name: String,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
 pub struct Summary {
     /// Number of media downloaded
     pub media_downloaded: i32,
@@ -162,6 +413,40 @@ pub struct Summary {
     pub media_skipped: i32,
     /// Number of media supported present and parsable
     pub media_supported: i32,
+    /// Number of media that could not be downloaded
+    pub media_failed: i32,
+    /// Number of media that was removed from the host
+    pub media_removed: i32,
+    /// Number of posts filtered out before processing
+    pub posts_filtered: i32,
+    /// Number of posts skipped because their content was removed or deleted
+    pub posts_removed: i32,
+    /// Number of posts skipped because they link to hosts which have shut down
+    pub posts_dead_host: i32,
+    /// Number of posts skipped because they only have a placeholder instead of a URL
+    pub posts_no_media: i32,
+    /// Number of posts skipped because their subreddit is in the skip list
+    pub posts_skipped_subreddit: i32,
+    /// Number of posts skipped because of their flair
+    pub posts_skipped_flair: i32,
+    /// Number of posts skipped because they were created before the age limit
+    pub posts_too_old: i32,
+    /// Number of posts skipped because their subreddit reached the maximum posts per subreddit
+    pub posts_capped: i32,
+    /// Number of media skipped because of their extension
+    pub media_excluded: i32,
+    /// Number of media already present on disk, only counted when not downloading
+    pub media_present: i32,
+    /// Number of thumbnails of gfycat/redgifs gifs downloaded
+    pub thumbnails_downloaded: i32,
+    /// Number of posts whose thumbnail was downloaded since none of their media could be
+    pub posts_thumbnail_only: i32,
+    /// Number of posts that were unsaved or had their upvote removed
+    pub posts_undone: i32,
+    /// Number of posts that could not be unsaved or have their upvote removed
+    pub posts_undo_failed: i32,
+    /// Number of metadata sidecars rewritten for media already present on disk
+    pub sidecars_refreshed: i32,
 }
 
 impl Add for Summary {
@@ -172,6 +457,85 @@ impl Add for Summary {
             media_supported: self.media_supported + rhs.media_supported,
             media_downloaded: self.media_downloaded + rhs.media_downloaded,
             media_skipped: self.media_skipped + rhs.media_skipped,
+            media_failed: self.media_failed + rhs.media_failed,
+            media_removed: self.media_removed + rhs.media_removed,
+            posts_filtered: self.posts_filtered + rhs.posts_filtered,
+            posts_removed: self.posts_removed + rhs.posts_removed,
+            posts_dead_host: self.posts_dead_host + rhs.posts_dead_host,
+            posts_no_media: self.posts_no_media + rhs.posts_no_media,
+            posts_skipped_subreddit: self.posts_skipped_subreddit + rhs.posts_skipped_subreddit,
+            posts_skipped_flair: self.posts_skipped_flair + rhs.posts_skipped_flair,
+            posts_too_old: self.posts_too_old + rhs.posts_too_old,
+            posts_capped: self.posts_capped + rhs.posts_capped,
+            media_excluded: self.media_excluded + rhs.media_excluded,
+            media_present: self.media_present + rhs.media_present,
+            thumbnails_downloaded: self.thumbnails_downloaded + rhs.thumbnails_downloaded,
+            posts_thumbnail_only: self.posts_thumbnail_only + rhs.posts_thumbnail_only,
+            posts_undone: self.posts_undone + rhs.posts_undone,
+            posts_undo_failed: self.posts_undo_failed + rhs.posts_undo_failed,
+            sidecars_refreshed: self.sidecars_refreshed + rhs.sidecars_refreshed,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every field is given a distinct value, so that a field added from the wrong side or not
+    /// at all is caught. Listing all the fields also fails the build when a field is added
+    fn summary(base: i32) -> Summary {
+        Summary {
+            media_downloaded: base + 1,
+            media_skipped: base + 2,
+            media_supported: base + 3,
+            media_failed: base + 4,
+            media_removed: base + 5,
+            posts_filtered: base + 6,
+            posts_removed: base + 7,
+            posts_dead_host: base + 8,
+            posts_no_media: base + 9,
+            posts_skipped_subreddit: base + 10,
+            posts_skipped_flair: base + 11,
+            posts_too_old: base + 12,
+            posts_capped: base + 13,
+            media_excluded: base + 14,
+            media_present: base + 15,
+            thumbnails_downloaded: base + 16,
+            posts_thumbnail_only: base + 17,
+            posts_undone: base + 18,
+            posts_undo_failed: base + 19,
+            sidecars_refreshed: base + 20,
+        }
+    }
+
+    #[test]
+    fn add_sums_every_field() {
+        let sum = summary(0) + summary(100);
+        assert_eq!(sum.media_downloaded, 102);
+        assert_eq!(sum.media_skipped, 104);
+        assert_eq!(sum.media_supported, 106);
+        assert_eq!(sum.media_failed, 108);
+        assert_eq!(sum.media_removed, 110);
+        assert_eq!(sum.posts_filtered, 112);
+        assert_eq!(sum.posts_removed, 114);
+        assert_eq!(sum.posts_dead_host, 116);
+        assert_eq!(sum.posts_no_media, 118);
+        assert_eq!(sum.posts_skipped_subreddit, 120);
+        assert_eq!(sum.posts_skipped_flair, 122);
+        assert_eq!(sum.posts_too_old, 124);
+        assert_eq!(sum.posts_capped, 126);
+        assert_eq!(sum.media_excluded, 128);
+        assert_eq!(sum.media_present, 130);
+        assert_eq!(sum.thumbnails_downloaded, 132);
+        assert_eq!(sum.posts_thumbnail_only, 134);
+        assert_eq!(sum.posts_undone, 136);
+        assert_eq!(sum.posts_undo_failed, 138);
+        assert_eq!(sum.sidecars_refreshed, 140);
+    }
+
+    #[test]
+    fn add_default_is_identity() {
+        assert_eq!(summary(0) + Summary::default(), summary(0));
+    }
+}
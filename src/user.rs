@@ -1,18 +1,33 @@
-use crate::auth::Auth;
+use crate::auth::{Auth, Client};
 use crate::errors::ReddSaverError;
-use crate::structures::{Listing, UserAbout};
+use crate::structures::{CommentListing, Listing, UserAbout};
 use crate::utils::get_user_agent_string;
-use log::{debug, info};
-use reqwest::header::USER_AGENT;
+use log::{debug, info, warn};
+use reqwest::header::{USER_AGENT, WWW_AUTHENTICATE};
+use reqwest::{RequestBuilder, Response, StatusCode};
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::fs;
+use std::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::delay_for;
+
+/// Maximum number of items returned by the API in a single request
+pub static MAX_PAGE_SIZE: u32 = 100;
+/// Number of times the comments of a post are requested while reddit is rate limiting
+static COMMENTS_ATTEMPTS: u32 = 3;
+/// Longest time to wait for the rate limit of the API to be reset
+static MAX_RATE_LIMIT_DELAY_SECS: u64 = 60;
 
 #[derive(Debug)]
 pub struct User<'a> {
+    /// Client used to login again when the access token expires
+    client: &'a Client<'a>,
     /// Contains authentication information about the user
-    auth: &'a Auth,
+    auth: RwLock<Auth>,
     /// Username of the user who authorized the application
     name: &'a str,
 }
@@ -21,34 +36,87 @@ pub struct User<'a> {
 pub enum ListingType {
     Saved,
     Upvoted,
+    Submitted,
+    /// The newest posts of a multireddit, optionally owned by another user
+    Multireddit {
+        owner: Option<String>,
+        name: String,
+    },
+}
+
+impl ListingType {
+    /// Whether reddit lists the posts in the order they were created, newest first. The saved
+    /// and upvoted posts are in the order they were saved or upvoted instead
+    pub fn is_newest_first(&self) -> bool {
+        matches!(self, ListingType::Submitted | ListingType::Multireddit { .. })
+    }
+
+    /// Path of the listing in the API, relative to the user the listing is fetched for
+    fn path(&self, user: &str) -> String {
+        match self {
+            ListingType::Multireddit { owner, name } => {
+                format!("user/{}/m/{}/new", owner.as_deref().unwrap_or(user), name)
+            }
+            _ => format!("user/{}/{}", user, self),
+        }
+    }
 }
 
 impl Display for ListingType {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match *self {
+        match self {
             ListingType::Saved => write!(f, "saved"),
             ListingType::Upvoted => write!(f, "upvoted"),
+            ListingType::Submitted => write!(f, "submitted"),
+            ListingType::Multireddit { name, .. } => write!(f, "m/{}", name),
         }
     }
 }
 
 impl<'a> User<'a> {
-    pub fn new(auth: &'a Auth, name: &'a str) -> Self {
-        User { auth, name }
+    pub fn new(client: &'a Client<'a>, auth: Auth, name: &'a str) -> Self {
+        User { client, auth: RwLock::new(auth), name }
+    }
+
+    /// Send the request built with the current access token. If Reddit rejects the token,
+    /// which happens when it expires during long runs, login again and retry the request once
+    async fn send_authorized<F>(&self, request: F) -> Result<Response, ReddSaverError>
+    where
+        F: Fn(&str) -> RequestBuilder,
+    {
+        let access_token = self.auth.read().unwrap().access_token.clone();
+        let response = request(&access_token).send().await?;
+        if response.status() != StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+
+        warn!("Access token was rejected by Reddit. Logging in again...");
+        let auth = self.client.login().await?;
+        let access_token = auth.access_token.clone();
+        *self.auth.write().unwrap() = auth;
+
+        let response = request(&access_token).send().await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(ReddSaverError::AuthenticationFailed);
+        }
+        Ok(response)
     }
 
     pub async fn about(&self) -> Result<UserAbout, ReddSaverError> {
         // all API requests that use a bearer token should be made to oauth.reddit.com instead
         let url = format!("https://oauth.reddit.com/user/{}/about", self.name);
-        let client = reqwest::Client::new();
-
-        let response = client
-            .get(&url)
-            .bearer_auth(&self.auth.access_token)
-            // reddit will forbid you from accessing the API if the provided user agent is not unique
-            .header(USER_AGENT, get_user_agent_string(None, None))
-            .send()
+        let client = self.client.http_client();
+
+        let response = self
+            .send_authorized(|access_token| {
+                client
+                    .get(&url)
+                    .bearer_auth(access_token)
+                    // reddit will forbid you from accessing the API if the provided user agent is not unique
+                    .header(USER_AGENT, get_user_agent_string(None, None))
+            })
             .await?
+            .error_for_status()?
             .json::<UserAbout>()
             .await?;
 
@@ -57,38 +125,118 @@ impl<'a> User<'a> {
         Ok(response)
     }
 
+    /// Fetch the top comments of a post, with the replies to them up to the given depth.
+    /// When reddit is rate limiting the requests, wait for the limit to be reset and try again
+    pub async fn comments(
+        &self,
+        id: &str,
+        limit: u32,
+        depth: u32,
+    ) -> Result<CommentListing, ReddSaverError> {
+        let url = format!(
+            "https://oauth.reddit.com/comments/{}?limit={}&depth={}&sort=top",
+            id, limit, depth
+        );
+        let client = self.client.http_client();
+
+        let mut attempt = 1;
+        let response = loop {
+            let response = self
+                .send_authorized(|access_token| {
+                    client
+                        .get(&url)
+                        .bearer_auth(access_token)
+                        .header(USER_AGENT, get_user_agent_string(None, None))
+                })
+                .await?;
+            if response.status() != StatusCode::TOO_MANY_REQUESTS || attempt >= COMMENTS_ATTEMPTS {
+                break response.error_for_status()?;
+            }
+
+            // the reset header is the number of seconds until the rate limit is reset
+            let seconds = response
+                .headers()
+                .get("x-ratelimit-reset")
+                .and_then(|r| r.to_str().ok())
+                .and_then(|r| r.trim().parse::<f64>().ok())
+                .map_or(MAX_RATE_LIMIT_DELAY_SECS, |r| r.ceil() as u64);
+            let delay = Duration::from_secs(seconds.min(MAX_RATE_LIMIT_DELAY_SECS));
+            warn!("Reddit is rate limiting the requests. Retrying in {}s", delay.as_secs());
+            delay_for(delay).await;
+            attempt += 1;
+        };
+
+        // the response is the listing of the post followed by the listing of its comments
+        let (_, comments) = response.json::<(serde::de::IgnoredAny, CommentListing)>().await?;
+
+        debug!("Comments Response: {:#?}", comments);
+
+        Ok(comments)
+    }
+
+    /// Fetch a single post as a listing with only that post, so that it can be downloaded
+    /// like any other listing
+    pub async fn post(&self, id: &str) -> Result<Listing, ReddSaverError> {
+        let url = format!("https://oauth.reddit.com/by_id/t3_{}", id);
+        let client = self.client.http_client();
+
+        let response = self
+            .send_authorized(|access_token| {
+                client
+                    .get(&url)
+                    .bearer_auth(access_token)
+                    .header(USER_AGENT, get_user_agent_string(None, None))
+            })
+            .await?
+            .json::<Listing>()
+            .await?;
+
+        debug!("Post Response: {:#?}", response);
+
+        Ok(response)
+    }
+
+    /// Fetch the listing page by page, sending each page to the downloader as soon as it is
+    /// available so that downloading can begin before the whole listing has been fetched.
+    /// Optionally, the complete listing is also saved as JSON to the given file.
+    /// Reddit returns the newest posts first, to process the oldest posts first the whole
+    /// listing has to be fetched before it can be sent to the downloader in reverse
     pub async fn listing(
         &self,
         listing_type: &ListingType,
-    ) -> Result<Vec<Listing>, ReddSaverError> {
-        let client = reqwest::Client::new();
+        start_after: Option<&str>,
+        dump_listing: Option<&str>,
+        page_size: u32,
+        oldest_first: bool,
+        sender: UnboundedSender<Listing>,
+    ) -> Result<(), ReddSaverError> {
+        let client = self.client.http_client();
+        let mut dump: Option<Vec<Listing>> = dump_listing.map(|_| Vec::new());
+        let mut held: Vec<Listing> = Vec::new();
 
         let mut complete = false;
         let mut processed = 0;
-        let mut after: Option<String> = None;
-        let mut listing: Vec<Listing> = Vec::new();
+        // if a starting cursor is provided, the listing is fetched from the page after that item
+        let mut after: Option<String> = start_after.map(String::from);
         while !complete {
             // during the first call to the API, we would not provide the after query parameter
             // in subsequent calls, we use the value for after from the response of the
             //  previous request and continue doing so till the value of after is null
-            let url = if processed == 0 {
-                format!("https://oauth.reddit.com/user/{}/{}", self.name, listing_type.to_string())
+            let path = listing_type.path(self.name);
+            let url = if let Some(a) = after.as_ref() {
+                format!("https://oauth.reddit.com/{}?after={}", path, a)
             } else {
-                format!(
-                    "https://oauth.reddit.com/user/{}/{}?after={}",
-                    self.name,
-                    listing_type.to_string(),
-                    after.as_ref().unwrap()
-                )
+                format!("https://oauth.reddit.com/{}", path)
             };
 
-            let response = client
-                .get(&url)
-                .bearer_auth(&self.auth.access_token)
-                .header(USER_AGENT, get_user_agent_string(None, None))
-                // the maximum number of items returned by the API in a single request is 100
-                .query(&[("limit", 100)])
-                .send()
+            let response = self
+                .send_authorized(|access_token| {
+                    client
+                        .get(&url)
+                        .bearer_auth(access_token)
+                        .header(USER_AGENT, get_user_agent_string(None, None))
+                        .query(&[("limit", page_size)])
+                })
                 .await?
                 .json::<Listing>()
                 .await?;
@@ -99,47 +247,115 @@ impl<'a> User<'a> {
             processed += response.borrow().data.dist;
             info!("Number of items processed : {}", processed);
 
-            // if there is a response, continue collecting them into a vector
+            debug!("Posts: {:#?}", response);
+
+            // if there is a response, continue sending them to the downloader
             if response.borrow().data.after.as_ref().is_none() {
                 info!("Data gathering complete. Yay.");
-                listing.push(response);
                 complete = true;
             } else {
                 debug!("Processing till: {}", response.borrow().data.after.as_ref().unwrap());
                 after = response.borrow().data.after.clone();
-                listing.push(response);
             }
+
+            if let Some(pages) = dump.as_mut() {
+                pages.push(response.clone());
+            }
+
+            // the downloader goes away only if it has run into an error and stopped processing,
+            // in which case there is no point in fetching the rest of the listing
+            if oldest_first {
+                held.push(response);
+            } else if sender.send(response).is_err() {
+                debug!("Downloader is no longer receiving listings. Stopping data gathering.");
+                complete = true;
+            }
+        }
+
+        for mut page in held.into_iter().rev() {
+            page.data.children.reverse();
+            if sender.send(page).is_err() {
+                debug!("Downloader is no longer receiving listings. Stopping.");
+                break;
+            }
+        }
+
+        if let (Some(file_name), Some(pages)) = (dump_listing, dump) {
+            fs::write(file_name, serde_json::to_string_pretty(&pages)?)?;
+            info!("Saved the listing to: {}", file_name);
         }
 
-        Ok(listing)
+        Ok(())
     }
 
     pub async fn undo(&self, name: &str, listing_type: &ListingType) -> Result<(), ReddSaverError> {
-        let client = reqwest::Client::new();
+        let client = self.client.http_client();
         let url: String;
+        let scope: &str;
         let mut map = HashMap::new();
         map.insert("id", name);
 
         match listing_type {
             ListingType::Upvoted => {
                 url = format!("https://oauth.reddit.com/api/vote");
+                scope = "vote";
                 map.insert("dir", "0");
             }
             ListingType::Saved => {
                 url = format!("https://oauth.reddit.com/api/unsave");
+                scope = "save";
+            }
+            ListingType::Submitted | ListingType::Multireddit { .. } => {
+                return Err(ReddSaverError::UndoNotSupported(listing_type.to_string()));
             }
         }
 
-        let response = client
-            .post(&url)
-            .bearer_auth(&self.auth.access_token)
-            .header(USER_AGENT, get_user_agent_string(None, None))
-            .form(&map)
-            .send()
+        let response = self
+            .send_authorized(|access_token| {
+                client
+                    .post(&url)
+                    .bearer_auth(access_token)
+                    .header(USER_AGENT, get_user_agent_string(None, None))
+                    .form(&map)
+            })
             .await?;
 
         debug!("Response: {:#?}", response);
 
+        // apps with a limited set of scopes are forbidden from unsaving or removing upvotes
+        if response.status() == StatusCode::FORBIDDEN && is_insufficient_scope(&response) {
+            return Err(ReddSaverError::InsufficientScope(String::from(scope)));
+        }
+        // the post would otherwise be believed to be unsaved or no longer upvoted when it is not
+        if !response.status().is_success() {
+            return Err(ReddSaverError::UndoFailed(
+                String::from(name),
+                response.status().to_string(),
+            ));
+        }
+
         Ok(())
     }
 }
+
+/// Check if reddit forbade the request since the access token does not have the required scope
+fn is_insufficient_scope(response: &Response) -> bool {
+    response
+        .headers()
+        .get(WWW_AUTHENTICATE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.contains("insufficient_scope"))
+}
+
+/// Read the listing saved using --dump-listing. The pages are saved in the order they were
+/// fetched from the API, so they are reversed when processing the oldest posts first
+pub fn load_listing(file_name: &str, oldest_first: bool) -> Result<Vec<Listing>, ReddSaverError> {
+    let mut pages: Vec<Listing> = serde_json::from_str(&fs::read_to_string(file_name)?)?;
+    if oldest_first {
+        pages.reverse();
+        for page in pages.iter_mut() {
+            page.data.children.reverse();
+        }
+    }
+    Ok(pages)
+}
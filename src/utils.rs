@@ -1,12 +1,20 @@
 use crate::errors::ReddSaverError;
+use log::{debug, warn};
 use mime::Mime;
 use rand::Rng;
 use random_names::RandomName;
-use reqwest::header::CONTENT_TYPE;
-use std::path::Path;
+use reqwest::header::{CONTENT_TYPE, RANGE};
+use reqwest::{Certificate, ClientBuilder, StatusCode};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
+use url::Url;
 use which::which;
 
+/// Free space below which a warning is shown before downloading
+static LOW_DISK_SPACE_MB: u64 = 1024;
+
 /// Generate user agent string of the form <name>:<version>.
 /// If no arguments passed generate random name and number
 pub fn get_user_agent_string(name: Option<String>, version: Option<String>) -> String {
@@ -28,21 +36,21 @@ pub fn check_path_present(file_path: &str) -> bool {
 
 /// Function that masks sensitive data such as password and client secrets
 pub fn mask_sensitive(word: &str) -> String {
-    let word_length = word.len();
-    return if word.is_empty() {
+    if word.is_empty() {
         // return with indication if string is empty
-        String::from("<EMPTY>")
-    } else if word_length > 0 && word_length <= 3 {
-        // if string length is between 1-3, mask all characters
-        "*".repeat(word_length)
-    } else {
-        // if string length greater than 5, mask all characters
-        // except the first two and the last characters
-        word.chars()
-            .enumerate()
-            .map(|(i, c)| if i == 0 || i == 1 || i == word_length - 1 { c } else { '*' })
-            .collect()
-    };
+        return String::from("<EMPTY>");
+    }
+
+    // reveal at most a quarter of the characters, and never more than the first two
+    // and the last character. secrets shorter than four characters are masked completely
+    let word_length = word.chars().count();
+    let revealed = (word_length / 4).min(3);
+    let revealed_end = revealed / 2;
+    let revealed_start = revealed - revealed_end;
+    word.chars()
+        .enumerate()
+        .map(|(i, c)| if i < revealed_start || i >= word_length - revealed_end { c } else { '*' })
+        .collect()
 }
 
 /// Return delimited subreddit names or EMPTY if None
@@ -50,6 +58,195 @@ pub fn print_subreddits(subreddits: &Option<Vec<&str>>) -> String {
     return if let Some(s) = subreddits { s.join(",") } else { String::from("<ALL>") };
 }
 
+/// Check if the given name is a valid reddit fullname of the form <kind>_<id>, eg: t3_k1ng2h
+pub fn is_valid_fullname(name: &str) -> bool {
+    let kinds = ["t1", "t2", "t3", "t4", "t5", "t6", "t8"];
+    match name.split_once('_') {
+        Some((kind, id)) => {
+            kinds.contains(&kind) && !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric())
+        }
+        None => false,
+    }
+}
+
+/// List all the files in the directory, including the ones in its subdirectories
+pub fn list_files(directory: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        // symbolic links are not followed, so that nothing outside the directory is listed
+        if entry.file_type()?.is_dir() {
+            files.extend(list_files(&entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Normalize the extension given as an option, eg: .GIF to gif
+pub fn check_extension(extension: &str) -> Result<String, ReddSaverError> {
+    let normalized = extension.trim().trim_start_matches('.').to_lowercase();
+    if normalized.is_empty() || !normalized.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(ReddSaverError::InvalidExtension(String::from(extension)));
+    }
+    Ok(normalized)
+}
+
+/// Parse an age such as 30m, 24h, 7d or 2w. A number without a unit is in days
+pub fn parse_age(age: &str) -> Result<Duration, ReddSaverError> {
+    let age = age.trim();
+    let (number, unit) = match age.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => age.split_at(index),
+        None => (age, "d"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(ReddSaverError::InvalidAge(String::from(age))),
+    };
+    match number.parse::<u64>() {
+        Ok(number) => Ok(Duration::from_secs(number * seconds)),
+        Err(_) => Err(ReddSaverError::InvalidAge(String::from(age))),
+    }
+}
+
+/// Read the names of subreddits from a file with one subreddit on each line. Empty lines,
+/// comments starting with # and the r/ prefix of the names are ignored
+pub fn read_subreddits(file_name: &str) -> Result<Vec<String>, ReddSaverError> {
+    let contents = std::fs::read_to_string(file_name)?;
+    Ok(contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| String::from(line.trim_start_matches("/r/").trim_start_matches("r/")))
+        .collect())
+}
+
+/// Extract the ID of a post from its permalink, eg: https://www.reddit.com/r/pics/comments/k1ng2h/title/
+/// or from its short link, eg: https://redd.it/k1ng2h
+pub fn post_id_from_url(url: &str) -> Option<String> {
+    let parsed = Url::parse(url).ok()?;
+    let host = parsed.host_str()?.to_lowercase();
+    let segments: Vec<&str> = parsed.path_segments()?.filter(|s| !s.is_empty()).collect();
+    let id = if host == "redd.it" {
+        segments.first()?
+    } else if host == "reddit.com" || host.ends_with(".reddit.com") {
+        let position = segments.iter().position(|s| *s == "comments")?;
+        segments.get(position + 1)?
+    } else {
+        return None;
+    };
+    Some(String::from(*id)).filter(|id| id.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// Whether the URL is a share link of a post, eg: https://www.reddit.com/r/pics/s/a1B2c3D4e5,
+/// which only redirects to the permalink of the post
+pub fn is_share_link(url: &str) -> bool {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return false,
+    };
+    let host = parsed.host_str().unwrap_or_default().to_lowercase();
+    let segments: Vec<&str> = match parsed.path_segments() {
+        Some(segments) => segments.filter(|s| !s.is_empty()).collect(),
+        None => return false,
+    };
+    (host == "reddit.com" || host.ends_with(".reddit.com"))
+        && matches!(segments.as_slice(), ["r", _, "s", _])
+}
+
+/// Check the free space of the filesystem of the data directory before downloading, failing if
+/// less than the required space is free. The size of the downloads is not known beforehand, so
+/// without a required space only a warning is shown when the free space is low
+pub fn check_free_space(
+    data_directory: &str,
+    required_mb: Option<u64>,
+) -> Result<(), ReddSaverError> {
+    let available_mb = fs2::available_space(data_directory)? / (1024 * 1024);
+    debug!("{} MB are free in the data directory {}", available_mb, data_directory);
+    match required_mb {
+        Some(required_mb) if available_mb < required_mb => {
+            Err(ReddSaverError::InsufficientDiskSpace(available_mb, required_mb))
+        }
+        None if available_mb < LOW_DISK_SPACE_MB => {
+            warn!(
+                "Only {} MB are free in the data directory {}, the disk might fill up during the run",
+                available_mb, data_directory
+            );
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Move the file, copying it and removing the original when it is on another filesystem, since
+/// files cannot be renamed across filesystems (EXDEV), eg: from a tmpfs mounted at /tmp. The error
+/// for this differs between the platforms, so the file is copied whenever it could not be renamed
+pub fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if from.is_file() => {
+            debug!("Could not rename {} to {} ({}), copying it", from.display(), to.display(), e);
+            if let Err(e) = std::fs::copy(from, to) {
+                // a partial copy would otherwise be mistaken for the complete file in later runs
+                std::fs::remove_file(to).ok();
+                return Err(e);
+            }
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Expand the `{token}` placeholders in the template using the given function.
+/// Placeholders for which the function returns None are left as is
+pub fn expand_template<F>(template: &str, mut value_of: F) -> String
+where
+    F: FnMut(&str) -> Option<String>,
+{
+    let mut expanded = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        match rest[start..].find('}') {
+            Some(length) => {
+                let placeholder = &rest[start..=start + length];
+                let token = &placeholder[1..placeholder.len() - 1];
+                match value_of(token) {
+                    Some(value) => expanded.push_str(&value),
+                    None => expanded.push_str(placeholder),
+                }
+                rest = &rest[start + length + 1..];
+            }
+            None => break,
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Replace the characters that cannot be used in a file or directory name with underscores
+pub fn sanitize_path_component(component: &str) -> String {
+    let sanitized: String = component
+        .trim()
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // make sure the component cannot point to the current or the parent directory
+    if sanitized.is_empty() || sanitized == "." || sanitized == ".." {
+        String::from("_")
+    } else {
+        sanitized
+    }
+}
+
 /// Check if the given application is present in the $PATH
 pub fn application_present(name: String) -> bool {
     let result = which(name);
@@ -59,9 +256,79 @@ pub fn application_present(name: String) -> bool {
     }
 }
 
-/// Check if the given URL contains an MP4 track using the content type
-pub async fn check_url_is_mp4(url: &str) -> Result<Option<bool>, ReddSaverError> {
-    let response = reqwest::get(url).await?;
+/// Build the HTTP client shared by all the downloads. The connect timeout only limits
+/// establishing the connection, while the timeout limits the whole request, including the body
+pub fn http_client(
+    connect_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    ipv4_only: bool,
+    bind_address: Option<IpAddr>,
+    ca_cert: Option<&Certificate>,
+    insecure: bool,
+) -> Result<reqwest::Client, ReddSaverError> {
+    let mut builder = with_tls_options(reqwest::Client::builder(), ca_cert, insecure);
+    // the connector skips the addresses of the hosts of the other family than the bound
+    // address, so binding to the unspecified IPv4 address only connects over IPv4
+    if let Some(bind_address) = bind_address {
+        builder = builder.local_address(bind_address);
+    } else if ipv4_only {
+        builder = builder.local_address(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    }
+    if let Some(connect_timeout) = connect_timeout {
+        builder = builder.connect_timeout(connect_timeout);
+    }
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    Ok(builder.build()?)
+}
+
+/// Client for the requests to the reddit API, which only shares the certificate options with the
+/// client for the media, since the other options are about the hosts of the media
+pub fn api_client(
+    ca_cert: Option<&Certificate>,
+    insecure: bool,
+) -> Result<reqwest::Client, ReddSaverError> {
+    Ok(with_tls_options(reqwest::Client::builder(), ca_cert, insecure).build()?)
+}
+
+/// Trust the root certificate in addition to the ones of the system, eg: of a TLS-intercepting
+/// proxy. Certificates are not verified at all when insecure
+fn with_tls_options(
+    mut builder: ClientBuilder,
+    ca_cert: Option<&Certificate>,
+    insecure: bool,
+) -> ClientBuilder {
+    if let Some(ca_cert) = ca_cert {
+        builder = builder.add_root_certificate(ca_cert.clone());
+    }
+    if insecure {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    builder
+}
+
+/// Read a root certificate in the PEM or the DER format
+pub fn read_ca_cert(file_name: &str) -> Result<Certificate, ReddSaverError> {
+    let invalid = |e: String| ReddSaverError::InvalidCaCert(String::from(file_name), e);
+    let contents = std::fs::read(file_name).map_err(|e| invalid(e.to_string()))?;
+    Certificate::from_pem(&contents)
+        .or_else(|_| Certificate::from_der(&contents))
+        .map_err(|e| invalid(e.to_string()))
+}
+
+/// Check if the given URL contains an MP4 track using the content type.
+/// Only the headers are needed for this, so a HEAD request is used instead of fetching the body
+pub async fn check_url_is_mp4(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<Option<bool>, ReddSaverError> {
+    let mut response = client.head(url).send().await?;
+    // some servers do not allow HEAD requests. fall back to a GET request
+    // for only the first byte so that we still avoid fetching the whole body
+    if response.status() == StatusCode::METHOD_NOT_ALLOWED {
+        response = client.get(url).header(RANGE, "bytes=0-0").send().await?;
+    }
     let headers = response.headers();
 
     match headers.get(CONTENT_TYPE) {
@@ -77,3 +344,42 @@ pub async fn check_url_is_mp4(url: &str) -> Result<Option<bool>, ReddSaverError>
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_sensitive_empty() {
+        assert_eq!(mask_sensitive(""), "<EMPTY>");
+    }
+
+    #[test]
+    fn mask_sensitive_masks_short_secrets_completely() {
+        assert_eq!(mask_sensitive("a"), "*");
+        assert_eq!(mask_sensitive("abc"), "***");
+    }
+
+    #[test]
+    fn mask_sensitive_reveals_a_quarter_at_most() {
+        assert_eq!(mask_sensitive("abcd"), "a***");
+        assert_eq!(mask_sensitive("abcdefgh"), "a******h");
+        assert_eq!(mask_sensitive("abcdefghijklmnop"), "ab*************p");
+    }
+
+    #[test]
+    fn mask_sensitive_counts_characters_of_multibyte_secrets() {
+        assert_eq!(mask_sensitive("pässwörd"), "p******d");
+        assert_eq!(mask_sensitive("ключ"), "к***");
+    }
+
+    #[test]
+    fn move_file_keeps_destination_when_source_is_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let to = dir.path().join("media.jpg");
+        std::fs::write(&to, "media").unwrap();
+
+        assert!(move_file(&dir.path().join("missing.jpg"), &to).is_err());
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "media");
+    }
+}